@@ -1,7 +1,13 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
-use merni::{AggregationSink, ThreadLocalAggregator};
-use merni::{counter, distribution, gauge};
+use merni::{AggregationSink, Counter, Gauge, Metric, MetricMeta, MetricType, Sink};
+use merni::{ThreadLocalAggregator, counter, distribution, gauge};
+use rustc_hash::FxHashMap;
+use thread_local::ThreadLocal;
 
 pub struct NoopSink;
 impl AggregationSink for NoopSink {
@@ -12,6 +18,123 @@ pub fn noop_aggregator() -> ThreadLocalAggregator<()> {
     ThreadLocalAggregator::new(Duration::from_millis(20), NoopSink)
 }
 
+/// An aggregator which can be flushed by the [`ConcurrentFlusher`].
+pub trait Flush: Send + Sync + 'static {
+    fn flush(&self);
+}
+
+impl Flush for ThreadLocalAggregator<()> {
+    fn flush(&self) {
+        ThreadLocalAggregator::flush(self, None).unwrap();
+    }
+}
+
+/// The previous design of the [`ThreadLocalAggregator`] hot path, to compare against.
+///
+/// The aggregations of each thread are behind a single [`Mutex`], which is locked on every emit,
+/// and while the flusher drains them.
+#[derive(Default)]
+pub struct MutexAggregator {
+    aggregations: ThreadLocal<Mutex<MutexAggregations>>,
+}
+
+#[derive(Default)]
+struct MutexAggregations {
+    counters: FxHashMap<LocalKey, f64>,
+    gauges: FxHashMap<LocalKey, f64>,
+    distributions: FxHashMap<LocalKey, Vec<f64>>,
+}
+
+/// Hashes the metric by the address of its [`MetricMeta`] and its tag values.
+struct LocalKey(Metric);
+
+impl LocalKey {
+    fn meta(&self) -> *const MetricMeta {
+        let meta: &MetricMeta = &self.0;
+        meta
+    }
+}
+
+impl Hash for LocalKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.meta().hash(state);
+        self.0.tags().for_each(|(_, value)| value.hash(state));
+    }
+}
+
+impl PartialEq for LocalKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.meta() == other.meta() && self.0.tags().eq(other.0.tags())
+    }
+}
+impl Eq for LocalKey {}
+
+impl Sink for MutexAggregator {
+    fn emit(&self, metric: Metric) {
+        let mut aggregations = self.aggregations.get_or_default().lock().unwrap();
+        let value = metric.value().get();
+        match metric.ty() {
+            MetricType::Counter => {
+                *aggregations.counters.entry(LocalKey(metric)).or_default() += value
+            }
+            MetricType::Gauge => {
+                aggregations.gauges.insert(LocalKey(metric), value);
+            }
+            _ => aggregations
+                .distributions
+                .entry(LocalKey(metric))
+                .or_default()
+                .push(value),
+        }
+    }
+}
+
+impl Flush for MutexAggregator {
+    fn flush(&self) {
+        for aggregations in self.aggregations.iter() {
+            let mut aggregations = aggregations.lock().unwrap();
+            aggregations.counters.drain().for_each(drop);
+            aggregations.gauges.drain().for_each(drop);
+            aggregations.distributions.drain().for_each(drop);
+        }
+    }
+}
+
+/// Flushes the aggregator in a tight loop on a background thread, until being dropped.
+///
+/// This maximizes the contention between the emitting threads and the flusher.
+pub struct ConcurrentFlusher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConcurrentFlusher {
+    pub fn new(aggregator: Arc<impl Flush>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    aggregator.flush();
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for ConcurrentFlusher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
 pub fn emit_simple() {
     counter!("some.counter": 1);
     counter!("some.tagged.counter": 2, "tag_key" => "tag_value");
@@ -24,6 +147,35 @@ pub fn emit_simple() {
     gauge!("some.tagged.gauge": 4, "tag_key" => "tag_value");
 }
 
+/// The same metrics as [`emit_simple`], emitted using pre-registered handles.
+pub struct SimpleHandles {
+    counter: Counter,
+    tagged_counter: Counter,
+    gauge: Gauge,
+    tagged_gauge: Gauge,
+}
+
+impl SimpleHandles {
+    pub fn new() -> Self {
+        let no_tags: [(&str, &str); 0] = [];
+        Self {
+            counter: Counter::register("some.counter", no_tags),
+            tagged_counter: Counter::register("some.tagged.counter", [("tag_key", "tag_value")]),
+            gauge: Gauge::register("some.gauge", no_tags),
+            tagged_gauge: Gauge::register("some.tagged.gauge", [("tag_key", "tag_value")]),
+        }
+    }
+
+    pub fn emit(&self) {
+        for _ in 0..2 {
+            self.counter.increment(1);
+            self.tagged_counter.increment(2);
+            self.gauge.set(3);
+            self.tagged_gauge.set(4);
+        }
+    }
+}
+
 pub fn emit_distribution() {
     distribution!("some.distribution": 1);
     distribution!("some.tagged.distribution": 2, "tag_key" => "tag_value");
@@ -37,6 +37,65 @@ fn simple(bencher: Bencher) {
         });
 }
 
+#[divan::bench]
+fn simple_concurrent_flush(bencher: Bencher) {
+    let sink = Arc::new(noop_aggregator());
+    let _flusher = ConcurrentFlusher::new(Arc::clone(&sink));
+    bencher
+        .with_inputs(|| Dispatcher::new(Arc::clone(&sink)))
+        .bench_values(|dispatcher| {
+            let guard = set_local_dispatcher(dispatcher);
+            emit_simple();
+            guard.take()
+        });
+}
+
+#[divan::bench]
+fn distribution_concurrent_flush(bencher: Bencher) {
+    let sink = Arc::new(noop_aggregator());
+    let _flusher = ConcurrentFlusher::new(Arc::clone(&sink));
+    bencher
+        .with_inputs(|| Dispatcher::new(Arc::clone(&sink)))
+        .bench_values(|dispatcher| {
+            let guard = set_local_dispatcher(dispatcher);
+            emit_distribution();
+            guard.take()
+        });
+}
+
+#[divan::bench]
+fn simple_mutex_concurrent_flush(bencher: Bencher) {
+    let sink = Arc::new(MutexAggregator::default());
+    let _flusher = ConcurrentFlusher::new(Arc::clone(&sink));
+    bencher
+        .with_inputs(|| Dispatcher::new(Arc::clone(&sink)))
+        .bench_values(|dispatcher| {
+            let guard = set_local_dispatcher(dispatcher);
+            emit_simple();
+            guard.take()
+        });
+}
+
+#[divan::bench]
+fn distribution_mutex_concurrent_flush(bencher: Bencher) {
+    let sink = Arc::new(MutexAggregator::default());
+    let _flusher = ConcurrentFlusher::new(Arc::clone(&sink));
+    bencher
+        .with_inputs(|| Dispatcher::new(Arc::clone(&sink)))
+        .bench_values(|dispatcher| {
+            let guard = set_local_dispatcher(dispatcher);
+            emit_distribution();
+            guard.take()
+        });
+}
+
+#[divan::bench]
+fn simple_handles_global(bencher: Bencher) {
+    // handles register their cells with each new dispatcher, so this uses the global one
+    let handles = SimpleHandles::new();
+    bencher.bench(|| handles.emit());
+}
+
 #[divan::bench]
 fn distribution(bencher: Bencher) {
     let sink = Arc::new(noop_aggregator());
@@ -1,5 +1,9 @@
-use merni::{Dispatcher, set_global_dispatcher};
-use tango_bench::{IntoBenchmarks, benchmark_fn, tango_benchmarks, tango_main};
+use std::sync::Arc;
+
+use merni::{Dispatcher, Sink, set_global_dispatcher, set_local_dispatcher};
+use tango_bench::{
+    Bencher, ErasedSampler, IntoBenchmarks, benchmark_fn, tango_benchmarks, tango_main,
+};
 
 mod benches;
 use benches::*;
@@ -9,8 +13,39 @@ fn factorial_benchmarks() -> impl IntoBenchmarks {
     [
         benchmark_fn("simple_global", |b| b.iter(emit_simple)),
         benchmark_fn("distribution_global", |b| b.iter(emit_distribution)),
+        benchmark_fn("simple_handles_global", |b| {
+            let handles = SimpleHandles::new();
+            b.iter(move || handles.emit())
+        }),
+        benchmark_fn("simple_concurrent_flush", |b| {
+            concurrent_flush(b, Arc::new(noop_aggregator()), emit_simple)
+        }),
+        benchmark_fn("distribution_concurrent_flush", |b| {
+            concurrent_flush(b, Arc::new(noop_aggregator()), emit_distribution)
+        }),
+        benchmark_fn("simple_mutex_concurrent_flush", |b| {
+            concurrent_flush(b, Arc::new(MutexAggregator::default()), emit_simple)
+        }),
+        benchmark_fn("distribution_mutex_concurrent_flush", |b| {
+            concurrent_flush(b, Arc::new(MutexAggregator::default()), emit_distribution)
+        }),
     ]
 }
 
+/// Emits metrics while the aggregator is being flushed concurrently.
+fn concurrent_flush<A: Flush + Sink>(
+    b: Bencher,
+    sink: Arc<A>,
+    emit: fn(),
+) -> Box<dyn ErasedSampler> {
+    let flusher = ConcurrentFlusher::new(Arc::clone(&sink));
+    b.iter(move || {
+        let _flusher = &flusher;
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&sink)));
+        emit();
+        guard.take()
+    })
+}
+
 tango_benchmarks!(factorial_benchmarks());
 tango_main!();
@@ -0,0 +1,182 @@
+//! Derive macros for [merni](https://docs.rs/merni).
+//!
+//! These are re-exported by `merni` with the `"derive"` feature enabled,
+//! and should be used through that re-export.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, LitStr, parse_macro_input};
+
+/// Derives `merni::Tags` for structs with named fields.
+///
+/// See the documentation of `merni::Tags` for details.
+#[proc_macro_derive(Tags, attributes(tag))]
+pub fn derive_tags(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let result = match &input.data {
+        Data::Struct(_) => derive_struct(&input),
+        Data::Enum(_) => Err(syn::Error::new(
+            Span::call_site(),
+            "`Tags` can not be derived for enums, derive `ToTagValue` instead",
+        )),
+        Data::Union(_) => Err(syn::Error::new(
+            Span::call_site(),
+            "`Tags` can not be derived for unions",
+        )),
+    };
+    result.unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// Derives `merni::ToTagValue` for enums with unit variants.
+///
+/// See the documentation of `merni::Tags` for details.
+#[proc_macro_derive(ToTagValue, attributes(tag))]
+pub fn derive_to_tag_value(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let result = match &input.data {
+        Data::Enum(_) => derive_enum(&input),
+        _ => Err(syn::Error::new(
+            Span::call_site(),
+            "`ToTagValue` can only be derived for enums",
+        )),
+    };
+    result.unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// The `#[tag(...)]` attributes of a field or variant.
+#[derive(Default)]
+struct TagAttrs {
+    rename: Option<LitStr>,
+    display: bool,
+}
+
+impl TagAttrs {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut tag_attrs = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("tag")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    tag_attrs.rename = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("display") {
+                    tag_attrs.display = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `rename` or `display`"))
+                }
+            })?;
+        }
+        Ok(tag_attrs)
+    }
+}
+
+fn derive_struct(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        unreachable!()
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "`Tags` can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut keys = vec![];
+    let mut values = vec![];
+    for field in &fields.named {
+        let attrs = TagAttrs::parse(&field.attrs)?;
+        let ident = field.ident.as_ref().unwrap();
+        let name = ident.to_string();
+        keys.push(
+            attrs
+                .rename
+                .unwrap_or_else(|| LitStr::new(name.trim_start_matches("r#"), ident.span())),
+        );
+        values.push(if attrs.display {
+            quote! { ::merni::TagValue::Display(&self.#ident) }
+        } else {
+            quote! { ::merni::ToTagValue::to_tag_value(&self.#ident) }
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::merni::Tags for #ident #ty_generics #where_clause {
+            const KEYS: &'static [&'static str] = &[#(#keys),*];
+
+            fn visit_values(&self, visitor: &mut dyn FnMut(::merni::TagValue<'_>)) {
+                #(visitor(#values);)*
+            }
+        }
+    })
+}
+
+fn derive_enum(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        unreachable!()
+    };
+
+    let mut arms = vec![];
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "`ToTagValue` can only be derived for enums with unit variants",
+            ));
+        }
+        let attrs = TagAttrs::parse(&variant.attrs)?;
+        if attrs.display {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "`display` is only supported on struct fields",
+            ));
+        }
+        let ident = &variant.ident;
+        let value = attrs
+            .rename
+            .unwrap_or_else(|| LitStr::new(&snake_case(&ident.to_string()), ident.span()));
+        arms.push(quote! { Self::#ident => #value });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::merni::ToTagValue for #ident #ty_generics #where_clause {
+            fn to_tag_value(&self) -> ::merni::TagValue<'_> {
+                ::merni::TagValue::Str(match self {
+                    #(#arms,)*
+                })
+            }
+        }
+    })
+}
+
+/// Turns a `CamelCase` variant name into `snake_case`.
+fn snake_case(name: &str) -> String {
+    let name = name.trim_start_matches("r#");
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.char_indices() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_snake_case() {
+        assert_eq!(snake_case("Get"), "get");
+        assert_eq!(snake_case("NotFound"), "not_found");
+        assert_eq!(snake_case("lower"), "lower");
+    }
+}
@@ -1,16 +1,38 @@
-use std::hash::{Hash, Hasher};
+use std::collections::hash_map::Entry;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
 use crossbeam_utils::CachePadded;
-use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::{FxBuildHasher, FxHashMap as HashMap};
+use smol_buf::Str24;
 use thread_local::ThreadLocal;
 
+use crate::handle::timestamp;
+use crate::metric::{renamed, set_tag_value, tagged};
 use crate::tags::TagValues;
-use crate::{Metric, MetricKey, MetricMeta, MetricType, Sink};
+use crate::{
+    AggregatedSet, DDSketch, Metric, MetricCell, MetricKey, MetricMeta, MetricType, MetricUnit,
+    Sink, TaggedMetricMeta,
+};
+
+/// The tag value which all the tags of series exceeding the cardinality limits are collapsed to.
+///
+/// See [`CardinalityOverflow::Collapse`].
+pub const OVERFLOW_TAG_VALUE: &str = "__overflow__";
+
+/// The self-telemetry counter of distinct series which were dropped or collapsed due to cardinality limits.
+static DROPPED_SERIES: TaggedMetricMeta<1> = MetricMeta::new(
+    MetricType::Counter,
+    MetricUnit::Unknown,
+    "merni.aggregator.dropped_series",
+)
+.with_tags(&["metric"]);
 
 /// A Sink for aggregated metrics.
 pub trait AggregationSink: Send + 'static {
@@ -54,6 +76,8 @@ impl PartialEq for LocalKey {
 impl Eq for LocalKey {}
 
 /// An aggregated Gauge.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct AggregatedGauge {
     /// The minimum value within this aggregation.
     pub min: f64,
@@ -66,6 +90,8 @@ pub struct AggregatedGauge {
 
     /// The latest value added to this aggregation.
     pub last: f64,
+    /// When the `last` value was added.
+    pub(crate) updated: u64,
 }
 
 impl Default for AggregatedGauge {
@@ -76,15 +102,211 @@ impl Default for AggregatedGauge {
             sum: 0.0,
             count: 0,
             last: 0.0,
+            updated: 0,
+        }
+    }
+}
+
+impl AggregatedGauge {
+    fn merge(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+        // gauges are merged across threads and buffers in arbitrary order
+        if other.updated >= self.updated {
+            self.last = other.last;
+            self.updated = other.updated;
         }
     }
 }
 
 /// A precisely aggregated distribution, keeping a list of all the observed values.
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
 pub struct PreciseAggregatedDistribution {
     /// All the aggregated values.
     pub values: Vec<f64>,
+    /// The weight of each of the aggregated `values`, restoring the values dropped by sampling.
+    ///
+    /// This is empty if none of the values were sampled, in which case all weights are `1`.
+    pub weights: Vec<u64>,
+}
+
+impl PreciseAggregatedDistribution {
+    /// Adds a value, which stands in for `weight` observed values.
+    pub fn add(&mut self, value: f64, weight: u64) {
+        if weight != 1 || !self.weights.is_empty() {
+            self.weights.resize(self.values.len(), 1);
+            self.weights.push(weight);
+        }
+        self.values.push(value);
+    }
+
+    /// Merges the `other` distribution into this one.
+    pub fn merge(&mut self, other: Self) {
+        if !self.weights.is_empty() || !other.weights.is_empty() {
+            self.weights.resize(self.values.len(), 1);
+            match other.weights.is_empty() {
+                true => self
+                    .weights
+                    .resize(self.values.len() + other.values.len(), 1),
+                false => self.weights.extend(other.weights),
+            }
+        }
+        self.values.extend(other.values);
+    }
+
+    /// Iterates over all the values, along with their weights.
+    pub fn weighted_values(&self) -> impl ExactSizeIterator<Item = (f64, u64)> + '_ {
+        let weight = |index| self.weights.get(index).copied().unwrap_or(1);
+        self.values
+            .iter()
+            .enumerate()
+            .map(move |(index, value)| (*value, weight(index)))
+    }
+
+    /// The total number of observed values, which is the sum of all weights.
+    pub fn count(&self) -> u64 {
+        match self.weights.is_empty() {
+            true => self.values.len() as u64,
+            false => self.weights.iter().sum(),
+        }
+    }
+}
+
+/// How distribution-like metrics are being aggregated by the [`ThreadLocalAggregator`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DistributionAggregation {
+    /// Keep a list of all the observed values, see [`PreciseAggregatedDistribution`].
+    #[default]
+    Precise,
+    /// Aggregate values into a [`DDSketch`], using bounded memory.
+    Sketch,
+}
+
+/// What happens to new series once a cardinality limit of the [`ThreadLocalAggregator`] is hit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CardinalityOverflow {
+    /// Drop the series altogether.
+    #[default]
+    Drop,
+    /// Collapse all the tag values of the series to [`OVERFLOW_TAG_VALUE`].
+    ///
+    /// Metrics without any tags are dropped instead.
+    Collapse,
+}
+
+/// The configuration of a [`ThreadLocalAggregator`].
+#[derive(Debug, Clone)]
+pub struct AggregatorConfig {
+    flush_interval: Duration,
+    distributions: DistributionAggregation,
+    metric_distributions: HashMap<String, DistributionAggregation>,
+
+    max_series: Option<usize>,
+    max_series_per_metric: Option<usize>,
+    metric_max_series: HashMap<String, usize>,
+    overflow: CardinalityOverflow,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10))
+    }
+}
+
+impl AggregatorConfig {
+    /// Creates a new configuration, flushing aggregated metrics according to the `flush_interval`.
+    pub fn new(flush_interval: Duration) -> Self {
+        Self {
+            flush_interval,
+            distributions: Default::default(),
+            metric_distributions: Default::default(),
+
+            max_series: None,
+            max_series_per_metric: None,
+            metric_max_series: Default::default(),
+            overflow: Default::default(),
+        }
+    }
+
+    /// Sets how distribution-like metrics are being aggregated.
+    ///
+    /// This defaults to [`DistributionAggregation::Precise`].
+    pub fn distributions(mut self, aggregation: DistributionAggregation) -> Self {
+        self.distributions = aggregation;
+        self
+    }
+
+    /// Sets how the distribution-like metric with the given key is being aggregated.
+    ///
+    /// This overrides the default set via [`distributions`](Self::distributions).
+    pub fn metric_distributions(mut self, key: &str, aggregation: DistributionAggregation) -> Self {
+        self.metric_distributions.insert(key.into(), aggregation);
+        self
+    }
+
+    /// Limits the total number of distinct series (metrics and their tag values) per flush.
+    ///
+    /// Series exceeding the limit are handled according to [`overflow`](Self::overflow),
+    /// and counted by the `merni.aggregator.dropped_series` counter.
+    ///
+    /// The limits are also applied to the series of each thread as metrics are being added,
+    /// which bounds the memory used between flushes.
+    pub fn max_series(mut self, limit: usize) -> Self {
+        self.max_series = Some(limit);
+        self
+    }
+
+    /// Limits the number of distinct tag combinations of each metric per flush.
+    ///
+    /// Series exceeding the limit are handled according to [`overflow`](Self::overflow),
+    /// and counted by the `merni.aggregator.dropped_series` counter.
+    pub fn max_series_per_metric(mut self, limit: usize) -> Self {
+        self.max_series_per_metric = Some(limit);
+        self
+    }
+
+    /// Limits the number of distinct tag combinations of the metric with the given key per flush.
+    ///
+    /// This overrides the default set via [`max_series_per_metric`](Self::max_series_per_metric).
+    pub fn metric_max_series(mut self, key: &str, limit: usize) -> Self {
+        self.metric_max_series.insert(key.into(), limit);
+        self
+    }
+
+    /// Sets what happens to series exceeding the cardinality limits.
+    ///
+    /// This defaults to [`CardinalityOverflow::Drop`].
+    pub fn overflow(mut self, overflow: CardinalityOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    fn has_limits(&self) -> bool {
+        self.max_series.is_some()
+            || self.max_series_per_metric.is_some()
+            || !self.metric_max_series.is_empty()
+    }
+
+    fn new_distribution(&self, key: &str) -> LocalDistribution {
+        let aggregation = self
+            .metric_distributions
+            .get(key)
+            .copied()
+            .unwrap_or(self.distributions);
+        match aggregation {
+            DistributionAggregation::Precise => LocalDistribution::Precise(Default::default()),
+            DistributionAggregation::Sketch => LocalDistribution::Sketch(Default::default()),
+        }
+    }
+}
+
+/// A thread-local distribution, aggregated according to the [`DistributionAggregation`].
+enum LocalDistribution {
+    Precise(PreciseAggregatedDistribution),
+    Sketch(DDSketch),
 }
 
 /// The thread-local "pre"-aggregations.
@@ -93,33 +315,120 @@ pub struct PreciseAggregatedDistribution {
 #[derive(Default)]
 pub(crate) struct PreAggregations {
     /// All aggregated counter metrics.
-    counters: HashMap<LocalKey, f64>,
+    pub(crate) counters: HashMap<LocalKey, f64>,
     /// All aggregated gauge metrics.
     pub(crate) gauges: HashMap<LocalKey, AggregatedGauge>,
     /// All aggregated distribution-like metrics.
-    distributions: HashMap<LocalKey, PreciseAggregatedDistribution>,
+    distributions: HashMap<LocalKey, LocalDistribution>,
+    /// All aggregated set metrics.
+    sets: HashMap<LocalKey, AggregatedSet>,
+    /// The series counted towards the cardinality limits, which bound the memory used
+    /// between flushes.
+    series: SeriesCounts,
+}
+
+impl PreAggregations {
+    /// Checks whether the series of `key` is within the cardinality limits.
+    ///
+    /// This approximates the limits enforced by the [`CardinalityLimiter`] when flushing,
+    /// as each thread only knows about its own series.
+    /// Returns the key to aggregate the value into, or [`None`] if the value should be dropped.
+    fn limit(&mut self, config: &AggregatorConfig, key: LocalKey) -> Option<LocalKey> {
+        if !config.has_limits() {
+            return Some(key);
+        }
+        let exists = match key.0.ty() {
+            MetricType::Counter => self.counters.contains_key(&key),
+            MetricType::Gauge => self.gauges.contains_key(&key),
+            MetricType::Distribution | MetricType::Timer => self.distributions.contains_key(&key),
+            MetricType::Set => self.sets.contains_key(&key),
+        };
+        if exists || self.series.admit(config, key.0.meta, &key.0.tag_values) {
+            return Some(key);
+        }
+        let tag_values = overflow_tag_values(config, &key.0.tag_values)?;
+        Some(LocalKey(MetricKey {
+            meta: key.0.meta,
+            tag_values,
+        }))
+    }
+}
+
+/// Double-buffered [`PreAggregations`] of a single thread.
+///
+/// The owning thread writes into the buffer of the current epoch, while the flusher advances
+/// the epoch and drains the previous buffer. Writers never wait for the flusher to finish draining,
+/// they rather switch to the other buffer if it is locked.
+/// Writers only use a buffer if the epoch did not advance while locking it, so all values are
+/// picked up by the next flush.
+///
+/// It also holds the [`MetricCell`]s of the metric handles first used on this thread.
+#[derive(Default)]
+pub(crate) struct LocalAggregations {
+    epoch: AtomicUsize,
+    buffers: [CachePadded<Mutex<PreAggregations>>; 2],
+    pub(crate) cells: Mutex<Vec<Arc<MetricCell>>>,
+}
+
+impl LocalAggregations {
+    /// Locks the buffer to write into, without blocking on the flusher.
+    fn current(&self) -> MutexGuard<'_, PreAggregations> {
+        let mut index = self.epoch.load(Ordering::Acquire) & 1;
+        loop {
+            // The flusher only ever holds one of the locks, so one of them is always available.
+            let guard = match self.buffers[index].try_lock() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    index ^= 1;
+                    continue;
+                }
+            };
+            // The flusher advances the epoch before locking the previous buffer. If the epoch
+            // still points to this buffer, the next flush waits for the lock and drains it.
+            // Otherwise, this buffer might have been drained already.
+            let current = self.epoch.load(Ordering::Acquire) & 1;
+            if current == index {
+                return guard;
+            }
+            index = current;
+        }
+    }
+
+    /// Advances the epoch, and locks the previous buffer to be drained.
+    pub(crate) fn swap(&self) -> MutexGuard<'_, PreAggregations> {
+        let index = self.epoch.fetch_add(1, Ordering::AcqRel) & 1;
+        self.buffers[index]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
 }
 
 /// The thread-local "pre"-aggregations.
-type ThreadLocalAggregations = Arc<ThreadLocal<CachePadded<Mutex<PreAggregations>>>>;
+type ThreadLocalAggregations = Arc<ThreadLocal<LocalAggregations>>;
 
 pub(crate) enum Task<Output> {
     Flush(SyncSender<Output>),
-    Shutdown,
+    /// Stops the thread after a final flush, optionally sending back its result.
+    Shutdown(Option<SyncSender<Output>>),
 }
 
+type AggregatorThread<Output> = (SyncSender<Task<Output>>, JoinHandle<()>);
+
 /// An aggregator that uses fast thread-local "pre"-aggregation.
 pub struct ThreadLocalAggregator<Output> {
     /// The thread-local "pre"-aggregations.
     pub(crate) aggregations: ThreadLocalAggregations,
+    pub(crate) config: Arc<AggregatorConfig>,
 
-    pub(crate) thread: Option<(SyncSender<Task<Output>>, JoinHandle<()>)>,
+    pub(crate) thread: Mutex<Option<AggregatorThread<Output>>>,
 }
 
 impl<Output> Drop for ThreadLocalAggregator<Output> {
     fn drop(&mut self) {
-        if let Some((sender, thread)) = self.thread.take() {
-            let _ = sender.try_send(Task::Shutdown);
+        let thread = self.thread.get_mut().unwrap_or_else(|err| err.into_inner());
+        if let Some((sender, thread)) = thread.take() {
+            let _ = sender.try_send(Task::Shutdown(None));
             drop(sender);
             thread.join().unwrap();
         }
@@ -132,6 +441,17 @@ impl<Output: Send + 'static> ThreadLocalAggregator<Output> {
     /// This will flush aggregated metrics to the given [`AggregationSink`] on a background thread,
     /// according to the `flush_interval`.
     pub fn new(flush_interval: Duration, sink: impl AggregationSink<Output = Output>) -> Self {
+        Self::with_config(AggregatorConfig::new(flush_interval), sink)
+    }
+
+    /// Create a new thread-local aggregator with the given [`AggregatorConfig`].
+    ///
+    /// This will flush aggregated metrics to the given [`AggregationSink`] on a background thread.
+    pub fn with_config(
+        config: AggregatorConfig,
+        sink: impl AggregationSink<Output = Output>,
+    ) -> Self {
+        let config = Arc::new(config);
         let aggregations = Default::default();
         let (send_signal, recv_signal) = sync_channel(0);
 
@@ -139,72 +459,147 @@ impl<Output: Send + 'static> ThreadLocalAggregator<Output> {
             .name("merni-aggregator".into())
             .spawn({
                 let aggregations = Arc::clone(&aggregations);
-                move || Self::thread(aggregations, flush_interval, sink, recv_signal)
+                let config = Arc::clone(&config);
+                move || Self::thread(aggregations, config, sink, recv_signal)
             })
             .unwrap();
 
         Self {
             aggregations,
-            thread: Some((send_signal, thread)),
+            config,
+            thread: Mutex::new(Some((send_signal, thread))),
         }
     }
 
+    /// Creates an aggregator without a background thread, which is drained manually.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn without_thread(config: AggregatorConfig) -> Self {
+        Self {
+            aggregations: Default::default(),
+            config: Arc::new(config),
+            thread: Default::default(),
+        }
+    }
+
+    /// Synchronously merges all the metrics aggregated so far, without any sink.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn drain(&self) -> Aggregations {
+        Self::merge_thread_locals(&self.aggregations, &self.config, true)
+    }
+
     /// Flushes all aggregated metrics to the configured sink, returning its result.
     pub fn flush(&self, timeout: Option<Duration>) -> Result<Output, RecvTimeoutError> {
-        let Some((thread_sender, _thread)) = &self.thread else {
+        let thread = self.thread.lock().unwrap_or_else(|err| err.into_inner());
+        let Some((thread_sender, _thread)) = &*thread else {
             return Err(RecvTimeoutError::Disconnected);
         };
+        let thread_sender = thread_sender.clone();
+        drop(thread);
+
         let (sender, receiver) = sync_channel(1);
         thread_sender
             .send(Task::Flush(sender))
             .map_err(|_| RecvTimeoutError::Disconnected)?;
-        if let Some(timeout) = timeout {
-            receiver.recv_timeout(timeout)
-        } else {
-            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
-        }
+        recv(&receiver, timeout)
+    }
+
+    /// Stops the background thread after a final flush to the configured sink, returning its result.
+    ///
+    /// Once the final flush completed within the `timeout`, the background thread is joined.
+    /// Otherwise, it is left to finish on its own.
+    /// Metrics emitted after the shutdown are not being flushed anymore.
+    pub fn shutdown(&self, timeout: Option<Duration>) -> Result<Output, RecvTimeoutError> {
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        let Some((thread_sender, thread)) = thread else {
+            return Err(RecvTimeoutError::Disconnected);
+        };
+
+        let (sender, receiver) = sync_channel(1);
+        thread_sender
+            .send(Task::Shutdown(Some(sender)))
+            .map_err(|_| RecvTimeoutError::Disconnected)?;
+        let output = recv(&receiver, timeout)?;
+        thread.join().unwrap();
+        Ok(output)
     }
 
     fn thread(
         thread_locals: ThreadLocalAggregations,
-        flush_interval: Duration,
+        config: Arc<AggregatorConfig>,
         mut sink: impl AggregationSink<Output = Output>,
         recv_signal: Receiver<Task<Output>>,
     ) {
         loop {
-            let signal = recv_signal.recv_timeout(flush_interval);
-
-            let mut all_aggregations = Aggregations::default();
-            for thread_local in thread_locals.iter() {
-                let mut thread_local = thread_local.lock().unwrap();
-                all_aggregations.merge_aggregations(&mut thread_local);
-            }
+            let signal = recv_signal.recv_timeout(config.flush_interval);
+
+            // On shutdown, both buffers are drained, to not lose any late values.
+            let shutdown = matches!(
+                signal,
+                Ok(Task::Shutdown(_)) | Err(RecvTimeoutError::Disconnected)
+            );
+            let all_aggregations = Self::merge_thread_locals(&thread_locals, &config, shutdown);
             let output = sink.emit(all_aggregations);
 
             match signal {
                 Ok(Task::Flush(sender)) => {
                     let _ = sender.send(output);
                 }
-                Ok(Task::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(Task::Shutdown(sender)) => {
+                    if let Some(sender) = sender {
+                        let _ = sender.send(output);
+                    }
+                    return;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
                 _ => {}
             }
         }
     }
 
+    /// Merges the aggregations of all threads, enforcing the configured cardinality limits.
+    ///
+    /// With `drain`, both buffers of each thread are merged, to not lose any late values.
+    fn merge_thread_locals(
+        thread_locals: &ThreadLocalAggregations,
+        config: &AggregatorConfig,
+        drain: bool,
+    ) -> Aggregations {
+        let mut all_aggregations = Aggregations::default();
+        let mut limiter = CardinalityLimiter::new(config);
+        for thread_local in thread_locals.iter() {
+            all_aggregations.merge_limited(&mut thread_local.swap(), &mut limiter);
+            if drain {
+                all_aggregations.merge_limited(&mut thread_local.swap(), &mut limiter);
+            }
+            all_aggregations.merge_cells(&thread_local.cells, &mut limiter);
+        }
+        limiter.record_dropped(&mut all_aggregations);
+        all_aggregations
+    }
+
     /// Adds the [`Metric`] to this thread-local aggregator.
     fn add_metric(&self, metric: Metric) {
-        let mut aggregations = self.aggregations.get_or_default().lock().unwrap();
+        let mut aggregations = self.aggregations.get_or_default().current();
         let ty = metric.ty();
-        let key = LocalKey(metric.key);
+        let metric_key = metric.key();
         let value = metric.value.get();
+        let weight = metric.weight();
+        let Some(key) = aggregations.limit(&self.config, LocalKey(metric.key)) else {
+            return;
+        };
 
         match ty {
             MetricType::Counter => {
-                *aggregations.counters.entry(key).or_default() += value;
+                *aggregations.counters.entry(key).or_default() += value * weight as f64;
             }
             MetricType::Gauge => {
                 let gauge = aggregations.gauges.entry(key).or_default();
                 gauge.last = value;
+                gauge.updated = timestamp();
 
                 gauge.min = gauge.min.min(value);
                 gauge.max = gauge.max.max(value);
@@ -212,25 +607,59 @@ impl<Output: Send + 'static> ThreadLocalAggregator<Output> {
                 gauge.count += 1;
             }
             MetricType::Distribution | MetricType::Timer => {
-                aggregations
+                let distribution = aggregations
                     .distributions
                     .entry(key)
+                    .or_insert_with(|| self.config.new_distribution(metric_key));
+                match distribution {
+                    LocalDistribution::Precise(distribution) => distribution.add(value, weight),
+                    LocalDistribution::Sketch(sketch) => sketch.add_with_count(value, weight),
+                }
+            }
+            MetricType::Set => {
+                aggregations
+                    .sets
+                    .entry(key)
                     .or_default()
-                    .values
-                    .push(value);
+                    .insert(value.to_bits());
             }
         }
     }
 }
 
+/// Receives the result of a flush, waiting for up to `timeout`.
+fn recv<Output>(
+    receiver: &Receiver<Output>,
+    timeout: Option<Duration>,
+) -> Result<Output, RecvTimeoutError> {
+    match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout),
+        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    }
+}
+
 impl<Output: Send + 'static> Sink for ThreadLocalAggregator<Output> {
     fn emit(&self, metric: Metric) {
         self.add_metric(metric)
     }
+
+    fn register(&self, cell: &Arc<MetricCell>) -> bool {
+        let ty = cell.key().ty();
+        if ty != MetricType::Counter && ty != MetricType::Gauge {
+            return false;
+        }
+        let local = self.aggregations.get_or_default();
+        local
+            .cells
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(Arc::clone(cell));
+        true
+    }
 }
 
 /// An aggregated metric key, along with its tag values.
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct AggregatedMetric {
     pub(crate) meta: MetricMeta,
     pub(crate) tag_values: TagValues,
@@ -254,10 +683,32 @@ impl AggregatedMetric {
             .copied()
             .zip(values.iter().map(|s| s.as_ref()))
     }
+
+    /// Replaces the key, or name of this metric.
+    ///
+    /// See [`Metric::with_key`].
+    pub fn with_key(mut self, key: &str) -> Self {
+        if let Some(meta) = renamed(&self.meta, key) {
+            self.meta = *meta;
+        }
+        self
+    }
+
+    /// Sets the tag `key` to `value`, replacing an existing tag with the same key.
+    ///
+    /// See [`Metric::with_tag`].
+    pub fn with_tag(mut self, key: &str, value: impl Display) -> Self {
+        if let Some(&meta) = tagged(&self.meta, key) {
+            self.tag_values = set_tag_value(&self.meta, self.tag_values, key, value);
+            self.meta = meta;
+        }
+        self
+    }
 }
 
 /// The final aggregated metrics.
-#[derive(Default)]
+#[derive(Default, Clone)]
+#[non_exhaustive]
 pub struct Aggregations {
     /// All aggregated counter metrics.
     pub counters: HashMap<AggregatedMetric, f64>,
@@ -265,34 +716,281 @@ pub struct Aggregations {
     pub gauges: HashMap<AggregatedMetric, AggregatedGauge>,
     /// All aggregated distribution-like metrics.
     pub distributions: HashMap<AggregatedMetric, PreciseAggregatedDistribution>,
+    /// All distribution-like metrics aggregated into sketches.
+    ///
+    /// See [`DistributionAggregation::Sketch`].
+    pub sketches: HashMap<AggregatedMetric, DDSketch>,
+    /// All aggregated set metrics.
+    pub sets: HashMap<AggregatedMetric, AggregatedSet>,
 }
 
 impl Aggregations {
+    /// Retains only the metrics for which the predicate returns `true`.
+    pub fn retain(&mut self, mut f: impl FnMut(&AggregatedMetric) -> bool) {
+        self.counters.retain(|key, _| f(key));
+        self.gauges.retain(|key, _| f(key));
+        self.distributions.retain(|key, _| f(key));
+        self.sketches.retain(|key, _| f(key));
+        self.sets.retain(|key, _| f(key));
+    }
+
+    /// Maps the keys of all the metrics, merging metrics which end up with the same key.
+    pub fn map_metrics(self, mut f: impl FnMut(AggregatedMetric) -> AggregatedMetric) -> Self {
+        let mut mapped = Self::default();
+        for (key, value) in self.counters {
+            *mapped.counters.entry(f(key)).or_default() += value;
+        }
+        for (key, other) in self.gauges {
+            mapped.gauges.entry(f(key)).or_default().merge(&other);
+        }
+        for (key, other) in self.distributions {
+            mapped.distributions.entry(f(key)).or_default().merge(other);
+        }
+        for (key, other) in self.sketches {
+            match mapped.sketches.entry(f(key)) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(&other),
+                Entry::Vacant(entry) => {
+                    entry.insert(other);
+                }
+            }
+        }
+        for (key, other) in self.sets {
+            match mapped.sets.entry(f(key)) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(&other),
+                Entry::Vacant(entry) => {
+                    entry.insert(other);
+                }
+            }
+        }
+        mapped
+    }
+
+    /// Merges the values of all the metric handle cells into `self`.
+    ///
+    /// Cells which are no longer referenced by any handle are removed after merging their values.
+    pub(crate) fn merge_cells(
+        &mut self,
+        cells: &Mutex<Vec<Arc<MetricCell>>>,
+        limiter: &mut CardinalityLimiter,
+    ) {
+        let mut cells = cells.lock().unwrap_or_else(|err| err.into_inner());
+        cells.retain(|cell| {
+            let is_referenced = Arc::strong_count(cell) > 1;
+            let Some(values) = cell.take() else {
+                return is_referenced;
+            };
+            let key = AggregatedMetric {
+                meta: *cell.key().meta,
+                tag_values: cell.key().tag_values.clone(),
+            };
+            match cell.key().ty() {
+                MetricType::Counter => {
+                    if let Some(key) = limiter.limit(key, |key| self.counters.contains_key(key)) {
+                        *self.counters.entry(key).or_default() += values.sum;
+                    }
+                }
+                _ => {
+                    if let Some(key) = limiter.limit(key, |key| self.gauges.contains_key(key)) {
+                        let other = AggregatedGauge {
+                            min: values.min,
+                            max: values.max,
+                            sum: values.sum,
+                            count: values.count,
+                            last: values.last,
+                            updated: values.updated,
+                        };
+                        self.gauges.entry(key).or_default().merge(&other);
+                    }
+                }
+            }
+            is_referenced
+        });
+    }
+
     /// Merges all the aggregates into `self`.
+    #[cfg(test)]
     pub(crate) fn merge_aggregations(&mut self, aggregations: &mut PreAggregations) {
+        self.merge_limited(aggregations, &mut CardinalityLimiter::default());
+    }
+
+    /// Merges all the aggregates into `self`, enforcing the limits of the [`CardinalityLimiter`].
+    pub(crate) fn merge_limited(
+        &mut self,
+        aggregations: &mut PreAggregations,
+        limiter: &mut CardinalityLimiter,
+    ) {
+        let series = std::mem::take(&mut aggregations.series);
+        limiter.merge_dropped(series.dropped);
+
         for (key, value) in aggregations.counters.drain() {
             let key = key.into_metric();
+            let Some(key) = limiter.limit(key, |key| self.counters.contains_key(key)) else {
+                continue;
+            };
             *self.counters.entry(key).or_default() += value;
         }
 
         for (key, other) in aggregations.gauges.drain() {
             let key = key.into_metric();
-            let gauge = self.gauges.entry(key).or_default();
-
-            gauge.min = gauge.min.min(other.min);
-            gauge.max = gauge.max.max(other.max);
-            gauge.sum += other.sum;
-            gauge.count += other.count;
-            gauge.last = other.last;
+            let Some(key) = limiter.limit(key, |key| self.gauges.contains_key(key)) else {
+                continue;
+            };
+            self.gauges.entry(key).or_default().merge(&other);
         }
 
         for (key, other) in aggregations.distributions.drain() {
             let key = key.into_metric();
-            self.distributions
-                .entry(key)
-                .or_default()
-                .values
-                .extend(other.values);
+            let Some(key) = limiter.limit(key, |key| {
+                self.distributions.contains_key(key) || self.sketches.contains_key(key)
+            }) else {
+                continue;
+            };
+            match other {
+                LocalDistribution::Precise(other) => {
+                    self.distributions.entry(key).or_default().merge(other);
+                }
+                LocalDistribution::Sketch(other) => match self.sketches.entry(key) {
+                    Entry::Occupied(mut entry) => entry.get_mut().merge(&other),
+                    Entry::Vacant(entry) => {
+                        entry.insert(other);
+                    }
+                },
+            }
+        }
+
+        for (key, other) in aggregations.sets.drain() {
+            let key = key.into_metric();
+            let Some(key) = limiter.limit(key, |key| self.sets.contains_key(key)) else {
+                continue;
+            };
+            match self.sets.entry(key) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(&other),
+                Entry::Vacant(entry) => {
+                    entry.insert(other);
+                }
+            }
+        }
+    }
+}
+
+/// Counts the distinct series per metric key, and overall, along with the dropped series.
+#[derive(Default)]
+pub(crate) struct SeriesCounts {
+    total: usize,
+    metrics: HashMap<&'static str, usize>,
+    /// The distinct dropped series per metric key, identified by their hash.
+    ///
+    /// These are counted using an [`AggregatedSet`], so the same series dropped on multiple
+    /// threads is only counted once, while still using bounded memory.
+    dropped: HashMap<&'static str, AggregatedSet>,
+}
+
+impl SeriesCounts {
+    /// Counts a new series, returning `false` if it exceeds the limits of the `config`.
+    ///
+    /// Series exceeding the limits are not counted, but rather recorded as dropped.
+    fn admit(
+        &mut self,
+        config: &AggregatorConfig,
+        meta: &MetricMeta,
+        tag_values: &TagValues,
+    ) -> bool {
+        // series which were already collapsed on a thread are always accepted
+        let is_overflow = |values: &[Str24]| values.iter().all(|v| v == OVERFLOW_TAG_VALUE);
+        if config.overflow == CardinalityOverflow::Collapse
+            && tag_values.as_deref().is_some_and(is_overflow)
+        {
+            return true;
+        }
+
+        let name = meta.key();
+        let metric_limit = config
+            .metric_max_series
+            .get(name)
+            .copied()
+            .or(config.max_series_per_metric);
+        let metric_series = self.metrics.entry(name).or_default();
+        let exceeds_metric = metric_limit.is_some_and(|limit| *metric_series >= limit);
+        let exceeds_total = config.max_series.is_some_and(|limit| self.total >= limit);
+
+        if !exceeds_metric && !exceeds_total {
+            *metric_series += 1;
+            self.total += 1;
+            return true;
+        }
+
+        let hash = FxBuildHasher.hash_one((meta, tag_values));
+        self.dropped.entry(name).or_default().insert(hash);
+        false
+    }
+}
+
+/// Returns the tag values to collapse a series exceeding the cardinality limits into,
+/// or [`None`] if the series should be dropped.
+fn overflow_tag_values(config: &AggregatorConfig, tag_values: &TagValues) -> Option<TagValues> {
+    match (config.overflow, tag_values) {
+        (CardinalityOverflow::Collapse, Some(tag_values)) => {
+            let overflow = Str24::new(OVERFLOW_TAG_VALUE);
+            Some(Some(tag_values.iter().map(|_| overflow.clone()).collect()))
+        }
+        _ => None,
+    }
+}
+
+/// Enforces the cardinality limits of the [`AggregatorConfig`] while merging [`Aggregations`].
+///
+/// This counts the distinct series per metric key, and overall, within a single flush.
+#[derive(Default)]
+pub(crate) struct CardinalityLimiter<'a> {
+    config: Option<&'a AggregatorConfig>,
+    series: SeriesCounts,
+}
+
+impl<'a> CardinalityLimiter<'a> {
+    pub fn new(config: &'a AggregatorConfig) -> Self {
+        Self {
+            config: config.has_limits().then_some(config),
+            ..Default::default()
+        }
+    }
+
+    /// Checks whether the series of `key` is within the limits.
+    ///
+    /// Series which are already `exists`ing are always within the limits.
+    /// Returns the key to merge the series into, or [`None`] if the series should be dropped.
+    fn limit(
+        &mut self,
+        key: AggregatedMetric,
+        exists: impl FnOnce(&AggregatedMetric) -> bool,
+    ) -> Option<AggregatedMetric> {
+        let Some(config) = self.config else {
+            return Some(key);
+        };
+        if exists(&key) || self.series.admit(config, &key.meta, &key.tag_values) {
+            return Some(key);
+        }
+        let tag_values = overflow_tag_values(config, &key.tag_values)?;
+        Some(AggregatedMetric {
+            meta: key.meta,
+            tag_values,
+        })
+    }
+
+    /// Merges the series which were already dropped when being added to a thread.
+    fn merge_dropped(&mut self, dropped: HashMap<&'static str, AggregatedSet>) {
+        for (name, dropped) in dropped {
+            self.series.dropped.entry(name).or_default().merge(&dropped);
+        }
+    }
+
+    /// Records the self-telemetry counter of dropped series into the `aggregations`.
+    pub fn record_dropped(self, aggregations: &mut Aggregations) {
+        for (name, dropped) in self.series.dropped {
+            let key = AggregatedMetric {
+                meta: DROPPED_SERIES.meta,
+                tag_values: Some(Box::new([Str24::new(name)])),
+            };
+            *aggregations.counters.entry(key).or_default() += dropped.cardinality() as f64;
         }
     }
 }
@@ -1,19 +1,117 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use reqwest::header;
-use tokio::runtime::Handle;
+use reqwest::StatusCode;
+use tokio::runtime::{Handle, RuntimeFlavor};
 use tokio::task::JoinHandle;
 use zstd::stream::raw::{Encoder, Operation};
 use zstd::zstd_safe::{InBuffer, OutBuffer};
 
+use self::spill::Spill;
+use self::transport::{REQUEST_TIMEOUT, ReqwestTransport};
+use crate::protobuf::ProtoBuf;
 use crate::{
-    AggregatedMetric, AggregationSink, Aggregations, Dispatcher, MetricMeta, MetricType,
-    MetricUnit, ThreadLocalAggregator, set_global_dispatcher,
+    AggregatedMetric, AggregationSink, Aggregations, AggregatorConfig, DDSketch, Dispatcher,
+    DistributionAggregation, MetricMeta, MetricType, MetricUnit, ThreadLocalAggregator,
+    set_global_dispatcher,
 };
 
+mod spill;
+mod transport;
+
+pub use transport::{HttpFuture, HttpRequest, HttpResponse, HttpTransport};
+
 type DatadogAggregator = Arc<ThreadLocalAggregator<io::Result<Vec<JoinHandle<()>>>>>;
+type EventHandler = Arc<dyn Fn(&DatadogEvent) + Send + Sync>;
+
+/// The outcome of submitting a payload to Datadog.
+///
+/// These events are reported to the handler configured via [`DatadogBuilder::on_event`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DatadogEvent {
+    /// The payload was successfully submitted.
+    Submitted {
+        /// The API endpoint the payload was submitted to.
+        endpoint: &'static str,
+        /// The HTTP status of the response.
+        status: u16,
+        /// The size of the compressed payload.
+        bytes: usize,
+        /// The number of attempts it took to submit the payload.
+        attempts: u32,
+    },
+    /// The payload was dropped, either because of a non-retryable error,
+    /// or because the retry deadline was exceeded.
+    Dropped {
+        /// The API endpoint the payload was submitted to.
+        endpoint: &'static str,
+        /// The HTTP status of the last response, if any.
+        status: Option<u16>,
+        /// The size of the compressed payload.
+        bytes: usize,
+        /// The number of attempts made to submit the payload.
+        attempts: u32,
+        /// A description of the last error.
+        error: String,
+    },
+    /// The payload could not be submitted, and was stored in the spill directory
+    /// to be replayed later, see [`DatadogBuilder::spill_directory`].
+    Spilled {
+        /// The API endpoint the payload is destined for.
+        endpoint: &'static str,
+        /// The size of the compressed payload.
+        bytes: usize,
+    },
+}
+
+impl fmt::Display for DatadogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatadogEvent::Submitted {
+                endpoint,
+                status,
+                bytes,
+                attempts,
+            } => write!(
+                f,
+                "submitted metrics to datadog (endpoint={endpoint}, status={status}, bytes={bytes}, attempts={attempts})"
+            ),
+            DatadogEvent::Dropped {
+                endpoint,
+                status,
+                bytes,
+                attempts,
+                error,
+            } => {
+                write!(
+                    f,
+                    "error submitting metrics to datadog (endpoint={endpoint}, "
+                )?;
+                if let Some(status) = status {
+                    write!(f, "status={status}, ")?;
+                }
+                write!(f, "bytes={bytes}, attempts={attempts}, err={error})")
+            }
+            DatadogEvent::Spilled { endpoint, bytes } => write!(
+                f,
+                "spilled metrics to disk (endpoint={endpoint}, bytes={bytes})"
+            ),
+        }
+    }
+}
+
+/// The default event handler, which logs dropped payloads to stderr.
+fn log_dropped(event: &DatadogEvent) {
+    if let DatadogEvent::Dropped { .. } = event {
+        eprintln!("merni: {event}");
+    }
+}
 
 /// Creates a [`DatadogBuilder`] with sensible defaults.
 ///
@@ -40,7 +138,16 @@ pub fn datadog<'a>(api_key: impl Into<Option<&'a str>>) -> DatadogBuilder {
         ddog_site,
 
         prefix: String::new(),
-        global_tags: String::new(),
+        global_tags: Vec::new(),
+        sketches: false,
+
+        retry_deadline: Duration::from_secs(30),
+        on_event: Arc::new(log_dropped),
+        transport: None,
+
+        spill_directory: None,
+        spill_max_size: 100 * 1024 * 1024,
+        spill_max_age: Duration::from_secs(60 * 60),
     }
 }
 
@@ -53,7 +160,16 @@ pub struct DatadogBuilder {
     ddog_site: io::Result<Option<String>>,
 
     prefix: String,
-    global_tags: String,
+    global_tags: Vec<String>,
+    sketches: bool,
+
+    retry_deadline: Duration,
+    on_event: EventHandler,
+    transport: Option<Arc<dyn HttpTransport>>,
+
+    spill_directory: Option<PathBuf>,
+    spill_max_size: u64,
+    spill_max_age: Duration,
 }
 
 impl DatadogBuilder {
@@ -69,12 +185,23 @@ impl DatadogBuilder {
     ///
     /// For example, this could be something like `"hostname"`, or similar.
     pub fn global_tag(mut self, key: &str, value: &str) -> Self {
-        if !self.global_tags.is_empty() {
-            self.global_tags.push(',');
-        }
-        let formatted_tag = format!("{key}:{value}");
-        let formatted_tag = serde_json::to_string(&formatted_tag).unwrap();
-        self.global_tags.push_str(&formatted_tag);
+        self.global_tags.push(format!("{key}:{value}"));
+        self
+    }
+
+    /// Submits distributions as sketches to the Datadog sketches API.
+    ///
+    /// Sketches are a lot smaller than the raw points submitted to the distribution points API,
+    /// at the cost of a relative accuracy of about 1% for percentiles.
+    /// When enabled, [`try_init`](Self::try_init) also aggregates distributions into sketches,
+    /// see [`DistributionAggregation::Sketch`].
+    ///
+    /// Even when disabled, distributions with sampled values or aggregated into sketches are
+    /// submitted as sketches, as the distribution points API does not support weighted values.
+    ///
+    /// This is disabled by default.
+    pub fn sketches(mut self, sketches: bool) -> Self {
+        self.sketches = sketches;
         self
     }
 
@@ -100,17 +227,86 @@ impl DatadogBuilder {
         self
     }
 
+    /// Sets the deadline for retrying failed submissions.
+    ///
+    /// Submissions failing with a connection error, a timeout, a `429` or a `5xx` status are retried
+    /// with exponential backoff, honoring the `Retry-After` header.
+    /// Requests still pending at the deadline are cancelled, and payloads which could not
+    /// be submitted within the deadline are dropped.
+    ///
+    /// This defaults to 30 seconds.
+    pub fn retry_deadline(mut self, retry_deadline: Duration) -> Self {
+        self.retry_deadline = retry_deadline;
+        self
+    }
+
+    /// Sets a handler which is called with the outcome of each submitted payload.
+    ///
+    /// By default, dropped payloads are logged to stderr.
+    pub fn on_event(mut self, on_event: impl Fn(&DatadogEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Arc::new(on_event);
+        self
+    }
+
+    /// Sets a directory in which payloads that could not be submitted are stored.
+    ///
+    /// Payloads which failed because of a transient error, even after retrying them,
+    /// are stored in this directory, and replayed in order once submissions succeed again.
+    /// This also includes payloads stored by previous runs.
+    ///
+    /// Spilling payloads to disk is disabled by default.
+    pub fn spill_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.spill_directory = Some(directory.into());
+        self
+    }
+
+    /// Sets the maximum total size of the payloads stored in the spill directory.
+    ///
+    /// The oldest payloads are dropped once this size would be exceeded.
+    /// This defaults to 100 MiB.
+    pub fn spill_max_size(mut self, max_size: u64) -> Self {
+        self.spill_max_size = max_size;
+        self
+    }
+
+    /// Sets the maximum age of payloads stored in the spill directory.
+    ///
+    /// Older payloads are dropped instead of being replayed, as Datadog does not accept
+    /// metrics with timestamps too far in the past.
+    /// This defaults to 1 hour.
+    pub fn spill_max_age(mut self, max_age: Duration) -> Self {
+        self.spill_max_age = max_age;
+        self
+    }
+
+    /// Sets the [`HttpTransport`] used to submit payloads.
+    ///
+    /// This defaults to sending requests using `reqwest`.
+    pub fn transport(mut self, transport: impl HttpTransport) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
     /// Turns the builder into a [`DatadogSink`].
     pub fn into_sink(self) -> io::Result<DatadogSink> {
         let runtime = self.runtime.unwrap_or_else(Handle::current);
         let api_key = self.api_key?;
         let ddog_site = self.ddog_site?;
 
-        Ok(DatadogSink {
-            runtime,
-            client: reqwest::ClientBuilder::new()
-                .build()
-                .map_err(io::Error::other)?,
+        let mut global_tags = String::new();
+        for tag in &self.global_tags {
+            if !global_tags.is_empty() {
+                global_tags.push(',');
+            }
+            global_tags.push_str(&serde_json::to_string(tag).map_err(io::Error::other)?);
+        }
+
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Arc::new(ReqwestTransport::new()?),
+        };
+        let submitter = Submitter {
+            transport,
             api_key,
             ddog_site: ddog_site
                 .as_deref()
@@ -118,12 +314,26 @@ impl DatadogBuilder {
                 .trim_end_matches('/')
                 .into(),
 
+            retry_deadline: self.retry_deadline,
+            on_event: self.on_event,
+            spill: self
+                .spill_directory
+                .map(|directory| Spill::new(directory, self.spill_max_size, self.spill_max_age))
+                .map(Arc::new),
+        };
+
+        Ok(DatadogSink {
+            runtime,
+            submitter: Arc::new(submitter),
+
             join_handles: Vec::new(),
 
             metric_buf: Vec::with_capacity(MAX_COMPRESSED),
             scratch_buf: String::new(),
             prefix: self.prefix,
-            global_tags: self.global_tags,
+            global_tags,
+            sketch_tags: self.global_tags,
+            sketches: self.sketches,
 
             flush_interval_secs: self.flush_interval.as_secs(),
             next_flush_len: MAX_COMPRESSED - THRESHOLD,
@@ -135,20 +345,28 @@ impl DatadogBuilder {
 
     /// Initializes the datadog sink and aggregator, registering it as a global dispatcher.
     pub fn try_init(self) -> io::Result<DatadogFlusher> {
-        let flush_interval = self.flush_interval;
+        let mut config = AggregatorConfig::new(self.flush_interval);
+        if self.sketches {
+            config = config.distributions(DistributionAggregation::Sketch);
+        }
+        let runtime = self.runtime.clone().unwrap_or_else(Handle::current);
         let datadog = self.into_sink()?;
 
-        let aggregator = Arc::new(ThreadLocalAggregator::new(flush_interval, datadog));
+        let aggregator = Arc::new(ThreadLocalAggregator::with_config(config, datadog));
         let dispatcher = Dispatcher::new(Arc::clone(&aggregator));
         set_global_dispatcher(dispatcher)
             .map_err(|_| io::Error::other("unable to set global dispatcher"))?;
-        Ok(DatadogFlusher { aggregator })
+        Ok(DatadogFlusher {
+            aggregator,
+            runtime,
+        })
     }
 }
 
 /// This is a wrapper struct that allows flushing aggregated metrics to Datadog.
 pub struct DatadogFlusher {
     aggregator: DatadogAggregator,
+    runtime: Handle,
 }
 impl DatadogFlusher {
     /// Flushes aggregated metrics to datadog
@@ -160,14 +378,87 @@ impl DatadogFlusher {
 
         Ok(())
     }
+
+    /// Stops the aggregator after a final flush, and waits for the submissions of that flush.
+    ///
+    /// The `timeout` applies to both the final flush and its submissions, including their retries.
+    /// See [`ThreadLocalAggregator::shutdown`].
+    pub async fn shutdown(&self, timeout: Option<Duration>) -> io::Result<()> {
+        shutdown(&self.aggregator, timeout).await
+    }
+
+    /// Turns the flusher into a guard which [`shutdown`](Self::shutdown)s when being dropped.
+    ///
+    /// The guard can be held in `main`, to submit the last metrics before the process exits.
+    /// Waiting for the submissions while blocking in `drop` requires a multi-threaded tokio runtime,
+    /// so this fails with [`io::ErrorKind::Unsupported`] for any other runtime.
+    /// Call [`shutdown`](Self::shutdown) explicitly in that case.
+    pub fn shutdown_guard(self, timeout: Duration) -> io::Result<DatadogShutdownGuard> {
+        if self.runtime.runtime_flavor() != RuntimeFlavor::MultiThread {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the shutdown guard requires a multi-threaded runtime",
+            ));
+        }
+        Ok(DatadogShutdownGuard {
+            flusher: self,
+            timeout,
+        })
+    }
+}
+
+async fn shutdown(aggregator: &DatadogAggregator, timeout: Option<Duration>) -> io::Result<()> {
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    // The shutdown blocks until the final flush is done, which must not happen on a runtime worker.
+    let aggregator = Arc::clone(aggregator);
+    let tasks = tokio::task::spawn_blocking(move || aggregator.shutdown(timeout))
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)??;
+    for task in tasks {
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, task)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => task.await,
+        };
+        result.map_err(io::Error::other)?;
+    }
+
+    Ok(())
+}
+
+/// A guard which shuts down the [`DatadogFlusher`] when being dropped.
+///
+/// See [`DatadogFlusher::shutdown_guard`].
+pub struct DatadogShutdownGuard {
+    flusher: DatadogFlusher,
+    timeout: Duration,
+}
+
+impl Drop for DatadogShutdownGuard {
+    fn drop(&mut self) {
+        let DatadogFlusher {
+            aggregator,
+            runtime,
+        } = &self.flusher;
+        // The shutdown runs on a separate thread, as this thread might be a runtime worker,
+        // which can not block on the runtime itself.
+        let (aggregator, runtime, timeout) =
+            (Arc::clone(aggregator), runtime.clone(), self.timeout);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = runtime.block_on(shutdown(&aggregator, Some(timeout)));
+            let _ = sender.send(());
+        });
+        let _ = receiver.recv_timeout(timeout);
+    }
 }
 
 /// An aggregator sink which pushes metrics to Datadog, using the Datadog API.
 pub struct DatadogSink {
     runtime: Handle,
-    client: reqwest::Client,
-    api_key: String,
-    ddog_site: String,
+    submitter: Arc<Submitter>,
 
     join_handles: Vec<JoinHandle<()>>,
 
@@ -175,6 +466,8 @@ pub struct DatadogSink {
     scratch_buf: String,
     prefix: String,
     global_tags: String,
+    sketch_tags: Vec<String>,
+    sketches: bool,
 
     flush_interval_secs: u64,
     next_flush_len: usize,
@@ -197,8 +490,64 @@ const MAX_UNCOMPRESSED: usize = 5242880;
 const BYTES_PER_POINT: usize = 20;
 
 const DD_SITE: &str = "https://api.datadoghq.com";
-const DISTRIBUTION_ENDPOINT: &str = "/api/v1/distribution_points";
-const METRICS_ENDPOINT: &str = "/api/v2/series";
+
+/// The Datadog API endpoints metrics are being submitted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    /// The `/api/v2/series` endpoint for counters and gauges.
+    Metrics,
+    /// The `/api/v1/distribution_points` endpoint for distributions.
+    Distributions,
+    /// The `/api/beta/sketches` endpoint for distributions submitted as sketches.
+    Sketches,
+}
+
+impl Endpoint {
+    fn path(self) -> &'static str {
+        match self {
+            Endpoint::Metrics => "/api/v2/series",
+            Endpoint::Distributions => "/api/v1/distribution_points",
+            Endpoint::Sketches => "/api/beta/sketches",
+        }
+    }
+
+    /// The name of the endpoint, as used in the names of spilled payloads.
+    fn name(self) -> &'static str {
+        match self {
+            Endpoint::Metrics => "series",
+            Endpoint::Distributions => "distribution_points",
+            Endpoint::Sketches => "sketches",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            Endpoint::Metrics,
+            Endpoint::Distributions,
+            Endpoint::Sketches,
+        ]
+        .into_iter()
+        .find(|endpoint| endpoint.name() == name)
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Endpoint::Sketches => "application/x-protobuf",
+            _ => "application/json",
+        }
+    }
+
+    /// The trailer closing the payload.
+    ///
+    /// The JSON endpoints wrap all the metrics in a `series` array,
+    /// whereas the `SketchPayload` protobuf message is just a concatenation of `Sketch` messages.
+    fn trailer(self) -> &'static [u8] {
+        match self {
+            Endpoint::Sketches => b"",
+            _ => br#"]}"#,
+        }
+    }
+}
 
 impl DatadogSink {
     fn emit_metrics(&mut self, metrics: Aggregations) -> io::Result<Vec<JoinHandle<()>>> {
@@ -213,33 +562,65 @@ impl DatadogSink {
         for (meta, value) in metrics.gauges {
             self.push_metric(&meta, timestamp, value.last)?;
         }
-        self.flush(METRICS_ENDPOINT)?;
+        for (meta, set) in metrics.sets {
+            self.push_metric(&meta, timestamp, set.cardinality() as f64)?;
+        }
+        self.flush(Endpoint::Metrics)?;
+
+        // The distribution points API does not support weighted values,
+        // so sampled distributions are always submitted as sketches.
+        let (points, weighted): (Vec<_>, Vec<_>) = metrics
+            .distributions
+            .into_iter()
+            .partition(|(_, distribution)| !self.sketches && distribution.weights.is_empty());
+        for (meta, distribution) in points {
+            self.push_distribution_values(&meta, timestamp, &distribution.values)?;
+        }
+        self.flush(Endpoint::Distributions)?;
 
-        for (meta, values) in metrics.distributions {
-            let mut rest = values.values.as_slice();
-            while !rest.is_empty() {
-                let num_values = (self.next_flush_len / BYTES_PER_POINT).min(rest.len());
-                let values = rest.split_off(..num_values).unwrap();
-                self.push_distribution(&meta, timestamp, values)?;
+        for (meta, distribution) in weighted {
+            let mut sketch = DDSketch::new();
+            for (value, weight) in distribution.weighted_values() {
+                sketch.add_with_count(value, weight);
             }
+            self.push_sketch(&meta, timestamp, &sketch)?;
+        }
+        for (meta, sketch) in metrics.sketches {
+            self.push_sketch(&meta, timestamp, &sketch)?;
         }
-        self.flush(DISTRIBUTION_ENDPOINT)?;
+        self.flush(Endpoint::Sketches)?;
 
         Ok(std::mem::take(&mut self.join_handles))
     }
 
-    fn flush(&mut self, endpoint: &str) -> io::Result<()> {
+    fn push_distribution_values(
+        &mut self,
+        meta: &AggregatedMetric,
+        timestamp: u64,
+        mut rest: &[f64],
+    ) -> io::Result<()> {
+        while !rest.is_empty() {
+            // The values still buffered count towards the next flush as well.
+            let available = self.next_flush_len.saturating_sub(self.metric_buf.len());
+            let num_values = (available / BYTES_PER_POINT).clamp(1, rest.len());
+            let values = rest.split_off(..num_values).unwrap();
+            self.push_distribution(meta, timestamp, values)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, endpoint: Endpoint) -> io::Result<()> {
         if self.metric_buf.is_empty() && self.bytes_written == 0 {
             return Ok(());
         }
-        self.metric_buf.extend_from_slice(br#"]}"#);
+        self.metric_buf.extend_from_slice(endpoint.trailer());
 
         self.flush_to_zstd()?;
         self.do_flush(endpoint)?;
 
         Ok(())
     }
-    fn maybe_flush(&mut self, endpoint: &str) -> io::Result<()> {
+    fn maybe_flush(&mut self, endpoint: Endpoint) -> io::Result<()> {
         if self.metric_buf.len() >= self.next_flush_len {
             self.flush_to_zstd()?;
         }
@@ -255,7 +636,7 @@ impl DatadogSink {
             .min(uncompressed_left)
             .saturating_sub(THRESHOLD);
         if self.next_flush_len < THRESHOLD {
-            self.metric_buf.extend_from_slice(br#"]}"#);
+            self.metric_buf.extend_from_slice(endpoint.trailer());
             self.flush_to_zstd()?;
             self.do_flush(endpoint)?;
         }
@@ -274,31 +655,16 @@ impl DatadogSink {
 
         Ok(())
     }
-    fn do_flush(&mut self, endpoint: &str) -> io::Result<()> {
+    fn do_flush(&mut self, endpoint: Endpoint) -> io::Result<()> {
         let output_len = self.compression_buffer.len();
         let mut output = OutBuffer::around_pos(&mut self.compression_buffer, output_len);
         self.cctx.finish(&mut output, true)?;
         self.cctx.reinit()?;
 
-        let request = self
-            .client
-            .post(format!("{}{endpoint}", self.ddog_site))
-            .header("DD-API-KEY", &self.api_key)
-            .header(header::ACCEPT, "application/json")
-            .header(header::CONTENT_ENCODING, "zstd")
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(self.compression_buffer.clone())
-            .send();
-
-        self.join_handles.push(self.runtime.spawn(async move {
-            // TODO: handle errors happening during error handling
-            let response = request.await.unwrap();
-            if let Err(err) = response.error_for_status_ref() {
-                let response_text = response.text().await.unwrap();
-
-                eprintln!("merni: error submitting metrics to datadog (err={err}, response={response_text})");
-            }
-        }));
+        let submitter = Arc::clone(&self.submitter);
+        let body = self.compression_buffer.clone();
+        self.join_handles
+            .push(self.runtime.spawn(submitter.submit(endpoint, body)));
 
         self.bytes_written = 0;
         self.compression_buffer.clear();
@@ -320,7 +686,7 @@ impl DatadogSink {
             r#""points":[{{"timestamp":{timestamp},"value":{value}}}]}}"#
         ))?;
 
-        self.maybe_flush(METRICS_ENDPOINT)
+        self.maybe_flush(Endpoint::Metrics)
     }
 
     fn push_distribution(
@@ -337,7 +703,40 @@ impl DatadogSink {
         serde_json::to_writer(&mut self.metric_buf, values).map_err(io::Error::other)?;
         self.metric_buf.extend_from_slice(br#"]]}"#);
 
-        self.maybe_flush(DISTRIBUTION_ENDPOINT)
+        self.maybe_flush(Endpoint::Distributions)
+    }
+
+    /// Writes a `Sketch` message, as part of a `SketchPayload`.
+    fn push_sketch(
+        &mut self,
+        meta: &AggregatedMetric,
+        timestamp: u64,
+        sketch: &DDSketch,
+    ) -> io::Result<()> {
+        // all the values might have been non-finite
+        if sketch.count() == 0 {
+            return Ok(());
+        }
+        self.scratch_buf.clear();
+        self.scratch_buf.push_str(&self.prefix);
+        self.scratch_buf.push_str(meta.key());
+
+        let mut payload = ProtoBuf {
+            buf: std::mem::take(&mut self.metric_buf),
+        };
+        payload.message(1, |msg| {
+            msg.string(1, &self.scratch_buf);
+            for tag in &self.sketch_tags {
+                msg.string(4, tag);
+            }
+            for (key, value) in meta.tags() {
+                msg.string(4, &format!("{key}:{value}"));
+            }
+            msg.message(7, |dogsketch| write_dogsketch(dogsketch, timestamp, sketch));
+        });
+        self.metric_buf = payload.buf;
+
+        self.maybe_flush(Endpoint::Sketches)
     }
 
     fn write_begin(&mut self) {
@@ -391,14 +790,15 @@ impl DatadogSink {
         self.metric_buf.extend_from_slice(br#""type":"#);
         let ty = match meta.ty() {
             MetricType::Counter => b'1',
-            MetricType::Gauge => b'3',
+            // sets are submitted as a gauge of their cardinality
+            MetricType::Gauge | MetricType::Set => b'3',
             _ => b'0',
         };
         self.metric_buf.push(ty);
         self.metric_buf.push(b',');
-        if meta.unit() != MetricUnit::Unknown {
+        if let Some(unit) = unit_name(meta) {
             self.metric_buf.extend_from_slice(br#""unit":"#);
-            serde_json::to_writer(&mut self.metric_buf, &meta.unit()).map_err(io::Error::other)?;
+            serde_json::to_writer(&mut self.metric_buf, unit).map_err(io::Error::other)?;
             self.metric_buf.push(b',');
         }
 
@@ -406,50 +806,722 @@ impl DatadogSink {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::tags::record_tags;
-
-//     use super::*;
-
-//     #[test]
-//     fn builds_metrics_request() {
-//         let mut sink = DatadogSink::new().unwrap();
-
-//         sink.push_metric(
-//             &AggregatedMetric {
-//                 meta: MetricMeta::new(MetricType::Counter, MetricUnit::Bytes, "some.bytes"),
-//                 tag_values: Default::default(),
-//             },
-//             12345,
-//             123.45,
-//         )
-//         .unwrap();
-
-//         sink.push_metric(
-//             &AggregatedMetric {
-//                 meta: MetricMeta::new(MetricType::Gauge, MetricUnit::Unknown, "a.gauge")
-//                     .with_tags(&["a_tag"])
-//                     .meta,
-//                 tag_values: record_tags(&[&"a_value"]),
-//             },
-//             12346,
-//             1234.567,
-//         )
-//         .unwrap();
-
-//         sink.flush(METRICS_ENDPOINT).unwrap();
-
-//         sink.push_distribution(
-//             &AggregatedMetric {
-//                 meta: MetricMeta::new(MetricType::Distribution, MetricUnit::Seconds, "a.timer"),
-//                 tag_values: Default::default(),
-//             },
-//             12346,
-//             &[1., 2., 3., 4.],
-//         )
-//         .unwrap();
-
-//         sink.flush(DISTRIBUTION_ENDPOINT).unwrap();
-//     }
-// }
+/// The unit of the metric, using the names of the Datadog unit catalog.
+fn unit_name(meta: &MetricMeta) -> Option<&'static str> {
+    Some(match meta.value_unit() {
+        MetricUnit::Nanoseconds => "nanosecond",
+        MetricUnit::Microseconds => "microsecond",
+        MetricUnit::Milliseconds => "millisecond",
+        MetricUnit::Seconds => "second",
+        MetricUnit::Bytes => "byte",
+        MetricUnit::Kibibytes => "kibibyte",
+        MetricUnit::Mebibytes => "mebibyte",
+        MetricUnit::Percent => "percent",
+        MetricUnit::Ratio => "fraction",
+        MetricUnit::Custom(unit) => unit,
+        _ => return None,
+    })
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Submits payloads to the Datadog API, retrying transient failures.
+struct Submitter {
+    transport: Arc<dyn HttpTransport>,
+    api_key: String,
+    ddog_site: String,
+
+    retry_deadline: Duration,
+    on_event: EventHandler,
+    spill: Option<Arc<Spill>>,
+}
+
+/// A failed attempt to submit a payload.
+struct Failure {
+    status: Option<u16>,
+    attempts: u32,
+    error: String,
+    /// Whether the failure is transient, and submitting the payload might succeed later.
+    transient: bool,
+}
+
+impl Submitter {
+    /// Submits the payload, spilling it to disk if it could not be submitted.
+    ///
+    /// Once a payload was successfully submitted, this also starts replaying previously spilled payloads
+    /// in a separate task, so that flushes do not wait for the whole backlog.
+    async fn submit(self: Arc<Self>, endpoint: Endpoint, body: Vec<u8>) {
+        let bytes = body.len();
+        match self.send(endpoint, &body, self.retry_deadline).await {
+            Ok((status, attempts)) => {
+                (self.on_event)(&DatadogEvent::Submitted {
+                    endpoint: endpoint.path(),
+                    status,
+                    bytes,
+                    attempts,
+                });
+                if self.spill.is_some() {
+                    tokio::spawn(self.replay());
+                }
+            }
+            Err(failure) => match &self.spill {
+                Some(spill) if failure.transient => match spill
+                    .blocking(move |spill| spill.store(endpoint, &body))
+                    .await
+                {
+                    Ok(evicted) => {
+                        (self.on_event)(&DatadogEvent::Spilled {
+                            endpoint: endpoint.path(),
+                            bytes,
+                        });
+                        for entry in evicted {
+                            let error = "evicted from spill directory".into();
+                            self.dropped(entry.endpoint, entry.size as usize, 0, None, error);
+                        }
+                    }
+                    Err(err) => {
+                        let error = format!("{}, spill error={err}", failure.error);
+                        self.dropped(endpoint, bytes, failure.attempts, failure.status, error);
+                    }
+                },
+                _ => self.dropped(
+                    endpoint,
+                    bytes,
+                    failure.attempts,
+                    failure.status,
+                    failure.error,
+                ),
+            },
+        }
+    }
+
+    /// Replays the spilled payloads in order, stopping at the first transient failure.
+    async fn replay(self: Arc<Self>) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        let Some(_guard) = spill.start_replay() else {
+            return;
+        };
+
+        let entries = spill.blocking(|spill| spill.entries()).await;
+        for entry in entries.unwrap_or_default() {
+            let size = entry.size as usize;
+            let remove = |path: PathBuf| spill.blocking(|_| std::fs::remove_file(path));
+            if spill.is_expired(&entry) {
+                let _ = remove(entry.path).await;
+                let error = "spilled payload expired".into();
+                self.dropped(entry.endpoint, size, 0, None, error);
+                continue;
+            }
+            let path = entry.path.clone();
+            let Ok(body) = spill.blocking(|_| std::fs::read(path)).await else {
+                continue;
+            };
+            match self.send(entry.endpoint, &body, Duration::ZERO).await {
+                Ok((status, attempts)) => {
+                    let _ = remove(entry.path).await;
+                    (self.on_event)(&DatadogEvent::Submitted {
+                        endpoint: entry.endpoint.path(),
+                        status,
+                        bytes: size,
+                        attempts,
+                    });
+                }
+                Err(failure) if failure.transient => break,
+                Err(failure) => {
+                    let _ = remove(entry.path).await;
+                    let Failure {
+                        status,
+                        attempts,
+                        error,
+                        ..
+                    } = failure;
+                    self.dropped(entry.endpoint, size, attempts, status, error);
+                }
+            }
+        }
+    }
+
+    /// Sends the payload, retrying transient failures until the `retry_deadline`.
+    ///
+    /// Attempts still in flight at the deadline are cancelled. A zero `retry_deadline` makes a
+    /// single attempt, bounded by the [`REQUEST_TIMEOUT`] instead.
+    /// Returns the response status and the number of attempts.
+    async fn send(
+        &self,
+        endpoint: Endpoint,
+        body: &[u8],
+        retry_deadline: Duration,
+    ) -> Result<(u16, u32), Failure> {
+        let deadline = Instant::now() + retry_deadline;
+        let attempt_deadline = match retry_deadline.is_zero() {
+            true => Instant::now() + REQUEST_TIMEOUT,
+            false => deadline,
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let request = HttpRequest {
+                url: format!("{}{}", self.ddog_site, endpoint.path()),
+                headers: vec![
+                    ("DD-API-KEY", self.api_key.clone()),
+                    ("Accept", "application/json".into()),
+                    ("Content-Encoding", "zstd".into()),
+                    ("Content-Type", endpoint.content_type().into()),
+                ],
+                body: body.to_vec(),
+            };
+
+            let response = self.transport.send(request);
+            let response = tokio::time::timeout_at(attempt_deadline.into(), response)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            let (status, retry_after, error, transient) = match response {
+                Ok(response) if (200..300).contains(&response.status) => {
+                    return Ok((response.status, attempts));
+                }
+                Ok(response) => {
+                    let retry_after = response
+                        .header("Retry-After")
+                        .and_then(|value| retry_after(value, SystemTime::now()));
+                    let status = StatusCode::from_u16(response.status).map_err(|err| Failure {
+                        status: Some(response.status),
+                        attempts,
+                        error: err.to_string(),
+                        transient: false,
+                    })?;
+                    let error = format!("{status}, response={}", response.body);
+                    let transient =
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+                    (Some(status.as_u16()), retry_after, error, transient)
+                }
+                Err(err) => {
+                    let transient = matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::TimedOut
+                    );
+                    (None, None, err.to_string(), transient)
+                }
+            };
+
+            let failure = Failure {
+                status,
+                attempts,
+                error,
+                transient,
+            };
+            if !transient {
+                return Err(failure);
+            }
+            let delay = jitter(backoff).max(retry_after.unwrap_or_default());
+            if Instant::now() + delay > deadline {
+                return Err(failure);
+            }
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn dropped(
+        &self,
+        endpoint: Endpoint,
+        bytes: usize,
+        attempts: u32,
+        status: Option<u16>,
+        error: String,
+    ) {
+        (self.on_event)(&DatadogEvent::Dropped {
+            endpoint: endpoint.path(),
+            status,
+            bytes,
+            attempts,
+            error,
+        })
+    }
+}
+
+/// Parses a `Retry-After` header, given either as a number of seconds, or as an HTTP date.
+///
+/// Only the `IMF-fixdate` format is supported for dates, like `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// and the obsolete date formats are ignored.
+fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let [_weekday, day, month, year, time, "GMT"] = *value.split(' ').collect::<Vec<_>>() else {
+        return None;
+    };
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == month)? as i64
+        + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let [hours, minutes, seconds] = *time.split(':').collect::<Vec<_>>() else {
+        return None;
+    };
+    let (hours, minutes, seconds): (i64, i64, i64) = (
+        hours.parse().ok()?,
+        minutes.parse().ok()?,
+        seconds.parse().ok()?,
+    );
+
+    // the days since the unix epoch, see <https://howardhinnant.github.io/date_algorithms.html>
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let timestamp = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+    let date = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.try_into().ok()?);
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Randomizes the given backoff to between 50% and 100% of its value.
+fn jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff.mul_f64(0.5 + 0.5 * (random as f64 / u64::MAX as f64))
+}
+
+/// Writes the contents of a `Dogsketch` message.
+fn write_dogsketch(buf: &mut ProtoBuf, timestamp: u64, sketch: &DDSketch) {
+    // The bin counts are `uint32`, so larger counts are split into multiple bins with the same key.
+    let bins = || {
+        sketch.bins().flat_map(|(key, count)| {
+            let full_bins = count / u32::MAX as u64;
+            let rest = count % u32::MAX as u64;
+            let full_bins = std::iter::repeat_n((key, u32::MAX as u64), full_bins as usize);
+            full_bins.chain((rest > 0).then_some((key, rest)))
+        })
+    };
+
+    buf.int64(1, timestamp as i64);
+    buf.int64(2, sketch.count() as i64);
+    buf.double(3, sketch.min());
+    buf.double(4, sketch.max());
+    buf.double(5, sketch.sum() / sketch.count() as f64);
+    buf.double(6, sketch.sum());
+    buf.packed_sint32(7, bins().map(|(key, _)| key as i32));
+    buf.packed_uint64(8, bins().map(|(_, count)| count));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::tags::record_tags;
+    use crate::testing::{CapturedRequest, CapturingTransport};
+
+    const ACCEPTED: &str =
+        "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    /// Responds to each incoming request with the next of the given raw HTTP responses.
+    ///
+    /// Returns the URL of the server, and a handle resolving to the received request bodies.
+    fn mock_datadog(
+        responses: &'static [&'static str],
+    ) -> (String, std::thread::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut bodies = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(len) = line.strip_prefix("content-length: ") {
+                        content_length = len.parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                stream.write_all(response.as_bytes()).unwrap();
+                bodies.push(body);
+            }
+            bodies
+        });
+        (format!("http://{addr}"), handle)
+    }
+
+    /// Creates a sink which records the outcome of its submissions.
+    fn recording_sink(builder: DatadogBuilder) -> (DatadogSink, Arc<Mutex<Vec<String>>>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink_events = Arc::clone(&events);
+        let sink = builder
+            .on_event(move |event| {
+                let outcome = match event {
+                    DatadogEvent::Submitted {
+                        status, attempts, ..
+                    } => format!("submitted {status} after {attempts}"),
+                    DatadogEvent::Dropped {
+                        status, attempts, ..
+                    } => format!("dropped {status:?} after {attempts}"),
+                    DatadogEvent::Spilled { .. } => "spilled".into(),
+                };
+                sink_events.lock().unwrap().push(outcome);
+            })
+            .into_sink()
+            .unwrap();
+        (sink, events)
+    }
+
+    async fn submit_counter(sink: &mut DatadogSink, value: f64) {
+        let meta = AggregatedMetric {
+            meta: MetricMeta::new(MetricType::Counter, MetricUnit::Unknown, "some.counter"),
+            tag_values: Default::default(),
+        };
+        sink.push_metric(&meta, 12345, value).unwrap();
+        sink.flush(Endpoint::Metrics).unwrap();
+        for task in std::mem::take(&mut sink.join_handles) {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_failed_submissions() {
+        let (ddog_site, server) = mock_datadog(&[
+            UNAVAILABLE,
+            ACCEPTED,
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 5\r\nConnection: close\r\n\r\nerror",
+        ]);
+        let (mut sink, events) = recording_sink(datadog("api-key").ddog_site(&ddog_site));
+
+        submit_counter(&mut sink, 1.).await;
+        submit_counter(&mut sink, 2.).await;
+
+        assert_eq!(server.join().unwrap().len(), 3);
+        let events = events.lock().unwrap();
+        assert_eq!(
+            *events,
+            &["submitted 202 after 2", "dropped Some(400) after 1"]
+        );
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(retry_after(" 5 ", now), Some(Duration::from_secs(5)));
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:47 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:27 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("Sunday, 06-Nov-94 08:49:37 GMT", now), None);
+    }
+
+    #[tokio::test]
+    async fn times_out_hanging_submissions() {
+        struct HangingTransport;
+        impl HttpTransport for HangingTransport {
+            fn send(&self, _request: HttpRequest) -> HttpFuture<'_> {
+                Box::pin(std::future::pending())
+            }
+        }
+
+        let (mut sink, events) = recording_sink(
+            datadog("api-key")
+                .transport(HangingTransport)
+                .retry_deadline(Duration::from_millis(100)),
+        );
+        let started = Instant::now();
+        submit_counter(&mut sink, 1.).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(*events.lock().unwrap(), &["dropped None after 1"]);
+    }
+
+    #[tokio::test]
+    async fn spills_and_replays_payloads() {
+        let directory = std::env::temp_dir().join(format!("merni-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        // a temporary file left behind by an interrupted write
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join(".00000000000000000001-0000000000.series"),
+            b"",
+        )
+        .unwrap();
+
+        let (ddog_site, server) = mock_datadog(&[UNAVAILABLE, ACCEPTED, ACCEPTED]);
+        let (mut sink, events) = recording_sink(
+            datadog("api-key")
+                .ddog_site(&ddog_site)
+                .retry_deadline(Duration::ZERO)
+                .spill_directory(&directory),
+        );
+
+        submit_counter(&mut sink, 1.).await;
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        submit_counter(&mut sink, 2.).await;
+        // the replay runs in a separate task
+        for _ in 0..100 {
+            if events.lock().unwrap().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], bodies[2]);
+        assert_ne!(bodies[0], bodies[1]);
+        let events = events.lock().unwrap();
+        assert_eq!(
+            *events,
+            &["spilled", "submitted 202 after 1", "submitted 202 after 1"]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn writes_dogsketch() {
+        let mut sketch = DDSketch::new();
+        sketch.add(1.);
+        sketch.add(1.);
+        sketch.add(-1.);
+
+        let mut buf = ProtoBuf::default();
+        write_dogsketch(&mut buf, 1, &sketch);
+
+        // `k` and `n` use the bin keys of the Datadog agent: `±1.` maps to key `±1338`.
+        let mut expected = ProtoBuf::default();
+        expected.int64(1, 1);
+        expected.int64(2, 3);
+        expected.double(3, -1.);
+        expected.double(4, 1.);
+        expected.double(5, 1. / 3.);
+        expected.double(6, 1.);
+        expected.packed_sint32(7, [-1338, 1338].into_iter());
+        expected.packed_uint64(8, [1, 2].into_iter());
+        assert_eq!(buf.buf, expected.buf);
+
+        // `k` is a zigzag-encoded varint
+        let k = &buf.buf[buf.buf.len() - 10..buf.buf.len() - 4];
+        assert_eq!(k, &[0x3a, 4, 0xf3, 0x14, 0xf4, 0x14]);
+    }
+
+    /// Awaits all the submissions of the sink, returning the captured requests.
+    async fn captured_requests(
+        sink: &mut DatadogSink,
+        transport: &CapturingTransport,
+    ) -> Vec<CapturedRequest> {
+        for task in std::mem::take(&mut sink.join_handles) {
+            task.await.unwrap();
+        }
+        transport.take()
+    }
+
+    #[tokio::test]
+    async fn builds_metrics_request() {
+        let transport = CapturingTransport::new();
+        let mut sink = datadog("api-key")
+            .transport(transport.clone())
+            .into_sink()
+            .unwrap();
+
+        sink.push_metric(
+            &AggregatedMetric {
+                meta: MetricMeta::new(MetricType::Counter, MetricUnit::Bytes, "some.bytes"),
+                tag_values: Default::default(),
+            },
+            12345,
+            123.45,
+        )
+        .unwrap();
+        sink.push_metric(
+            &AggregatedMetric {
+                meta: MetricMeta::new(MetricType::Gauge, MetricUnit::Unknown, "a.gauge")
+                    .with_tags(&["a_tag"])
+                    .meta,
+                tag_values: record_tags(&[&"a_value"]),
+            },
+            12346,
+            1234.567,
+        )
+        .unwrap();
+        sink.flush(Endpoint::Metrics).unwrap();
+
+        sink.push_distribution(
+            &AggregatedMetric {
+                meta: MetricMeta::new(MetricType::Distribution, MetricUnit::Seconds, "a.timer"),
+                tag_values: Default::default(),
+            },
+            12346,
+            &[1., 2., 3., 4.],
+        )
+        .unwrap();
+        sink.flush(Endpoint::Distributions).unwrap();
+
+        let requests = captured_requests(&mut sink, &transport).await;
+        assert_eq!(requests.len(), 2);
+
+        assert_eq!(requests[0].url, "https://api.datadoghq.com/api/v2/series");
+        assert_eq!(requests[0].content_type, "application/json");
+        assert_eq!(
+            requests[0].text(),
+            concat!(
+                r#"{"series":["#,
+                r#"{"metric":"some.bytes","interval":10,"type":1,"unit":"byte","#,
+                r#""points":[{"timestamp":12345,"value":123.45}]},"#,
+                r#"{"metric":"a.gauge","tags":["a_tag:a_value"],"type":3,"#,
+                r#""points":[{"timestamp":12346,"value":1234.567}]}"#,
+                r#"]}"#
+            )
+        );
+
+        assert_eq!(requests[1].path(), "/api/v1/distribution_points");
+        assert_eq!(
+            requests[1].text(),
+            r#"{"series":[{"metric":"a.timer","points":[[12346,[1.0,2.0,3.0,4.0]]]}]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn submits_weighted_distributions_as_sketches() {
+        let transport = CapturingTransport::new();
+        let mut sink = datadog("api-key")
+            .transport(transport.clone())
+            .into_sink()
+            .unwrap();
+
+        let meta = |key| AggregatedMetric {
+            meta: MetricMeta::new(MetricType::Distribution, MetricUnit::Unknown, key),
+            tag_values: Default::default(),
+        };
+        let mut aggregations = Aggregations::default();
+        let mut sampled = crate::PreciseAggregatedDistribution::default();
+        sampled.add(1., 1_000_000_000);
+        aggregations.distributions.insert(meta("sampled"), sampled);
+        let mut sketch = DDSketch::new();
+        sketch.add_with_count(2., 1_000_000_000);
+        aggregations.sketches.insert(meta("sketch"), sketch);
+
+        sink.join_handles = sink.emit_metrics(aggregations).unwrap();
+        let requests = captured_requests(&mut sink, &transport).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path(), "/api/beta/sketches");
+        assert!(requests[0].body.len() < 1000);
+    }
+
+    #[tokio::test]
+    async fn splits_large_payloads() {
+        let transport = CapturingTransport::new();
+        let mut sink = datadog("api-key")
+            .transport(transport.clone())
+            .into_sink()
+            .unwrap();
+
+        let meta = AggregatedMetric {
+            meta: MetricMeta::new(MetricType::Distribution, MetricUnit::Unknown, "large"),
+            tag_values: Default::default(),
+        };
+        let values: Vec<f64> = (0..1_000_000).map(|i| i as f64 + 0.123456789).collect();
+        sink.push_distribution_values(&meta, 12345, &values)
+            .unwrap();
+        sink.flush(Endpoint::Distributions).unwrap();
+
+        let requests = captured_requests(&mut sink, &transport).await;
+        assert!(requests.len() > 1);
+        let mut num_values = 0;
+        for request in requests {
+            assert!(request.body.len() <= MAX_UNCOMPRESSED);
+            let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            for series in payload["series"].as_array().unwrap() {
+                assert_eq!(series["metric"], "large");
+                num_values += series["points"][0][1].as_array().unwrap().len();
+            }
+        }
+        assert_eq!(num_values, values.len());
+    }
+
+    /// A transport which takes a while to respond, like a real network.
+    struct SlowTransport(CapturingTransport);
+    impl HttpTransport for SlowTransport {
+        fn send(&self, request: HttpRequest) -> HttpFuture<'_> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                self.0.send(request).await
+            })
+        }
+    }
+
+    fn slow_flusher() -> (DatadogFlusher, CapturingTransport) {
+        let transport = CapturingTransport::new();
+        let sink = datadog("api-key")
+            .transport(SlowTransport(transport.clone()))
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let dispatcher = Dispatcher::new(Arc::clone(&aggregator));
+        let _guard = crate::set_local_dispatcher(dispatcher);
+        crate::counter!("some.counter": 1);
+
+        let flusher = DatadogFlusher {
+            aggregator,
+            runtime: Handle::current(),
+        };
+        (flusher, transport)
+    }
+
+    #[tokio::test]
+    async fn submits_on_shutdown() {
+        let (flusher, transport) = slow_flusher();
+
+        flusher
+            .shutdown(Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        let requests = transport.take();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].text().contains(r#""metric":"some.counter""#));
+
+        flusher.shutdown(None).await.unwrap_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn submits_on_dropping_shutdown_guard() {
+        let (flusher, transport) = slow_flusher();
+
+        drop(flusher.shutdown_guard(Duration::from_secs(5)).unwrap());
+        assert_eq!(transport.take().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_shutdown_guard_on_current_thread() {
+        let (flusher, transport) = slow_flusher();
+
+        let err = flusher
+            .shutdown_guard(Duration::from_secs(5))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(transport.take().is_empty());
+    }
+}
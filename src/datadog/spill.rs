@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use super::Endpoint;
+
+/// An on-disk queue of compressed payloads which could not be submitted.
+///
+/// Each payload is stored as a separate file, named after the time it was stored
+/// and the endpoint it is destined for, like `00000001700000000000-0000000000.series`.
+/// As the names are zero-padded, sorting them yields the payloads in the order they were stored.
+///
+/// All methods access the file system synchronously, and should be called via [`Spill::blocking`]
+/// from async code.
+pub(super) struct Spill {
+    directory: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+
+    seq: AtomicU64,
+    replaying: AtomicBool,
+    /// Serializes [`store`](Self::store)s, so that the size accounting of concurrent stores is correct.
+    storing: Mutex<()>,
+}
+
+/// A single payload stored in the [`Spill`] directory.
+pub(super) struct SpillEntry {
+    pub path: PathBuf,
+    pub endpoint: Endpoint,
+    /// The time the payload was stored, in milliseconds since the unix epoch.
+    pub stored_at: u64,
+    pub size: u64,
+}
+
+impl Spill {
+    pub fn new(directory: PathBuf, max_size: u64, max_age: Duration) -> Self {
+        Self {
+            directory,
+            max_size,
+            max_age,
+
+            seq: AtomicU64::new(0),
+            replaying: AtomicBool::new(false),
+            storing: Mutex::new(()),
+        }
+    }
+
+    /// Runs a blocking operation on the spill directory on the blocking thread pool.
+    pub async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Spill) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let spill = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(&spill))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Lists all the stored payloads, in the order they were stored.
+    ///
+    /// This also removes temporary files which were left behind by an interrupted [`store`](Self::store).
+    pub fn entries(&self) -> io::Result<Vec<SpillEntry>> {
+        let mut entries = vec![];
+        let read_dir = match fs::read_dir(&self.directory) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(entries),
+            Err(err) => return Err(err),
+        };
+        for entry in read_dir {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            // temporary files, which are still being written, start with a `.`
+            let (temporary, name) = match name.strip_prefix('.') {
+                Some(name) => (true, name),
+                None => (false, name),
+            };
+            let Some((stem, endpoint)) = name.split_once('.') else {
+                continue;
+            };
+            let Some(endpoint) = Endpoint::from_name(endpoint) else {
+                continue;
+            };
+            let Some(stored_at) = stem.split('-').next().and_then(|t| t.parse().ok()) else {
+                continue;
+            };
+            if temporary {
+                // writing a payload does not take this long, so the file is a leftover
+                if self.is_expired_at(stored_at) {
+                    let _ = fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            entries.push(SpillEntry {
+                path: entry.path(),
+                endpoint,
+                stored_at,
+                size: entry.metadata()?.len(),
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    /// Stores a payload, evicting the oldest payloads to stay within the size limit.
+    ///
+    /// Returns the evicted entries.
+    pub fn store(&self, endpoint: Endpoint, body: &[u8]) -> io::Result<Vec<SpillEntry>> {
+        let size = body.len() as u64;
+        if size > self.max_size {
+            return Err(io::ErrorKind::QuotaExceeded.into());
+        }
+        let _storing = self.storing.lock().unwrap_or_else(|err| err.into_inner());
+        fs::create_dir_all(&self.directory)?;
+
+        let mut evicted = vec![];
+        let entries = self.entries()?;
+        let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+        for entry in entries {
+            if total_size + size <= self.max_size {
+                break;
+            }
+            total_size -= entry.size;
+            match fs::remove_file(&entry.path) {
+                Ok(()) => evicted.push(entry),
+                // the payload was concurrently replayed
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let name = format!(
+            "{:020}-{:010}.{}",
+            unix_millis(),
+            self.seq.fetch_add(1, Ordering::Relaxed),
+            endpoint.name()
+        );
+        let temp_path = self.directory.join(format!(".{name}"));
+        let result = fs::write(&temp_path, body)
+            .and_then(|_| fs::rename(&temp_path, self.directory.join(name)));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+
+        Ok(evicted)
+    }
+
+    /// Whether the payload has been stored for longer than the configured max age.
+    pub fn is_expired(&self, entry: &SpillEntry) -> bool {
+        self.is_expired_at(entry.stored_at)
+    }
+
+    fn is_expired_at(&self, stored_at: u64) -> bool {
+        let age = unix_millis().saturating_sub(stored_at);
+        age > self.max_age.as_millis() as u64
+    }
+
+    /// Marks the start of replaying stored payloads, until the returned guard is dropped.
+    ///
+    /// Returns `None` if the payloads are already being replayed.
+    pub fn start_replay(&self) -> Option<ReplayGuard<'_>> {
+        let replaying = !self.replaying.swap(true, Ordering::Acquire);
+        replaying.then_some(ReplayGuard(&self.replaying))
+    }
+}
+
+/// Marks the payloads of a [`Spill`] as being replayed, see [`Spill::start_replay`].
+pub(super) struct ReplayGuard<'a>(&'a AtomicBool);
+
+impl Drop for ReplayGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_concurrently() {
+        let directory =
+            std::env::temp_dir().join(format!("merni-spill-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let spill = Spill::new(directory.clone(), 1000, Duration::from_secs(60));
+
+        let evicted = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..25)
+                            .map(|_| spill.store(Endpoint::Metrics, &[0; 100]).unwrap().len())
+                            .sum::<usize>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .sum::<usize>()
+        });
+
+        let entries = spill.entries().unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(evicted, 90);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
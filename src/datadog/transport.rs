@@ -0,0 +1,136 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The timeout of a single request, which also bounds attempts without a retry deadline.
+pub(super) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The future returned by [`HttpTransport::send`].
+pub type HttpFuture<'a> = Pin<Box<dyn Future<Output = io::Result<HttpResponse>> + Send + 'a>>;
+
+/// A transport which sends the HTTP requests submitting metrics payloads.
+///
+/// The default transport uses [`reqwest`](https://docs.rs/reqwest).
+/// A custom transport can be configured using [`DatadogBuilder::transport`](crate::DatadogBuilder::transport),
+/// for example to capture the payloads in tests.
+///
+/// Errors with a kind of [`ConnectionRefused`](io::ErrorKind::ConnectionRefused),
+/// [`ConnectionReset`](io::ErrorKind::ConnectionReset),
+/// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) or
+/// [`TimedOut`](io::ErrorKind::TimedOut) are considered transient,
+/// and the request is retried.
+pub trait HttpTransport: Send + Sync + 'static {
+    /// Sends the request, resolving to the response of the server.
+    fn send(&self, request: HttpRequest) -> HttpFuture<'_>;
+}
+
+/// A `POST` request, as sent by an [`HttpTransport`].
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    /// The full URL of the request.
+    pub url: String,
+    /// The headers of the request, including the `Content-Encoding` of the body.
+    pub headers: Vec<(&'static str, String)>,
+    /// The (compressed) body of the request.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the given header, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(self.headers.iter().map(|(k, v)| (*k, v.as_str())), name)
+    }
+}
+
+/// The response to an [`HttpRequest`].
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The headers of the response.
+    pub headers: Vec<(String, String)>,
+    /// The body of the response.
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// Creates a response with the given status code, without any headers or body.
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the value of the given header, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(
+            self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            name,
+        )
+    }
+}
+
+fn find_header<'a>(
+    mut headers: impl Iterator<Item = (&'a str, &'a str)>,
+    name: &str,
+) -> Option<&'a str> {
+    headers
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// The default [`HttpTransport`], using a [`reqwest::Client`].
+pub(super) struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new() -> io::Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(Self { client })
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> HttpFuture<'_> {
+        Box::pin(async move {
+            let mut builder = self.client.post(request.url).body(request.body);
+            for (key, value) in request.headers {
+                builder = builder.header(key, value);
+            }
+            let response = builder.send().await.map_err(|err| {
+                let kind = if err.is_connect() {
+                    io::ErrorKind::ConnectionRefused
+                } else if err.is_timeout() {
+                    io::ErrorKind::TimedOut
+                } else if err.is_request() {
+                    // the connection failed while sending the request, or receiving the response
+                    io::ErrorKind::ConnectionReset
+                } else {
+                    io::ErrorKind::Other
+                };
+                io::Error::new(kind, err)
+            })?;
+
+            let status = response.status().as_u16();
+            let headers = response
+                .headers()
+                .iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value.to_str().ok()?.into())))
+                .collect();
+            let body = response.text().await.unwrap_or_default();
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
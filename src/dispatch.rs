@@ -1,11 +1,159 @@
 use std::fmt::{Debug, Display};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock, RwLock};
 
-use crate::tags::{InputTags, record_tags};
-use crate::{IntoMetricValue, Metric, MetricKey, MetricMeta, MetricValue, Sink, TaggedMetricMeta};
+use smallvec::SmallVec;
+use smol_buf::Str24;
+
+use crate::interner::{AddressMap, Interner};
+use crate::metric::normalize_sample_rate;
+use crate::scope::apply_scoped_tags;
+use crate::tags::{InputTags, TagValues, record_tags, record_typed_tags};
+use crate::{
+    IntoMetricValue, Metric, MetricCell, MetricKey, MetricMeta, MetricType, MetricUnit,
+    MetricValue, Sink, TaggedMetricMeta, Tags, TypedMetricMeta,
+};
+
+type LazyTagValue = LazyLock<String, Box<dyn FnOnce() -> String + Send>>;
+
+/// The self-telemetry counter of metrics dropped because the limit of interned metrics was reached.
+static DROPPED_METRICS: TaggedMetricMeta<1> = MetricMeta::new(
+    MetricType::Counter,
+    MetricUnit::Unknown,
+    "merni.interner.dropped_metrics",
+)
+.with_tags(&["metric"]);
+
+/// A builder for a [`Dispatcher`], configuring a prefix and global tags for all metrics.
+///
+/// The prefix and global tags are applied before metrics reach the [`Sink`], so all sinks
+/// see them as part of the metrics key and [`tags()`](crate::MetricKey::tags).
+///
+/// The metrics with prefix and global tags applied are interned, and share the limit of
+/// distinct metrics with [`Dispatcher::emit_dynamic`]. Metrics beyond that limit are dropped,
+/// and counted by the `merni.interner.dropped_metrics` counter.
+#[derive(Default)]
+pub struct DispatcherBuilder {
+    prefix: String,
+    global_tags: Vec<(String, LazyTagValue)>,
+}
+
+impl Debug for DispatcherBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DispatcherBuilder")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DispatcherBuilder {
+    /// Sets a prefix for all the emitted metrics.
+    ///
+    /// For example, this could be `"myservice."`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Adds a global tag to all the emitted metrics.
+    ///
+    /// Global tags come before the tags of the metric itself.
+    pub fn global_tag(self, key: &str, value: impl Display) -> Self {
+        let value = value.to_string();
+        self.global_tag_with(key, move || value)
+    }
+
+    /// Adds a global tag to all the emitted metrics, with a lazily computed value.
+    ///
+    /// The value is computed once, when the first metric is being emitted.
+    /// This can be used to read the value from the environment, or the hostname.
+    pub fn global_tag_with(
+        mut self,
+        key: &str,
+        value: impl FnOnce() -> String + Send + 'static,
+    ) -> Self {
+        self.global_tags
+            .push((key.into(), LazyLock::new(Box::new(value))));
+        self
+    }
+
+    /// Creates the [`Dispatcher`], dispatching metrics to the given [`Sink`].
+    pub fn build<S>(self, sink: S) -> Dispatcher
+    where
+        S: Sink + Send + Sync + 'static,
+    {
+        let context = (!self.prefix.is_empty() || !self.global_tags.is_empty()).then(|| {
+            Box::new(GlobalContext {
+                prefix: self.prefix,
+                global_tags: self.global_tags,
+                global_values: Default::default(),
+                metas: Default::default(),
+            })
+        });
+        let mut dispatcher = Dispatcher::new(sink);
+        dispatcher.context = context;
+        dispatcher
+    }
+}
+
+/// The prefix and global tags applied to all the metrics of a [`Dispatcher`].
+struct GlobalContext {
+    prefix: String,
+    global_tags: Vec<(String, LazyTagValue)>,
+    /// The recorded values of the `global_tags`, which are computed lazily.
+    global_values: OnceLock<Box<[Str24]>>,
+    /// The metrics with prefix and global tags applied, keyed by the address of the original.
+    ///
+    /// The metrics themselves are interned in the global [`Interner`], so they are shared
+    /// between dispatchers and count towards its limit. This cache avoids hashing the name
+    /// on every emit, and is freed along with the dispatcher.
+    metas: RwLock<AddressMap<Option<&'static MetricMeta>>>,
+}
+
+impl GlobalContext {
+    /// Returns the metric with prefix and global tag keys applied.
+    ///
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    fn meta(&self, meta: &'static MetricMeta) -> Option<&'static MetricMeta> {
+        let address = meta as *const MetricMeta as usize;
+        if let Some(meta) = self.metas.read().unwrap().get(&address) {
+            return *meta;
+        }
+
+        let name = format!("{}{}", self.prefix, meta.key());
+        let global_keys = self.global_tags.iter().map(|(key, _)| key.as_str());
+        let tag_keys = global_keys.chain(meta.tag_keys.iter().copied());
+        let interned = Interner::global().intern(meta.ty(), meta.unit(), &name, tag_keys);
+        *self
+            .metas
+            .write()
+            .unwrap()
+            .entry(address)
+            .or_insert(interned)
+    }
+
+    /// Returns the tag values with the global tag values prepended.
+    fn tag_values(&self, tag_values: TagValues) -> TagValues {
+        let global_values = self.global_values.get_or_init(|| {
+            self.global_tags
+                .iter()
+                .map(|(_, value)| Str24::new(LazyLock::force(value)))
+                .collect()
+        });
+        if global_values.is_empty() {
+            return tag_values;
+        }
+        let tag_values = tag_values.as_deref().unwrap_or_default();
+        Some(global_values.iter().chain(tag_values).cloned().collect())
+    }
+}
 
 /// A Dispatcher that can be used to emit metrics.
 pub struct Dispatcher {
+    /// A unique id of this dispatcher, as the address of a dispatcher can be reused.
+    id: u64,
     sink: Box<dyn Sink + Send + Sync + 'static>,
+    context: Option<Box<GlobalContext>>,
 }
 
 impl Debug for Dispatcher {
@@ -22,16 +170,48 @@ impl Dispatcher {
     where
         S: Sink + Send + Sync + 'static,
     {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             sink: Box::new(sink),
+            context: None,
         }
     }
 
+    /// The unique id of this dispatcher.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Creates a [`DispatcherBuilder`], to configure a prefix and global tags for all metrics.
+    pub fn builder() -> DispatcherBuilder {
+        DispatcherBuilder::default()
+    }
+
     /// Emit a metric value for the given metric.
     pub fn emit(&self, metric: &'static MetricMeta, value: impl IntoMetricValue) {
         let value = value.into_metric_value(metric);
 
-        self.record(metric, value, &[]);
+        self.record(metric, value, &[], 1.);
+    }
+
+    /// Emit a metric value for the given metric, which was sampled at the given `sample_rate`.
+    ///
+    /// The sample rate is in the range `0..=1`, and is carried along in the [`Metric`](crate::Metric).
+    /// Sampling itself is up to the caller, see the `@rate` syntax of the macros.
+    ///
+    /// The rate is rounded so that the metric stands in for a whole number of metrics,
+    /// see [`Metric::weight`](crate::Metric::weight). Rates above `1` are treated as `1`,
+    /// and metrics with a rate of `0` or below are dropped.
+    pub fn emit_sampled(
+        &self,
+        metric: &'static MetricMeta,
+        value: impl IntoMetricValue,
+        sample_rate: f64,
+    ) {
+        let value = value.into_metric_value(metric);
+
+        self.record(metric, value, &[], sample_rate);
     }
 
     /// Emit a metric value along with tags for the given metric.
@@ -44,17 +224,159 @@ impl Dispatcher {
         let TaggedMetricMeta { meta } = metric;
         let value = value.into_metric_value(meta);
 
-        self.record(meta, value, &tag_values);
+        self.record(meta, value, &tag_values, 1.);
     }
 
-    fn record(&self, meta: &'static MetricMeta, value: MetricValue, tag_values: InputTags) {
-        let key = MetricKey {
-            meta,
-            tag_values: record_tags(tag_values),
+    /// Emit a metric value along with tags for the given metric,
+    /// which was sampled at the given `sample_rate`.
+    ///
+    /// See [`emit_sampled`](Self::emit_sampled).
+    pub fn emit_tagged_sampled<const N: usize>(
+        &self,
+        metric: &'static TaggedMetricMeta<N>,
+        value: impl IntoMetricValue,
+        tag_values: [&dyn Display; N],
+        sample_rate: f64,
+    ) {
+        let TaggedMetricMeta { meta } = metric;
+        let value = value.into_metric_value(meta);
+
+        self.record(meta, value, &tag_values, sample_rate);
+    }
+
+    /// Emit a metric value along with a typed set of [`Tags`] for the given metric.
+    pub fn emit_with_tags<T: Tags>(
+        &self,
+        metric: &'static TypedMetricMeta,
+        value: impl IntoMetricValue,
+        tags: &T,
+    ) {
+        let Some(meta) = metric.meta::<T>() else {
+            return;
         };
+        let value = value.into_metric_value(meta);
 
-        let metric = Metric { key, value };
+        self.record_values(meta, value, record_typed_tags(tags), 1.);
+    }
+
+    /// Emit a metric value for a metric with a name and tags only known at runtime.
+    ///
+    /// Metrics are interned, so they can be aggregated just like the metrics declared by
+    /// the macros. The number of distinct metrics (based on their type, unit, name and tag keys)
+    /// is limited to 10,000 though, and metrics beyond that limit are being dropped.
+    ///
+    /// Returns `false` if the metric has been dropped.
+    pub fn emit_dynamic(
+        &self,
+        ty: MetricType,
+        unit: MetricUnit,
+        name: &str,
+        tags: &[(&str, &str)],
+        value: impl IntoMetricValue,
+    ) -> bool {
+        let tag_keys = tags.iter().map(|(key, _)| *key);
+        let Some(meta) = Interner::global().intern(ty, unit, name, tag_keys) else {
+            return false;
+        };
+        let value = value.into_metric_value(meta);
+        let tag_values: SmallVec<&dyn Display, 8> = tags
+            .iter()
+            .map(|(_, value)| value as &dyn Display)
+            .collect();
+
+        self.record(meta, value, &tag_values, 1.);
+        true
+    }
+
+    fn record(
+        &self,
+        meta: &'static MetricMeta,
+        value: MetricValue,
+        tag_values: InputTags,
+        sample_rate: f64,
+    ) {
+        self.record_values(meta, value, record_tags(tag_values), sample_rate)
+    }
+
+    /// Emits a metric with already recorded tag values.
+    pub(crate) fn record_values(
+        &self,
+        meta: &'static MetricMeta,
+        value: MetricValue,
+        tag_values: TagValues,
+        sample_rate: f64,
+    ) {
+        let Some(sample_rate) = normalize_sample_rate(sample_rate) else {
+            return;
+        };
+        let Some(key) = self.resolve_key(meta, tag_values) else {
+            self.report_dropped(meta);
+            return;
+        };
+
+        let metric = Metric {
+            key,
+            value,
+            sample_rate,
+        };
 
         self.sink.emit(metric)
     }
+
+    /// Registers the [`MetricCell`] of a pre-registered metric handle with the sink.
+    ///
+    /// Cells are shared by all threads, so the scoped tags are not applied to them.
+    /// Returns [`None`] if the sink does not support metric handles.
+    pub(crate) fn register_cell(
+        &self,
+        meta: &'static MetricMeta,
+        tag_values: TagValues,
+    ) -> Option<Arc<MetricCell>> {
+        let key = self.context_key(meta, tag_values)?;
+        let cell = Arc::new(MetricCell::new(key));
+        self.sink.register(&cell).then_some(cell)
+    }
+
+    /// Applies the scoped tags, and the prefix and global tags of this dispatcher.
+    ///
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    fn resolve_key(
+        &self,
+        meta: &'static MetricMeta,
+        tag_values: TagValues,
+    ) -> Option<MetricKey<'static>> {
+        let (meta, tag_values) = apply_scoped_tags(meta, tag_values)?;
+        self.context_key(meta, tag_values)
+    }
+
+    /// Applies the prefix and global tags of this dispatcher.
+    ///
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    fn context_key(
+        &self,
+        meta: &'static MetricMeta,
+        tag_values: TagValues,
+    ) -> Option<MetricKey<'static>> {
+        let (meta, tag_values) = match &self.context {
+            Some(context) => (context.meta(meta)?, context.tag_values(tag_values)),
+            None => (meta, tag_values),
+        };
+        Some(MetricKey { meta, tag_values })
+    }
+
+    /// Counts a metric which was dropped because the limit of interned metrics has been reached.
+    ///
+    /// The counter is emitted as-is, without the prefix and global tags,
+    /// as applying those might exceed the limit again.
+    pub(crate) fn report_dropped(&self, meta: &MetricMeta) {
+        let key = MetricKey {
+            meta: &DROPPED_METRICS.meta,
+            tag_values: Some(Box::new([Str24::new(meta.key())])),
+        };
+        self.sink.emit(Metric {
+            key,
+            value: 1.into_metric_value(&DROPPED_METRICS.meta),
+            sample_rate: 1.,
+        });
+    }
 }
@@ -1,29 +1,200 @@
 use std::cell::Cell;
-use std::sync::OnceLock;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
 
 use crate::Dispatcher;
 
-static GLOBAL_DISPATCHER: OnceLock<Dispatcher> = OnceLock::new();
+// only locked when changing the global dispatcher, or when refreshing a cached one
+static GLOBAL_DISPATCHER: Mutex<Option<Arc<Dispatcher>>> = Mutex::new(None);
+// bumped whenever the global dispatcher changes, invalidating the cached ones
+static GLOBAL_GENERATION: AtomicUsize = AtomicUsize::new(1);
+// the caches of all threads, so that a replaced dispatcher can be released
+static GLOBAL_CACHES: Mutex<Vec<Weak<GlobalCache>>> = Mutex::new(Vec::new());
 // because accessing a static global is faster than a thread local
 static LOCAL_COUNT: AtomicUsize = AtomicUsize::new(0);
 thread_local! {
     static LOCAL_DISPATCHER: Cell<Option<Dispatcher>> = const { Cell::new(None) };
+    static GLOBAL_CACHE: Arc<GlobalCache> = GlobalCache::register();
+}
+
+/// The global [`Dispatcher`] cached by a thread, so emitting does not touch its reference count.
+///
+/// Emitting marks the cached dispatcher as [`in_use`], and puts it back once done.
+/// Changing the global dispatcher releases all the cached ones, waiting for the ones in use.
+struct GlobalCache {
+    /// The cached dispatcher from [`Arc::into_raw`], null if there is none, or [`in_use`].
+    dispatcher: AtomicPtr<Dispatcher>,
+    /// The generation of the cached dispatcher, `0` if nothing has been cached yet.
+    generation: AtomicUsize,
+}
+
+/// The marker of a [`GlobalCache`] whose dispatcher is currently in use.
+fn in_use() -> *mut Dispatcher {
+    ptr::dangling_mut()
+}
+
+impl GlobalCache {
+    fn register() -> Arc<Self> {
+        let cache = Arc::new(Self {
+            dispatcher: AtomicPtr::new(ptr::null_mut()),
+            generation: AtomicUsize::new(0),
+        });
+        let mut caches = lock(&GLOBAL_CACHES);
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        cache
+    }
+
+    /// Takes and runs the closure with the cached dispatcher, refreshing it if it is outdated.
+    ///
+    /// Returns [`None`] without taking the closure if the cache is already in use further up
+    /// the stack, like when a sink emits metrics itself.
+    fn with<F, R>(&self, f: &mut Option<F>) -> Option<R>
+    where
+        F: FnOnce(&Dispatcher) -> R,
+        R: Default,
+    {
+        let cached = self.dispatcher.swap(in_use(), Ordering::Acquire);
+        if cached == in_use() {
+            return None;
+        }
+        let mut guard = CacheGuard {
+            cache: self,
+            // SAFETY: the pointer was created by `Arc::into_raw`, and is owned by this cache
+            dispatcher: (!cached.is_null()).then(|| unsafe { Arc::from_raw(cached) }),
+        };
+        // loaded after marking the cache as in use, as the generation is bumped before
+        // releasing the caches
+        let generation = GLOBAL_GENERATION.load(Ordering::Acquire);
+        if self.generation.load(Ordering::Relaxed) != generation {
+            let (dispatcher, generation) = {
+                let global = lock(&GLOBAL_DISPATCHER);
+                (global.clone(), GLOBAL_GENERATION.load(Ordering::Relaxed))
+            };
+            self.generation.store(generation, Ordering::Relaxed);
+            // the outdated dispatcher is dropped outside of the lock, as its sink might emit metrics
+            guard.dispatcher = dispatcher;
+        }
+        let f = f.take()?;
+        Some(guard.dispatcher.as_deref().map(f).unwrap_or_default())
+    }
+
+    /// Releases the cached dispatcher, waiting for it if it is currently in use.
+    fn release(&self) {
+        loop {
+            let cached = self.dispatcher.load(Ordering::Acquire);
+            if cached.is_null() {
+                return;
+            }
+            if cached == in_use() {
+                std::thread::yield_now();
+                continue;
+            }
+            let exchanged = self.dispatcher.compare_exchange(
+                cached,
+                ptr::null_mut(),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+            if exchanged.is_ok() {
+                // SAFETY: the pointer was created by `Arc::into_raw`, and was taken out of the cache
+                drop(unsafe { Arc::from_raw(cached) });
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for GlobalCache {
+    fn drop(&mut self) {
+        let cached = *self.dispatcher.get_mut();
+        if !cached.is_null() && cached != in_use() {
+            // SAFETY: the pointer was created by `Arc::into_raw`, and is owned by this cache
+            drop(unsafe { Arc::from_raw(cached) });
+        }
+    }
+}
+
+/// Puts the dispatcher back into the [`GlobalCache`], even when unwinding.
+struct CacheGuard<'a> {
+    cache: &'a GlobalCache,
+    dispatcher: Option<Arc<Dispatcher>>,
+}
+
+impl Drop for CacheGuard<'_> {
+    fn drop(&mut self) {
+        let dispatcher = self.dispatcher.take();
+        let dispatcher = dispatcher.map_or(ptr::null_mut(), |d| Arc::into_raw(d).cast_mut());
+        self.cache.dispatcher.store(dispatcher, Ordering::Release);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Swaps the global [`Dispatcher`], releasing the previous one from the caches of all threads.
+fn swap_global_dispatcher(dispatcher: Option<Arc<Dispatcher>>) -> Option<Arc<Dispatcher>> {
+    let previous = {
+        let mut global = lock(&GLOBAL_DISPATCHER);
+        GLOBAL_GENERATION.fetch_add(1, Ordering::Release);
+        std::mem::replace(&mut *global, dispatcher)
+    };
+
+    let caches: Vec<_> = lock(&GLOBAL_CACHES)
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    // the cache of this thread is in use if the dispatcher is replaced while emitting a metric,
+    // in which case its dispatcher is released once it is being used next
+    let current = GLOBAL_CACHE.try_with(Arc::clone).ok();
+    for cache in caches {
+        if !current
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, &cache))
+        {
+            cache.release();
+        }
+    }
+    previous
 }
 
 /// Initialize the global [`Dispatcher`].
 ///
 /// This will register the given `dispatcher` as the single global [`Dispatcher`] instance.
 ///
-/// This function can only be called once, and subsequent calls will return an
-/// [`Err`] in case a global [`Dispatcher`] has already been initialized.
+/// This will return an [`Err`] in case a global [`Dispatcher`] has already been initialized.
+/// Use [`replace_global_dispatcher`] to replace it instead.
 pub fn set_global_dispatcher(dispatcher: Dispatcher) -> Result<(), Dispatcher> {
-    let mut result = Err(dispatcher);
-    {
-        let result = &mut result;
-        let _ = GLOBAL_DISPATCHER.get_or_init(|| std::mem::replace(result, Ok(())).unwrap_err());
+    let mut global = lock(&GLOBAL_DISPATCHER);
+    if global.is_some() {
+        return Err(dispatcher);
     }
-    result
+    GLOBAL_GENERATION.fetch_add(1, Ordering::Release);
+    *global = Some(Arc::new(dispatcher));
+    Ok(())
+}
+
+/// Replaces the global [`Dispatcher`], returning the previous one.
+///
+/// This waits for metrics which are concurrently being emitted to the previous dispatcher.
+/// Its sink should then be flushed or shut down explicitly,
+/// like using [`DatadogFlusher::shutdown`](crate::DatadogFlusher::shutdown).
+pub fn replace_global_dispatcher(dispatcher: Dispatcher) -> Option<Arc<Dispatcher>> {
+    swap_global_dispatcher(Some(Arc::new(dispatcher)))
+}
+
+/// Removes the global [`Dispatcher`], returning the previous one.
+///
+/// See [`replace_global_dispatcher`].
+pub fn reset_global_dispatcher() -> Option<Arc<Dispatcher>> {
+    swap_global_dispatcher(None)
 }
 
 /// A Guard for the thread-locally set [`Dispatcher`].
@@ -56,6 +227,10 @@ impl Drop for LocalDispatcherGuard {
 ///
 /// Returns a [`LocalDispatcherGuard`] which will revert to the previously set
 /// [`Dispatcher`] on [`Drop`].
+///
+/// As the dispatcher is bound to the current thread, it does not follow async tasks which
+/// might be moved between threads, like on a multi-threaded tokio runtime.
+/// Use [`scope`] to bind a dispatcher to a [`Future`] instead.
 pub fn set_local_dispatcher(dispatcher: Dispatcher) -> LocalDispatcherGuard {
     let previous = LOCAL_DISPATCHER.replace(Some(dispatcher));
     if previous.is_none() {
@@ -65,10 +240,86 @@ pub fn set_local_dispatcher(dispatcher: Dispatcher) -> LocalDispatcherGuard {
     LocalDispatcherGuard { previous }
 }
 
+/// Wraps the [`Future`], using the given [`Dispatcher`] for all the metrics emitted within it.
+///
+/// The dispatcher is set as the thread-local dispatcher whenever the future is being polled,
+/// regardless of the thread it is polled on, and takes precedence over any dispatcher that
+/// thread might have. It is dropped once the future completes.
+/// If the future is dropped before completing, the dispatcher is also set while dropping it.
+///
+/// Tasks spawned from within the future do not inherit the dispatcher,
+/// and have to be wrapped on their own.
+///
+/// ```
+/// # struct MySink;
+/// # impl merni::Sink for MySink {
+/// #     fn emit(&self, _metric: merni::Metric) {}
+/// # }
+/// # async fn run() {
+/// let dispatcher = merni::Dispatcher::new(MySink);
+/// merni::scope(dispatcher, async {
+///     merni::counter!("some.counter": 1);
+/// })
+/// .await;
+/// # }
+/// ```
+pub fn scope<F: Future>(dispatcher: Dispatcher, future: F) -> WithDispatcher<F> {
+    WithDispatcher {
+        future: Some(future),
+        dispatcher: Some(dispatcher),
+    }
+}
+
+pin_project! {
+    /// A [`Future`] which sets its [`Dispatcher`] whenever it is being polled.
+    ///
+    /// This is created using [`scope`].
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    #[derive(Debug)]
+    pub struct WithDispatcher<F> {
+        // only `None` once dropped
+        #[pin]
+        future: Option<F>,
+        dispatcher: Option<Dispatcher>,
+    }
+
+    impl<F> PinnedDrop for WithDispatcher<F> {
+        fn drop(this: Pin<&mut Self>) {
+            let mut this = this.project();
+            // the future has not completed, and might emit metrics while being dropped
+            if let Some(dispatcher) = this.dispatcher.take() {
+                let _guard = set_local_dispatcher(dispatcher);
+                this.future.set(None);
+            }
+        }
+    }
+}
+
+impl<F: Future> Future for WithDispatcher<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let future = this.future.as_pin_mut().unwrap();
+
+        let Some(dispatcher) = this.dispatcher.take() else {
+            // the future has already completed
+            return future.poll(cx);
+        };
+        let guard = set_local_dispatcher(dispatcher);
+        let result = future.poll(cx);
+        let dispatcher = guard.take();
+        if result.is_pending() {
+            *this.dispatcher = Some(dispatcher);
+        }
+        result
+    }
+}
+
 /// Runs the closure with a [`Dispatcher`] if one is configured.
 ///
-/// This prefers the thread-local dispatcher if one is defined,
-/// and otherwise falling back to the global dispatcher.
+/// This prefers the thread-local dispatcher if one is defined, or the dispatcher of the
+/// currently polled [`scope`], and otherwise falling back to the global dispatcher.
 pub fn with_dispatcher<F, R>(f: F) -> R
 where
     F: FnOnce(&Dispatcher) -> R,
@@ -82,9 +333,11 @@ where
         return result;
     }
 
-    if let Some(dispatcher) = GLOBAL_DISPATCHER.get() {
-        return f(dispatcher);
+    let mut f = Some(f);
+    if let Ok(Some(result)) = GLOBAL_CACHE.try_with(|cache| cache.with(&mut f)) {
+        return result;
     }
-
-    Default::default()
+    // the cache is already in use, or has been destroyed on thread exit
+    let global = lock(&GLOBAL_DISPATCHER).clone();
+    global.as_deref().map(f.unwrap()).unwrap_or_default()
 }
@@ -0,0 +1,251 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+
+use arc_swap::ArcSwapOption;
+use smallvec::SmallVec;
+
+use crate::interner::Interner;
+use crate::scope::has_scoped_tags;
+use crate::tags::{TagValues, record_tags};
+use crate::{IntoMetricValue, MetricKey, MetricMeta, MetricType, MetricUnit, with_dispatcher};
+
+/// A pre-registered counter metric, which resolves its key and tags only once.
+///
+/// Updates skip formatting the tags and hashing the metric key. If the [`Sink`](crate::Sink) of the
+/// current [`Dispatcher`](crate::Dispatcher) supports it, like the `ThreadLocalAggregator`, the
+/// handle accumulates its values in a [`MetricCell`] which is read when flushing.
+/// Otherwise, each update is emitted as a regular [`Metric`](crate::Metric).
+///
+/// The cell is registered with the sink of the current dispatcher, and registered anew
+/// whenever the handle is used with a different dispatcher.
+/// As the cell is shared by all threads, updates within a [`ScopedTags`](crate::ScopedTags)
+/// scope bypass it and are emitted as regular metrics, so the scoped tags are applied to them.
+///
+/// ```
+/// let requests = merni::Counter::register("requests", [("route", "/")]);
+/// requests.increment(1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Counter {
+    handle: Arc<Handle>,
+}
+
+impl Counter {
+    /// Registers a counter with the given key and tags.
+    ///
+    /// Just like [`Dispatcher::emit_dynamic`](crate::Dispatcher::emit_dynamic), the number of
+    /// distinct metrics is limited, and updates of handles beyond that limit are being dropped.
+    pub fn register<K: AsRef<str>, V: Display>(
+        key: &str,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        Self {
+            handle: Arc::new(Handle::new(MetricType::Counter, key, tags)),
+        }
+    }
+
+    /// Increments the counter by `value`.
+    pub fn increment(&self, value: impl IntoMetricValue) {
+        self.handle.record(value)
+    }
+}
+
+/// A pre-registered gauge metric, which resolves its key and tags only once.
+///
+/// See [`Counter`] for how the values of handles are being recorded.
+#[derive(Debug, Clone)]
+pub struct Gauge {
+    handle: Arc<Handle>,
+}
+
+impl Gauge {
+    /// Registers a gauge with the given key and tags.
+    ///
+    /// See [`Counter::register`].
+    pub fn register<K: AsRef<str>, V: Display>(
+        key: &str,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        Self {
+            handle: Arc::new(Handle::new(MetricType::Gauge, key, tags)),
+        }
+    }
+
+    /// Sets the gauge to `value`.
+    pub fn set(&self, value: impl IntoMetricValue) {
+        self.handle.record(value)
+    }
+}
+
+#[derive(Debug)]
+struct Handle {
+    /// The metric, or [`None`] if the limit of interned metrics has been reached.
+    meta: Option<&'static MetricMeta>,
+    tag_values: TagValues,
+    /// The cell registered with the sink of the last [`Dispatcher`](crate::Dispatcher) used.
+    cell: ArcSwapOption<RegisteredCell>,
+}
+
+#[derive(Debug)]
+struct RegisteredCell {
+    /// The id of the [`Dispatcher`](crate::Dispatcher) this was registered with.
+    dispatcher: u64,
+    /// The cell, or [`None`] if the sink does not support metric handles.
+    cell: Option<Arc<MetricCell>>,
+}
+
+impl Handle {
+    fn new<K: AsRef<str>, V: Display>(
+        ty: MetricType,
+        key: &str,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        let tags: SmallVec<(K, V), 8> = tags.into_iter().collect();
+        let tag_keys = tags.iter().map(|(key, _)| key.as_ref());
+        let meta = Interner::global().intern(ty, MetricUnit::Unknown, key, tag_keys);
+        let tag_values: SmallVec<&dyn Display, 8> = tags
+            .iter()
+            .map(|(_, value)| value as &dyn Display)
+            .collect();
+
+        Self {
+            meta,
+            tag_values: record_tags(&tag_values),
+            cell: ArcSwapOption::const_empty(),
+        }
+    }
+
+    fn record(&self, value: impl IntoMetricValue) {
+        let Some(meta) = self.meta else {
+            return;
+        };
+        let value = value.into_metric_value(meta);
+
+        with_dispatcher(|dispatcher| {
+            if has_scoped_tags() {
+                return dispatcher.record_values(meta, value, self.tag_values.clone(), 1.);
+            }
+            let record = |cell: &Option<Arc<MetricCell>>| match cell {
+                Some(cell) => cell.record(value.get()),
+                None => dispatcher.record_values(meta, value, self.tag_values.clone(), 1.),
+            };
+            if let Some(registered) = &*self.cell.load()
+                && registered.dispatcher == dispatcher.id()
+            {
+                return record(&registered.cell);
+            }
+
+            let cell = dispatcher.register_cell(meta, self.tag_values.clone());
+            record(&cell);
+            self.cell.store(Some(Arc::new(RegisteredCell {
+                dispatcher: dispatcher.id(),
+                cell,
+            })));
+        });
+    }
+}
+
+/// A cell which accumulates the values of a pre-registered metric handle.
+///
+/// Cells are handed to [`Sink::register`](crate::Sink::register), and sinks which accept them
+/// are responsible for periodically [`take`](Self::take)ing their values.
+/// The values are recorded using atomics without any locking. A value recorded concurrently
+/// with a `take` might thus be split across two consecutive `take`s.
+#[derive(Debug)]
+pub struct MetricCell {
+    key: MetricKey<'static>,
+    count: AtomicU64,
+    // the following hold the bits of `f64`s
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    last: AtomicU64,
+}
+
+/// The values recorded by a [`MetricCell`] since they were last taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellValues {
+    /// The number of recorded values.
+    pub count: u64,
+    /// The total sum of recorded values.
+    pub sum: f64,
+    /// The minimum recorded value.
+    pub min: f64,
+    /// The maximum recorded value.
+    pub max: f64,
+    /// The latest recorded value.
+    pub last: f64,
+    /// When the values were taken, ordering the `last` value of gauges.
+    pub(crate) updated: u64,
+}
+
+impl MetricCell {
+    pub(crate) fn new(key: MetricKey<'static>) -> Self {
+        Self {
+            key,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+            min: AtomicU64::new(f64::INFINITY.to_bits()),
+            max: AtomicU64::new(f64::NEG_INFINITY.to_bits()),
+            last: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    /// The key of the metric, including its tags.
+    ///
+    /// Any prefix or global tags of the [`Dispatcher`](crate::Dispatcher) have already
+    /// been applied when registering the cell.
+    pub fn key(&self) -> &MetricKey<'static> {
+        &self.key
+    }
+
+    fn record(&self, value: f64) {
+        update_f64(&self.sum, |sum| sum + value);
+        if self.key.ty() == MetricType::Gauge {
+            update_f64(&self.min, |min| min.min(value));
+            update_f64(&self.max, |max| max.max(value));
+            self.last.store(value.to_bits(), Ordering::Relaxed);
+        }
+        // the count is updated last, and taken first, so a value is never taken without its count
+        self.count.fetch_add(1, Ordering::Release);
+    }
+
+    /// Takes all the values recorded since the last call, resetting the cell.
+    ///
+    /// Returns [`None`] if no values have been recorded.
+    pub fn take(&self) -> Option<CellValues> {
+        let count = self.count.swap(0, Ordering::Acquire);
+        if count == 0 {
+            return None;
+        }
+        let take_f64 = |atomic: &AtomicU64, reset: f64| {
+            f64::from_bits(atomic.swap(reset.to_bits(), Ordering::Relaxed))
+        };
+        Some(CellValues {
+            count,
+            sum: take_f64(&self.sum, 0.),
+            min: take_f64(&self.min, f64::INFINITY),
+            max: take_f64(&self.max, f64::NEG_INFINITY),
+            last: f64::from_bits(self.last.load(Ordering::Relaxed)),
+            updated: timestamp(),
+        })
+    }
+}
+
+/// Atomically updates an `f64` stored as bits, skipping the write if the value is unchanged.
+fn update_f64(atomic: &AtomicU64, f: impl Fn(f64) -> f64) {
+    let _ = atomic.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        let updated = f(f64::from_bits(bits)).to_bits();
+        (updated != bits).then_some(updated)
+    });
+}
+
+/// A monotonic timestamp in nanoseconds, ordering the `last` values of gauges.
+///
+/// This is never `0`, which is the timestamp of a gauge without any values.
+pub(crate) fn timestamp() -> u64 {
+    static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+    START.elapsed().as_nanos() as u64 + 1
+}
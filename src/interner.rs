@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::{OnceLock, RwLock};
+
+use crate::{MetricMeta, MetricType, MetricUnit};
+
+/// The maximum number of distinct dynamic metrics which can be interned.
+const MAX_INTERNED_METRICS: usize = 10_000;
+
+/// The key of an interned metric, with the name and tag keys joined by `\0`.
+type InternerKey = (MetricType, MetricUnit, String);
+
+/// An interner for [`MetricMeta`]s with runtime names and tag keys.
+///
+/// Interned metrics are leaked, so they can be used as `&'static` references just like
+/// the metrics declared by the macros. To keep the leaked memory bounded, the number of
+/// interned metrics is limited, and no more metrics are being interned once that limit is reached.
+pub(crate) struct Interner {
+    metas: RwLock<HashMap<InternerKey, &'static MetricMeta>>,
+    /// The metrics derived from an original metric, see [`Interner::intern_derived`].
+    derived: RwLock<HashMap<MetricMeta, Vec<(Derivation<'static>, &'static MetricMeta)>>>,
+    limit: usize,
+}
+
+/// How a metric is derived from an already existing metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Derivation<'a> {
+    /// The metric is renamed to the given key.
+    Renamed(&'a str),
+    /// The given tag key is added to the metric.
+    Tagged(&'a str),
+}
+
+impl Interner {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            metas: Default::default(),
+            derived: Default::default(),
+            limit,
+        }
+    }
+
+    /// The global interner, used by [`Dispatcher::emit_dynamic`](crate::Dispatcher::emit_dynamic),
+    /// and for the metrics with scoped tags, or a dispatcher prefix and global tags applied.
+    pub fn global() -> &'static Self {
+        static INTERNER: OnceLock<Interner> = OnceLock::new();
+        INTERNER.get_or_init(|| Interner::new(MAX_INTERNED_METRICS))
+    }
+
+    /// Interns a metric with the given name and tag keys.
+    ///
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    pub fn intern<'a>(
+        &self,
+        ty: MetricType,
+        unit: MetricUnit,
+        name: &str,
+        tag_keys: impl Iterator<Item = &'a str> + Clone,
+    ) -> Option<&'static MetricMeta> {
+        let mut joined = String::from(name);
+        for tag_key in tag_keys.clone() {
+            joined.push('\0');
+            joined.push_str(tag_key);
+        }
+        let key = (ty, unit, joined);
+
+        if let Some(meta) = self.metas.read().unwrap().get(&key) {
+            return Some(meta);
+        }
+
+        let mut metas = self.metas.write().unwrap();
+        if let Some(meta) = metas.get(&key) {
+            return Some(meta);
+        }
+        if metas.len() >= self.limit {
+            return None;
+        }
+
+        let meta = leak_meta(ty, unit, name, tag_keys);
+        metas.insert(key, meta);
+        Some(meta)
+    }
+
+    /// Interns a metric derived from an already existing metric, by renaming it or adding a tag.
+    ///
+    /// The derived metrics are cached per original metric and [`Derivation`], and count towards
+    /// the limit of interned metrics.
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    pub fn intern_derived(
+        &self,
+        meta: &MetricMeta,
+        derivation: Derivation<'_>,
+    ) -> Option<&'static MetricMeta> {
+        let find = |derived: &[(Derivation<'static>, &'static MetricMeta)]| {
+            derived
+                .iter()
+                .find(|(d, _)| *d == derivation)
+                .map(|(_, meta)| *meta)
+        };
+        if let Some(derived) = self.derived.read().unwrap().get(meta).and_then(|d| find(d)) {
+            return Some(derived);
+        }
+
+        let (ty, unit, tag_keys) = (meta.ty(), meta.unit(), meta.tag_keys.iter().copied());
+        let derived = match derivation {
+            Derivation::Renamed(key) => self.intern(ty, unit, key, tag_keys)?,
+            Derivation::Tagged(key) => {
+                self.intern(ty, unit, meta.key(), tag_keys.chain(Some(key)))?
+            }
+        };
+        // the derivation borrows its key from the derived metric, which is leaked
+        let stored = match derivation {
+            Derivation::Renamed(_) => Derivation::Renamed(derived.key()),
+            Derivation::Tagged(_) => Derivation::Tagged(derived.tag_keys[meta.tag_keys.len()]),
+        };
+
+        let mut metas = self.derived.write().unwrap();
+        let derived_metas = metas.entry(*meta).or_default();
+        if find(derived_metas).is_none() {
+            derived_metas.push((stored, derived));
+        }
+        Some(derived)
+    }
+}
+
+/// Creates a [`MetricMeta`] with the given name and tag keys, leaking it.
+fn leak_meta<'a>(
+    ty: MetricType,
+    unit: MetricUnit,
+    name: &str,
+    tag_keys: impl Iterator<Item = &'a str>,
+) -> &'static MetricMeta {
+    let name: &'static str = Box::leak(name.into());
+    let tag_keys: Vec<&'static str> = tag_keys.map(|key| &*Box::leak(key.into())).collect();
+    let mut meta = MetricMeta::new(ty, unit, name);
+    meta.tag_keys = Box::leak(tag_keys.into_boxed_slice());
+    Box::leak(Box::new(meta))
+}
+
+/// A map keyed by the address of a [`MetricMeta`].
+pub(crate) type AddressMap<V> = HashMap<usize, V, BuildHasherDefault<AddressHasher>>;
+
+/// A cheap hasher for addresses, which mixes the bits of the address with a single multiplication.
+#[derive(Default)]
+pub(crate) struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        // the low bits of aligned addresses are always zero, and are used to pick the bucket
+        self.0.rotate_left(26)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(*byte as u64);
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0xf1357aea2e62a9c5);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_up_to_limit() {
+        let interner = Interner::new(2);
+        let intern = |name, tag_keys: &[&'static str]| {
+            let tag_keys = tag_keys.iter().copied();
+            interner.intern(MetricType::Counter, MetricUnit::Unknown, name, tag_keys)
+        };
+
+        let a = intern("a", &["tag"]).unwrap();
+        assert_eq!(a.key(), "a");
+        assert_eq!(a.tag_keys, &["tag"]);
+        assert!(std::ptr::eq(a, intern("a", &["tag"]).unwrap()));
+
+        let b = intern("a", &[]).unwrap();
+        assert!(!std::ptr::eq(a, b));
+        assert!(intern("c", &[]).is_none());
+        assert!(intern("a", &[]).is_some());
+    }
+
+    #[test]
+    fn interns_derived_up_to_limit() {
+        let interner = Interner::new(2);
+        let meta = MetricMeta::new(MetricType::Counter, MetricUnit::Unknown, "a");
+
+        let renamed = interner
+            .intern_derived(&meta, Derivation::Renamed("b"))
+            .unwrap();
+        assert_eq!(renamed.key(), "b");
+        let cached = interner.intern_derived(&meta, Derivation::Renamed("b"));
+        assert!(std::ptr::eq(renamed, cached.unwrap()));
+
+        let tagged = interner
+            .intern_derived(&meta, Derivation::Tagged("tag"))
+            .unwrap();
+        assert_eq!(tagged.key(), "a");
+        assert_eq!(tagged.tag_keys, &["tag"]);
+
+        assert!(
+            interner
+                .intern_derived(&meta, Derivation::Renamed("c"))
+                .is_none()
+        );
+    }
+}
@@ -6,29 +6,68 @@
 
 mod dispatch;
 mod globals;
+mod handle;
+mod interner;
 #[doc(hidden)]
 pub mod macros;
 mod metric;
+#[cfg(any(feature = "datadog", feature = "otlp"))]
+mod protobuf;
+mod scope;
 mod sink;
 mod tags;
+mod timer;
 mod types;
 
 pub use dispatch::*;
 pub use globals::*;
+pub use handle::*;
 pub use metric::*;
+pub use scope::*;
 pub use sink::*;
+pub use tags::*;
+pub use timer::*;
 pub use types::*;
 
+#[cfg(feature = "derive")]
+pub use merni_derive::{Tags, ToTagValue};
+// allows the derive macro to refer to `::merni` within this crate
+#[cfg(all(test, feature = "derive"))]
+extern crate self as merni;
+
 #[cfg(feature = "aggregator")]
 mod aggregator;
 #[cfg(feature = "aggregator")]
+mod set;
+#[cfg(feature = "aggregator")]
+mod sketch;
+#[cfg(feature = "aggregator")]
 pub use aggregator::*;
+#[cfg(feature = "aggregator")]
+pub use set::*;
+#[cfg(feature = "aggregator")]
+pub use sketch::*;
 
 #[cfg(feature = "datadog")]
 mod datadog;
 #[cfg(feature = "datadog")]
 pub use datadog::*;
 
+#[cfg(feature = "otlp")]
+mod otlp;
+#[cfg(feature = "otlp")]
+pub use otlp::*;
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "prometheus")]
+pub use prometheus::*;
+
+#[cfg(feature = "statsd")]
+mod statsd;
+#[cfg(feature = "statsd")]
+pub use statsd::*;
+
 #[cfg(any(test, feature = "testing"))]
 /// This contains some utilities used for testing
 pub mod testing;
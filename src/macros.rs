@@ -1,11 +1,24 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 /// Create a [`MetricMeta`](crate::MetricMeta).
 ///
 /// Instead of creating and emitting metrics manually, it is recommended to emit
 /// metrics using the [`counter!`](crate::counter!), [`gauge!`](crate::gauge!),
-/// or [`distribution!`](crate::distribution!) macros.
+/// [`distribution!`](crate::distribution!), [`timer!`](crate::timer!) or [`set!`](crate::set!) macros.
+///
+/// All of these macros accept an optional [`MetricUnit`](crate::MetricUnit) after the key,
+/// like `"some.metric"@ms`. The supported shorthands are `ns`, `us`, `ms`, `s`, `b`, `kib`,
+/// `mib`, `percent` and `ratio`, and a string literal for a custom unit, like `@"requests"`.
+///
+/// Instead of `"key" => value` pairs, the tags can also be given as a typed set of
+/// [`Tags`](crate::Tags), like `tags = RequestTags { method, status }`.
+///
+/// They also accept an optional sample rate after the tags, like `@rate = 0.01`.
+/// Only that fraction of calls will emit the metric, and the rate is carried along in the
+/// [`Metric`](crate::Metric), so that sinks can restore the totals.
 #[macro_export]
 macro_rules! declare_metric {
-    ($ty:ident => $key:literal $(@ $unit:ident)? : $($tag_key:literal),*) => {{
+    ($ty:ident => $key:literal $(@ $unit:tt)? : $($tag_key:literal),*) => {{
         const N: usize = $crate::macros::__count_helper([$($crate::__replace_expr!($tag_key ())),*]);
         static METRIC: $crate::TaggedMetricMeta<N> = $crate::MetricMeta::new(
             $crate::MetricType::$ty,
@@ -15,7 +28,16 @@ macro_rules! declare_metric {
         .with_tags(&[$($tag_key,)?]);
         &METRIC
     }};
-    ($ty:ident => $key:literal $(@ $unit:ident)?) => {{
+    ($ty:ident => $key:literal $(@ $unit:tt)? : tags) => {{
+        static METRIC: $crate::TypedMetricMeta = $crate::MetricMeta::new(
+            $crate::MetricType::$ty,
+            $crate::__metric_unit!($($unit)?),
+            $key
+        )
+        .with_typed_tags();
+        &METRIC
+    }};
+    ($ty:ident => $key:literal $(@ $unit:tt)?) => {{
         static METRIC: $crate::MetricMeta = $crate::MetricMeta::new(
             $crate::MetricType::$ty,
             $crate::__metric_unit!($($unit)?),
@@ -26,6 +48,10 @@ macro_rules! declare_metric {
 }
 
 /// Emits a counter metric with the current [`Dispatcher`](crate::Dispatcher).
+///
+/// ```
+/// merni::counter!("requests": 1, "route" => "/", @rate = 0.01);
+/// ```
 #[macro_export]
 macro_rules! counter {
     ($($tt:tt)+) => {
@@ -49,11 +75,103 @@ macro_rules! distribution {
     }
 }
 
+/// Emits a set metric with the current [`Dispatcher`](crate::Dispatcher).
+///
+/// Sets count the number of unique values, which can be any [`Hash`]able value,
+/// see [`SetValue`](crate::SetValue).
+///
+/// ```
+/// merni::set!("unique.users": "user-id", "tenant" => "sentry");
+/// ```
+#[macro_export]
+macro_rules! set {
+    ($key:literal $(@ $unit:tt)? : $value:expr $(, $tag_key:literal => $tag_value:expr)* $(,)?) => {
+        $crate::__emit_metric!(
+            Set => $key $(@ $unit)? : $crate::SetValue(&($value))
+            $(, $tag_key => $tag_value)*
+        );
+    }
+}
+
+/// Emits a timer metric with the current [`Dispatcher`](crate::Dispatcher).
+///
+/// Timers are distributions which default to recording [`Duration`](std::time::Duration)s
+/// in milliseconds, unless an explicit unit is given.
+#[macro_export]
+macro_rules! timer {
+    ($($tt:tt)+) => {
+        $crate::__emit_metric!(Timer => $($tt)+);
+    }
+}
+
+/// Starts a [`TimerGuard`](crate::TimerGuard), which emits a timer metric when being dropped.
+///
+/// The tag values are captured when the timer is started.
+///
+/// ```
+/// let _timer = merni::time!("db.query", "table" => "users");
+/// // run the query…
+/// ```
+///
+/// The guard can also time a [`Future`](std::future::Future) until its completion:
+///
+/// ```
+/// # async fn query() {}
+/// # async {
+/// merni::time!("db.query", "table" => "users").timed(query()).await;
+/// # };
+/// ```
+#[macro_export]
+macro_rules! time {
+    ($key:literal $(@ $unit:tt)?, $($tag_key:literal => $tag_value:expr),+ $(,)?) => {{
+        let metric = $crate::declare_metric!(
+            Timer => $key $(@ $unit)? :
+            $($tag_key),+
+        );
+        $crate::TimerGuard::start_tagged(metric, [$(&($tag_value)),+])
+    }};
+    ($key:literal $(@ $unit:tt)?) => {{
+        $crate::TimerGuard::start($crate::declare_metric!(Timer => $key $(@ $unit)?))
+    }};
+}
+
 #[macro_export]
 #[doc(hidden)]
 macro_rules! __emit_metric {
     (
-        $ty:ident => $key:literal $(@ $unit:ident)? : $value:expr
+        $ty:ident => $key:literal $(@ $unit:tt)? : $value:expr
+        , $($tag_key:literal => $tag_value:expr,)+ @rate = $rate:expr $(,)?
+    ) => {{
+        static SAMPLER: $crate::macros::Sampler = $crate::macros::Sampler::new();
+        let sample_rate: f64 = $rate;
+        if SAMPLER.sample(sample_rate) {
+            $crate::with_dispatcher(|dispatcher| {
+                let metric = $crate::declare_metric!(
+                    $ty => $key $(@ $unit)? :
+                    $($tag_key),+
+                );
+                dispatcher.emit_tagged_sampled(metric, $value, [$(&($tag_value)),+], sample_rate);
+            });
+        }
+    }};
+    ($ty:ident => $key:literal $(@ $unit:tt)? : $value:expr, @rate = $rate:expr $(,)?) => {{
+        static SAMPLER: $crate::macros::Sampler = $crate::macros::Sampler::new();
+        let sample_rate: f64 = $rate;
+        if SAMPLER.sample(sample_rate) {
+            $crate::with_dispatcher(|dispatcher| {
+                let metric = $crate::declare_metric!($ty => $key $(@ $unit)?);
+                dispatcher.emit_sampled(metric, $value, sample_rate);
+            });
+        }
+    }};
+    ($ty:ident => $key:literal $(@ $unit:tt)? : $value:expr, tags = $tags:expr $(,)?) => {{
+        $crate::with_dispatcher(|dispatcher| {
+            let metric = $crate::declare_metric!($ty => $key $(@ $unit)? : tags);
+            dispatcher.emit_with_tags(metric, $value, &$tags);
+        });
+    }};
+    (
+        $ty:ident => $key:literal $(@ $unit:tt)? : $value:expr
         , $($tag_key:literal => $tag_value:expr),+
     ) => {{
         $crate::with_dispatcher(|dispatcher| {
@@ -64,7 +182,7 @@ macro_rules! __emit_metric {
             dispatcher.emit_tagged(metric, $value, [$(&($tag_value)),+]);
         });
     }};
-    ($ty:ident => $key:literal $(@ $unit:ident)? : $value:expr) => {{
+    ($ty:ident => $key:literal $(@ $unit:tt)? : $value:expr) => {{
         $crate::with_dispatcher(|dispatcher| {
             let metric = $crate::declare_metric!($ty => $key $(@ $unit)?);
             dispatcher.emit(metric, $value);
@@ -78,12 +196,72 @@ macro_rules! __metric_unit {
     () => {
         $crate::MetricUnit::Unknown
     };
+    (ns) => {
+        $crate::MetricUnit::Nanoseconds
+    };
+    (us) => {
+        $crate::MetricUnit::Microseconds
+    };
+    (ms) => {
+        $crate::MetricUnit::Milliseconds
+    };
     (s) => {
         $crate::MetricUnit::Seconds
     };
     (b) => {
         $crate::MetricUnit::Bytes
     };
+    (kib) => {
+        $crate::MetricUnit::Kibibytes
+    };
+    (mib) => {
+        $crate::MetricUnit::Mebibytes
+    };
+    (percent) => {
+        $crate::MetricUnit::Percent
+    };
+    (ratio) => {
+        $crate::MetricUnit::Ratio
+    };
+    ($unit:literal) => {
+        $crate::MetricUnit::Custom($unit)
+    };
+}
+
+/// Decides whether a sampled metric is being emitted, without touching any thread-locals.
+///
+/// Each call site has its own sampler, which advances a [SplitMix64](https://prng.di.unimi.it/splitmix64.c)
+/// sequence.
+#[doc(hidden)]
+pub struct Sampler {
+    state: AtomicU64,
+}
+
+impl Sampler {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns `true` with a probability of `rate`.
+    #[inline]
+    pub fn sample(&self, rate: f64) -> bool {
+        if rate >= 1. {
+            return true;
+        }
+        // the address of the sampler decorrelates the sequences of different call sites
+        let seed = self as *const Self as u64;
+        let mut z = self
+            .state
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(seed);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64) < rate * (1u64 << 53) as f64
+    }
 }
 
 // These are taken from <https://veykril.github.io/tlborm/decl-macros/building-blocks/counting.html#array-length>
@@ -1,7 +1,12 @@
+use std::fmt::Display;
 use std::ops::Deref;
+use std::sync::OnceLock;
 
+use smol_buf::Str24;
+
+use crate::interner::{Derivation, Interner};
 use crate::tags::TagValues;
-use crate::{MetricType, MetricUnit, MetricValue};
+use crate::{MetricType, MetricUnit, MetricValue, Tags};
 
 /// The metadata of a particular metric.
 ///
@@ -35,6 +40,14 @@ impl MetricMeta {
         TaggedMetricMeta { meta: self }
     }
 
+    /// Expects the metric tags to be given by a [`Tags`] type, turning this into a [`TypedMetricMeta`].
+    pub const fn with_typed_tags(self) -> TypedMetricMeta {
+        TypedMetricMeta {
+            meta: self,
+            tagged: OnceLock::new(),
+        }
+    }
+
     /// The metrics type.
     pub fn ty(&self) -> MetricType {
         self.ty
@@ -49,6 +62,17 @@ impl MetricMeta {
     pub fn key(&self) -> &'static str {
         self.key
     }
+
+    /// The unit of the recorded values.
+    ///
+    /// This is the explicit unit of the metric, except for [`MetricType::Timer`]s without unit,
+    /// which record [`MetricUnit::Milliseconds`].
+    pub fn value_unit(&self) -> MetricUnit {
+        match self.unit {
+            MetricUnit::Unknown if self.ty == MetricType::Timer => MetricUnit::Milliseconds,
+            unit => unit,
+        }
+    }
 }
 
 /// Metric metadata parameterized with the number of expected tags.
@@ -57,8 +81,38 @@ pub struct TaggedMetricMeta<const N: usize> {
     pub(crate) meta: MetricMeta,
 }
 
-/// The metric key, which represents a unique metric and its tags that is being emitted.
+/// Metric metadata with tags given by a [`Tags`] type.
+///
+/// The tag keys are taken from the [`Tags`] type it is first emitted with.
+/// Emitting it with a [`Tags`] type with different keys, for example from within a generic
+/// function, interns a separate metric just like [`Dispatcher::emit_dynamic`](crate::Dispatcher::emit_dynamic).
 #[derive(Debug)]
+pub struct TypedMetricMeta {
+    meta: MetricMeta,
+    tagged: OnceLock<MetricMeta>,
+}
+
+impl TypedMetricMeta {
+    /// Returns the metric with the tag keys of `T`.
+    ///
+    /// Returns [`None`] if the keys differ from the first type, and the limit of
+    /// interned metrics has been reached.
+    pub(crate) fn meta<T: Tags>(&'static self) -> Option<&'static MetricMeta> {
+        let meta = self.tagged.get_or_init(|| MetricMeta {
+            tag_keys: T::KEYS,
+            ..self.meta
+        });
+        // the same constant is not guaranteed to have the same address everywhere
+        if std::ptr::eq(meta.tag_keys, T::KEYS) || meta.tag_keys == T::KEYS {
+            return Some(meta);
+        }
+        let tag_keys = T::KEYS.iter().copied();
+        Interner::global().intern(meta.ty, meta.unit, meta.key, tag_keys)
+    }
+}
+
+/// The metric key, which represents a unique metric and its tags that is being emitted.
+#[derive(Debug, Clone)]
 pub struct MetricKey<'meta> {
     pub(crate) meta: &'meta MetricMeta,
     pub(crate) tag_values: TagValues,
@@ -87,10 +141,11 @@ impl<'meta> MetricKey<'meta> {
 /// A metric that is being emitted.
 ///
 /// This consists of its [`MetricKey`] along with tag values, and the [`MetricValue`].
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metric {
     pub(crate) key: MetricKey<'static>,
     pub(crate) value: MetricValue,
+    pub(crate) sample_rate: f64,
 }
 
 impl Deref for Metric {
@@ -106,4 +161,99 @@ impl Metric {
     pub fn value(&self) -> MetricValue {
         self.value
     }
+
+    /// The rate at which this metric was sampled, in the range `0..=1`.
+    ///
+    /// This is `1` unless the metric was emitted with an explicit sample rate,
+    /// in which case it stands in for `1 / sample_rate` metrics.
+    /// The rate is normalized so that this is always a whole number, see [`Metric::weight`].
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// The number of metrics this metric stands in for, which is `1 / sample_rate`.
+    pub fn weight(&self) -> u64 {
+        (1. / self.sample_rate).round() as u64
+    }
+
+    /// Replaces the key, or name of this metric.
+    ///
+    /// Renamed metrics are interned just like the metrics of
+    /// [`Dispatcher::emit_dynamic`](crate::Dispatcher::emit_dynamic), and share its limit.
+    /// Once that limit is reached, the metric is returned unchanged.
+    pub fn with_key(mut self, key: &str) -> Self {
+        if let Some(meta) = renamed(self.key.meta, key) {
+            self.key.meta = meta;
+        }
+        self
+    }
+
+    /// Sets the tag `key` to `value`, replacing an existing tag with the same key.
+    ///
+    /// Metrics with new tags are interned like [`with_key`](Self::with_key), and are
+    /// returned unchanged once the limit of interned metrics is reached.
+    pub fn with_tag(mut self, key: &str, value: impl Display) -> Self {
+        if let Some(meta) = tagged(self.key.meta, key) {
+            self.key.tag_values = set_tag_value(self.key.meta, self.key.tag_values, key, value);
+            self.key.meta = meta;
+        }
+        self
+    }
+
+    /// Replaces the [`MetricValue`] of this metric.
+    pub fn with_value(mut self, value: MetricValue) -> Self {
+        self.value = value;
+        self
+    }
+}
+
+/// Normalizes the sample rate, so that a sampled metric stands in for a whole number of metrics.
+///
+/// Rates above `1` are treated as `1`, and the number of metrics is limited to [`u32::MAX`].
+/// Returns [`None`] for rates of `0` or below, as such metrics do not stand in for any metrics.
+pub(crate) fn normalize_sample_rate(sample_rate: f64) -> Option<f64> {
+    if sample_rate >= 1. {
+        return Some(1.);
+    }
+    if sample_rate <= 0. || sample_rate.is_nan() {
+        return None;
+    }
+    let weight = (1. / sample_rate).round().min(u32::MAX as f64);
+    Some(1. / weight)
+}
+
+/// Derives the metadata of a metric with the same type, unit and tags, but a different key.
+///
+/// Returns [`None`] if the limit of interned metrics has been reached.
+pub(crate) fn renamed(meta: &MetricMeta, key: &str) -> Option<&'static MetricMeta> {
+    Interner::global().intern_derived(meta, Derivation::Renamed(key))
+}
+
+/// Derives the metadata of a metric with the additional tag `key`,
+/// or returns the metadata as-is if the metric already has that tag.
+///
+/// Returns [`None`] if the limit of interned metrics has been reached.
+pub(crate) fn tagged<'m>(meta: &'m MetricMeta, key: &str) -> Option<&'m MetricMeta> {
+    if meta.tag_keys.contains(&key) {
+        return Some(meta);
+    }
+    Interner::global().intern_derived(meta, Derivation::Tagged(key))
+}
+
+/// Sets the value of the tag `key` of the original metric, appending it if the tag is new.
+///
+/// See [`tagged`] for the metadata with the new tag.
+pub(crate) fn set_tag_value(
+    meta: &MetricMeta,
+    tag_values: TagValues,
+    key: &str,
+    value: impl Display,
+) -> TagValues {
+    let value = Str24::new(value.to_string());
+    let mut tag_values = tag_values.map(Vec::from).unwrap_or_default();
+    match meta.tag_keys.iter().position(|k| *k == key) {
+        Some(index) => tag_values[index] = value,
+        None => tag_values.push(value),
+    }
+    Some(tag_values.into())
 }
@@ -0,0 +1,995 @@
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use http_body_util::BodyExt;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use crate::protobuf::ProtoBuf;
+use crate::{
+    AggregatedMetric, AggregationSink, Aggregations, DDSketch, Dispatcher, MetricMeta, MetricUnit,
+    ThreadLocalAggregator, set_global_dispatcher,
+};
+
+type OtlpAggregator = Arc<ThreadLocalAggregator<io::Result<Vec<JoinHandle<()>>>>>;
+type EventHandler = Arc<dyn Fn(&OtlpEvent) + Send + Sync>;
+
+const HTTP_ENDPOINT: &str = "http://localhost:4318";
+const GRPC_ENDPOINT: &str = "http://localhost:4317";
+const HTTP_PATH: &str = "/v1/metrics";
+const GRPC_PATH: &str = "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+
+/// The default maximum message size of gRPC servers is 4 MiB.
+const MAX_PAYLOAD: usize = 4 * 1024 * 1024 - 64 * 1024;
+const DEFAULT_BUCKETS: &[f64] = &[
+    0., 5., 10., 25., 50., 75., 100., 250., 500., 750., 1000., 2500., 5000., 7500., 10000.,
+];
+const MAX_SCALE: i32 = 20;
+/// The minimum scale supported by OTLP, at which all finite values fit into 2 buckets.
+const MIN_SCALE: i32 = -10;
+
+const TEMPORALITY_DELTA: u64 = 1;
+
+/// The protocol used to submit metrics to an OTLP collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// Protobuf-encoded messages submitted via HTTP `POST` requests.
+    HttpProtobuf,
+    /// Protobuf-encoded messages submitted via unary gRPC calls.
+    Grpc,
+}
+
+/// The compression used for submitted OTLP payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpCompression {
+    /// Payloads are not compressed.
+    None,
+    /// Payloads are compressed using gzip.
+    Gzip,
+    /// Payloads are compressed using zstd.
+    Zstd,
+}
+
+impl OtlpCompression {
+    fn encoding(&self) -> Option<&'static str> {
+        match self {
+            OtlpCompression::None => None,
+            OtlpCompression::Gzip => Some("gzip"),
+            OtlpCompression::Zstd => Some("zstd"),
+        }
+    }
+}
+
+/// The outcome of submitting a payload to an OTLP collector.
+///
+/// These events are reported to the handler configured via [`OtlpBuilder::on_event`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OtlpEvent {
+    /// The payload was successfully submitted.
+    Submitted {
+        /// The HTTP status of the response.
+        status: u16,
+        /// The size of the (possibly compressed) payload.
+        bytes: usize,
+    },
+    /// The payload was dropped, because the request failed,
+    /// or the collector responded with an HTTP or gRPC error.
+    Dropped {
+        /// The HTTP status of the response, if any.
+        status: Option<u16>,
+        /// The size of the (possibly compressed) payload.
+        bytes: usize,
+        /// A description of the error.
+        error: String,
+    },
+}
+
+impl fmt::Display for OtlpEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtlpEvent::Submitted { status, bytes } => write!(
+                f,
+                "submitted metrics to OTLP collector (status={status}, bytes={bytes})"
+            ),
+            OtlpEvent::Dropped {
+                status,
+                bytes,
+                error,
+            } => {
+                f.write_str("error submitting metrics to OTLP collector (")?;
+                if let Some(status) = status {
+                    write!(f, "status={status}, ")?;
+                }
+                write!(f, "bytes={bytes}, err={error})")
+            }
+        }
+    }
+}
+
+/// The default event handler, which logs dropped payloads to stderr.
+fn log_dropped(event: &OtlpEvent) {
+    if let OtlpEvent::Dropped { .. } = event {
+        eprintln!("merni: {event}");
+    }
+}
+
+#[derive(Clone)]
+enum HistogramKind {
+    Explicit(Vec<f64>),
+    Exponential(usize),
+}
+
+/// Creates an [`OtlpBuilder`] with sensible defaults.
+///
+/// By default, it runs on the "current" tokio runtime, flushes metrics every 10 seconds,
+/// and submits metrics via HTTP to the `OTEL_EXPORTER_OTLP_METRICS_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` env variables if no explicit endpoint has been given,
+/// falling back to a collector running on `localhost`.
+///
+/// Calling [`try_init`](OtlpBuilder::try_init) will configure a global dispatcher and return an [`OtlpFlusher`].
+pub fn otlp<'a>(endpoint: impl Into<Option<&'a str>>) -> OtlpBuilder {
+    OtlpBuilder {
+        runtime: None,
+        flush_interval: Duration::from_secs(10),
+
+        endpoint: endpoint.into().map(String::from),
+        protocol: OtlpProtocol::HttpProtobuf,
+        compression: OtlpCompression::None,
+        headers: HeaderMap::new(),
+
+        prefix: String::new(),
+        global_tags: Vec::new(),
+        resource_attributes: ProtoBuf::default(),
+        histograms: HistogramKind::Explicit(DEFAULT_BUCKETS.into()),
+
+        on_event: Arc::new(log_dropped),
+    }
+}
+
+/// A builder for configuring common OTLP options.
+pub struct OtlpBuilder {
+    runtime: Option<Handle>,
+    flush_interval: Duration,
+
+    endpoint: Option<String>,
+    protocol: OtlpProtocol,
+    compression: OtlpCompression,
+    headers: HeaderMap,
+
+    prefix: String,
+    global_tags: Vec<(String, String)>,
+    resource_attributes: ProtoBuf,
+    histograms: HistogramKind,
+
+    on_event: EventHandler,
+}
+
+impl OtlpBuilder {
+    /// Sets a global prefix to all the emitted metrics.
+    ///
+    /// For example, this could be `"myservice."`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Adds a global attribute to all the emitted data points.
+    ///
+    /// For example, this could be something like `"hostname"`, or similar.
+    pub fn global_tag(mut self, key: &str, value: &str) -> Self {
+        self.global_tags.push((key.into(), value.into()));
+        self
+    }
+
+    /// Adds an attribute to the OTLP `Resource` all metrics are being reported for.
+    ///
+    /// For example, this could be `"service.name"`.
+    pub fn resource_attribute(mut self, key: &str, value: &str) -> Self {
+        write_attribute(&mut self.resource_attributes, 1, key, value);
+        self
+    }
+
+    /// Adds a header to all the requests, for example for authentication.
+    pub fn header(mut self, key: &str, value: &str) -> io::Result<Self> {
+        let key = HeaderName::try_from(key).map_err(io::Error::other)?;
+        let value = HeaderValue::try_from(value).map_err(io::Error::other)?;
+        self.headers.append(key, value);
+        Ok(self)
+    }
+
+    /// Sets the protocol used to submit metrics.
+    ///
+    /// This defaults to [`OtlpProtocol::HttpProtobuf`].
+    pub fn protocol(mut self, protocol: OtlpProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the compression used for submitted payloads.
+    ///
+    /// This defaults to [`OtlpCompression::None`].
+    pub fn compression(mut self, compression: OtlpCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Submits distributions as `Histogram`s with the given explicit bucket boundaries.
+    ///
+    /// This is the default, with boundaries suited for millisecond timings.
+    pub fn buckets(mut self, buckets: &[f64]) -> Self {
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        self.histograms = HistogramKind::Explicit(buckets);
+        self
+    }
+
+    /// Submits distributions as `ExponentialHistogram`s.
+    ///
+    /// The histogram scale is chosen for each data point, so that no more than
+    /// `max_buckets` positive and negative buckets are needed.
+    /// At least 2 buckets are needed for values on both sides of `1`.
+    pub fn exponential_histograms(mut self, max_buckets: usize) -> Self {
+        self.histograms = HistogramKind::Exponential(max_buckets.max(2));
+        self
+    }
+
+    /// Sets a handler which is called with the outcome of each submitted payload.
+    ///
+    /// By default, dropped payloads are logged to stderr.
+    pub fn on_event(mut self, on_event: impl Fn(&OtlpEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Arc::new(on_event);
+        self
+    }
+
+    /// Explicitly sets a tokio runtime [`Handle`] to use for the flusher thread.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Explicitly sets a flush interval.
+    ///
+    /// This defaults to 10 seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Turns the builder into an [`OtlpSink`].
+    pub fn into_sink(self) -> io::Result<OtlpSink> {
+        let runtime = self.runtime.unwrap_or_else(Handle::current);
+
+        let url = match (self.endpoint, self.protocol) {
+            (Some(endpoint), OtlpProtocol::HttpProtobuf) => endpoint,
+            (Some(endpoint), OtlpProtocol::Grpc) => format!("{}{GRPC_PATH}", base(&endpoint)),
+            (None, OtlpProtocol::HttpProtobuf) => {
+                match std::env::var("OTEL_EXPORTER_OTLP_METRICS_ENDPOINT") {
+                    Ok(endpoint) => endpoint,
+                    Err(_) => {
+                        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT");
+                        let endpoint = endpoint.as_deref().unwrap_or(HTTP_ENDPOINT);
+                        format!("{}{HTTP_PATH}", base(endpoint))
+                    }
+                }
+            }
+            (None, OtlpProtocol::Grpc) => {
+                let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT");
+                let endpoint = endpoint.as_deref().unwrap_or(GRPC_ENDPOINT);
+                format!("{}{GRPC_PATH}", base(endpoint))
+            }
+        };
+
+        let mut client = reqwest::ClientBuilder::new().default_headers(self.headers);
+        if self.protocol == OtlpProtocol::Grpc {
+            client = client.http2_prior_knowledge();
+        }
+
+        Ok(OtlpSink {
+            runtime,
+            client: client.build().map_err(io::Error::other)?,
+            url,
+            protocol: self.protocol,
+            compression: self.compression,
+
+            join_handles: Vec::new(),
+
+            metrics_buf: ProtoBuf::default(),
+            scratch_buf: String::new(),
+            prefix: self.prefix,
+            global_tags: self.global_tags,
+            resource_attributes: self.resource_attributes,
+            histograms: self.histograms,
+            on_event: self.on_event,
+
+            last_flush: unix_nanos(SystemTime::now())?,
+        })
+    }
+
+    /// Initializes the OTLP sink and aggregator, registering it as a global dispatcher.
+    pub fn try_init(self) -> io::Result<OtlpFlusher> {
+        let flush_interval = self.flush_interval;
+        let sink = self.into_sink()?;
+
+        let aggregator = Arc::new(ThreadLocalAggregator::new(flush_interval, sink));
+        let dispatcher = Dispatcher::new(Arc::clone(&aggregator));
+        set_global_dispatcher(dispatcher)
+            .map_err(|_| io::Error::other("unable to set global dispatcher"))?;
+        Ok(OtlpFlusher { aggregator })
+    }
+}
+
+fn base(endpoint: &str) -> &str {
+    endpoint.trim_end_matches('/')
+}
+
+/// This is a wrapper struct that allows flushing aggregated metrics to an OTLP collector.
+pub struct OtlpFlusher {
+    aggregator: OtlpAggregator,
+}
+impl OtlpFlusher {
+    /// Flushes aggregated metrics to the OTLP collector
+    pub async fn flush(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let tasks = self.aggregator.flush(timeout).map_err(io::Error::other)??;
+        for task in tasks {
+            task.await.map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An aggregator sink which exports metrics to an OpenTelemetry collector, using OTLP.
+///
+/// Counters are exported as delta `Sum`s, gauges as `Gauge`s, distributions
+/// as either `Histogram`s or `ExponentialHistogram`s, and sets as `Gauge`s of their cardinality.
+pub struct OtlpSink {
+    runtime: Handle,
+    client: reqwest::Client,
+    url: String,
+    protocol: OtlpProtocol,
+    compression: OtlpCompression,
+
+    join_handles: Vec<JoinHandle<()>>,
+
+    metrics_buf: ProtoBuf,
+    scratch_buf: String,
+    prefix: String,
+    global_tags: Vec<(String, String)>,
+    resource_attributes: ProtoBuf,
+    histograms: HistogramKind,
+    on_event: EventHandler,
+
+    last_flush: u64,
+}
+
+impl AggregationSink for OtlpSink {
+    type Output = io::Result<Vec<JoinHandle<()>>>;
+
+    fn emit(&mut self, metrics: Aggregations) -> Self::Output {
+        self.emit_metrics(metrics)
+    }
+}
+
+// Field numbers of the `Metric` message
+const METRIC_NAME: u32 = 1;
+const METRIC_UNIT: u32 = 3;
+const METRIC_GAUGE: u32 = 5;
+const METRIC_SUM: u32 = 7;
+const METRIC_HISTOGRAM: u32 = 9;
+const METRIC_EXPONENTIAL_HISTOGRAM: u32 = 10;
+
+impl OtlpSink {
+    fn emit_metrics(&mut self, metrics: Aggregations) -> io::Result<Vec<JoinHandle<()>>> {
+        let start = self.last_flush;
+        let now = unix_nanos(SystemTime::now())?;
+        self.last_flush = now;
+        let times = (start, now);
+
+        for (meta, value) in metrics.counters {
+            self.push_metric(&meta, METRIC_SUM, |buf, sink| {
+                buf.message(1, |point| {
+                    sink.write_number_point(point, &meta, times, value)
+                });
+                buf.uint64(2, TEMPORALITY_DELTA);
+                buf.bool(3, true);
+            })?;
+        }
+        for (meta, value) in metrics.gauges {
+            self.push_metric(&meta, METRIC_GAUGE, |buf, sink| {
+                buf.message(1, |point| {
+                    sink.write_number_point(point, &meta, times, value.last)
+                });
+            })?;
+        }
+        for (meta, set) in metrics.sets {
+            let value = set.cardinality() as f64;
+            self.push_metric(&meta, METRIC_GAUGE, |buf, sink| {
+                buf.message(1, |point| {
+                    sink.write_number_point(point, &meta, times, value)
+                });
+            })?;
+        }
+        let distributions = metrics
+            .distributions
+            .into_iter()
+            .map(|(meta, values)| (meta, HistogramValues::precise(values.weighted_values())));
+        let sketches = metrics
+            .sketches
+            .into_iter()
+            .map(|(meta, sketch)| (meta, HistogramValues::sketch(&sketch)));
+        for (meta, values) in distributions.chain(sketches) {
+            if values.count == 0 {
+                continue;
+            }
+            let values = &values;
+            let field = match self.histograms {
+                HistogramKind::Explicit(_) => METRIC_HISTOGRAM,
+                HistogramKind::Exponential(_) => METRIC_EXPONENTIAL_HISTOGRAM,
+            };
+            self.push_metric(&meta, field, |buf, sink| {
+                buf.message(1, |point| match &sink.histograms {
+                    HistogramKind::Explicit(bounds) => write_histogram_point(
+                        point,
+                        &sink.global_tags,
+                        &meta,
+                        times,
+                        bounds,
+                        values,
+                    ),
+                    HistogramKind::Exponential(max_buckets) => write_exponential_point(
+                        point,
+                        &sink.global_tags,
+                        &meta,
+                        times,
+                        *max_buckets,
+                        values,
+                    ),
+                });
+                buf.uint64(2, TEMPORALITY_DELTA);
+            })?;
+        }
+        self.flush()?;
+
+        Ok(std::mem::take(&mut self.join_handles))
+    }
+
+    /// Writes a single `Metric` message, with its data encoded by the given closure.
+    fn push_metric(
+        &mut self,
+        meta: &AggregatedMetric,
+        data_field: u32,
+        write_data: impl FnOnce(&mut ProtoBuf, &Self),
+    ) -> io::Result<()> {
+        self.scratch_buf.clear();
+        self.scratch_buf.push_str(&self.prefix);
+        self.scratch_buf.push_str(meta.key());
+
+        let mut metrics_buf = std::mem::take(&mut self.metrics_buf);
+        metrics_buf.message(2, |metric| {
+            metric.string(METRIC_NAME, &self.scratch_buf);
+            if let Some(unit) = unit_name(meta) {
+                metric.string(METRIC_UNIT, unit);
+            }
+            metric.message(data_field, |data| write_data(data, self));
+        });
+        self.metrics_buf = metrics_buf;
+
+        if self.metrics_buf.len() >= MAX_PAYLOAD {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn write_number_point(
+        &self,
+        point: &mut ProtoBuf,
+        meta: &AggregatedMetric,
+        (start, now): (u64, u64),
+        value: f64,
+    ) {
+        write_attributes(point, 7, &self.global_tags, meta);
+        point.fixed64(2, start);
+        point.fixed64(3, now);
+        point.optional_double(4, value);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.metrics_buf.len() == 0 {
+            return Ok(());
+        }
+
+        // ExportMetricsServiceRequest > ResourceMetrics
+        let mut request = ProtoBuf::default();
+        request.message(1, |resource_metrics| {
+            resource_metrics.message(1, |resource| {
+                resource
+                    .buf
+                    .extend_from_slice(&self.resource_attributes.buf);
+            });
+            resource_metrics.message(2, |scope_metrics| {
+                scope_metrics.message(1, |scope| {
+                    scope.string(1, env!("CARGO_PKG_NAME"));
+                    scope.string(2, env!("CARGO_PKG_VERSION"));
+                });
+                scope_metrics.buf.extend_from_slice(&self.metrics_buf.buf);
+            });
+        });
+        self.metrics_buf.clear();
+
+        self.do_flush(request.buf)
+    }
+
+    fn do_flush(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        let payload = match self.compression {
+            OtlpCompression::None => payload,
+            OtlpCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&payload)?;
+                encoder.finish()?
+            }
+            OtlpCompression::Zstd => zstd::bulk::compress(&payload, 0)?,
+        };
+        let bytes = payload.len();
+
+        let request = self.client.post(&self.url);
+        let request = match self.protocol {
+            OtlpProtocol::HttpProtobuf => {
+                let request = request.header(header::CONTENT_TYPE, "application/x-protobuf");
+                let request = match self.compression.encoding() {
+                    Some(encoding) => request.header(header::CONTENT_ENCODING, encoding),
+                    None => request,
+                };
+                request.body(payload)
+            }
+            OtlpProtocol::Grpc => {
+                let compressed = self.compression.encoding().is_some();
+                let mut body = Vec::with_capacity(payload.len() + 5);
+                body.push(compressed as u8);
+                body.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                body.extend_from_slice(&payload);
+
+                let request = request
+                    .header(header::CONTENT_TYPE, "application/grpc")
+                    .header(header::TE, "trailers");
+                let request = match self.compression.encoding() {
+                    Some(encoding) => request.header("grpc-encoding", encoding),
+                    None => request,
+                };
+                request.body(body)
+            }
+        }
+        .send();
+
+        let on_event = Arc::clone(&self.on_event);
+        self.join_handles.push(self.runtime.spawn(async move {
+            on_event(&submit(request, bytes).await);
+        }));
+
+        Ok(())
+    }
+}
+
+/// Awaits the response of a submitted payload.
+///
+/// gRPC errors are reported in the `grpc-status` header, or in the trailers which follow the body.
+async fn submit(
+    request: impl Future<Output = reqwest::Result<reqwest::Response>>,
+    bytes: usize,
+) -> OtlpEvent {
+    let dropped = |status, error| OtlpEvent::Dropped {
+        status,
+        bytes,
+        error,
+    };
+    let response = match request.await {
+        Ok(response) => response,
+        Err(err) => return dropped(None, err.to_string()),
+    };
+
+    let status = response.status();
+    let header_error = grpc_error(response.headers());
+    let body = match reqwest::Body::from(response).collect().await {
+        Ok(body) => body,
+        Err(err) => return dropped(Some(status.as_u16()), err.to_string()),
+    };
+    let grpc_error = header_error.or_else(|| body.trailers().and_then(grpc_error));
+
+    if !status.is_success() {
+        let response_text = String::from_utf8_lossy(&body.to_bytes()).into_owned();
+        dropped(
+            Some(status.as_u16()),
+            format!("HTTP status {status}, response={response_text}"),
+        )
+    } else if let Some(error) = grpc_error {
+        dropped(Some(status.as_u16()), error)
+    } else {
+        OtlpEvent::Submitted {
+            status: status.as_u16(),
+            bytes,
+        }
+    }
+}
+
+/// Formats a non-`OK` `grpc-status`, along with its `grpc-message`.
+fn grpc_error(headers: &HeaderMap) -> Option<String> {
+    let status = headers.get("grpc-status").filter(|status| *status != "0")?;
+    let mut error = format!("grpc-status={}", String::from_utf8_lossy(status.as_bytes()));
+    if let Some(message) = headers.get("grpc-message") {
+        let message = String::from_utf8_lossy(message.as_bytes());
+        let _ = write!(error, ", grpc-message={message}");
+    }
+    Some(error)
+}
+
+/// The values of a distribution, weighted by the number of times they were observed.
+struct HistogramValues {
+    values: Vec<(f64, u64)>,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl HistogramValues {
+    fn precise(values: impl IntoIterator<Item = (f64, u64)>) -> Self {
+        let values: Vec<_> = values.into_iter().collect();
+        let (mut count, mut sum, mut min, mut max) = (0, 0., f64::INFINITY, f64::NEG_INFINITY);
+        for (value, weight) in &values {
+            count += weight;
+            sum += value * *weight as f64;
+            min = value.min(min);
+            max = value.max(max);
+        }
+        Self {
+            values,
+            count,
+            sum,
+            min,
+            max,
+        }
+    }
+
+    fn sketch(sketch: &DDSketch) -> Self {
+        Self {
+            values: sketch.values().collect(),
+            count: sketch.count(),
+            sum: sketch.sum(),
+            min: sketch.min(),
+            max: sketch.max(),
+        }
+    }
+}
+
+fn write_histogram_point(
+    point: &mut ProtoBuf,
+    global_tags: &[(String, String)],
+    meta: &AggregatedMetric,
+    (start, now): (u64, u64),
+    bounds: &[f64],
+    values: &HistogramValues,
+) {
+    let mut bucket_counts = vec![0; bounds.len() + 1];
+    for (value, count) in &values.values {
+        bucket_counts[bounds.partition_point(|bound| bound < value)] += count;
+    }
+
+    write_attributes(point, 9, global_tags, meta);
+    point.fixed64(2, start);
+    point.fixed64(3, now);
+    point.fixed64(4, values.count);
+    point.optional_double(5, values.sum);
+    point.packed_fixed64(6, bucket_counts.into_iter());
+    point.packed_double(7, bounds);
+    point.optional_double(11, values.min);
+    point.optional_double(12, values.max);
+}
+
+fn write_exponential_point(
+    point: &mut ProtoBuf,
+    global_tags: &[(String, String)],
+    meta: &AggregatedMetric,
+    (start, now): (u64, u64),
+    max_buckets: usize,
+    values: &HistogramValues,
+) {
+    let histogram = ExponentialHistogram::new(&values.values, max_buckets);
+
+    write_attributes(point, 1, global_tags, meta);
+    point.fixed64(2, start);
+    point.fixed64(3, now);
+    point.fixed64(4, values.count);
+    point.optional_double(5, values.sum);
+    point.sint32(6, histogram.scale);
+    point.fixed64(7, histogram.zero_count);
+    for (field, buckets) in [(8, &histogram.positive), (9, &histogram.negative)] {
+        if !buckets.counts.is_empty() {
+            point.message(field, |msg| {
+                msg.sint32(1, buckets.offset);
+                msg.packed_uint64(2, buckets.counts.iter().copied());
+            });
+        }
+    }
+    point.optional_double(12, values.min);
+    point.optional_double(13, values.max);
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ExponentialBuckets {
+    offset: i32,
+    counts: Vec<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+struct ExponentialHistogram {
+    scale: i32,
+    zero_count: u64,
+    positive: ExponentialBuckets,
+    negative: ExponentialBuckets,
+}
+
+impl ExponentialHistogram {
+    /// Buckets the given weighted values, using the largest scale that needs at most
+    /// `max_buckets` buckets.
+    ///
+    /// The scale is never below [`MIN_SCALE`], which might need up to 2 buckets.
+    fn new(values: &[(f64, u64)], max_buckets: usize) -> Self {
+        // the bucket index at `MAX_SCALE`, which is downscaled by shifting
+        let index =
+            |value: f64| (value.abs().log2() * (1u64 << MAX_SCALE) as f64).ceil() as i64 - 1;
+
+        let mut ranges = [(i64::MAX, i64::MIN); 2];
+        for (value, _) in values.iter().filter(|(v, _)| *v != 0. && v.is_finite()) {
+            let range = &mut ranges[(*value < 0.) as usize];
+            let index = index(*value);
+            *range = (range.0.min(index), range.1.max(index));
+        }
+        let mut shift = 0;
+        while shift < MAX_SCALE - MIN_SCALE
+            && ranges.iter().any(|(min, max)| {
+                min <= max && ((max >> shift) - (min >> shift)) as usize >= max_buckets
+            })
+        {
+            shift += 1;
+        }
+
+        let mut histogram = Self {
+            scale: MAX_SCALE - shift,
+            zero_count: 0,
+            positive: Default::default(),
+            negative: Default::default(),
+        };
+        for (buckets, (min, max)) in [&mut histogram.positive, &mut histogram.negative]
+            .into_iter()
+            .zip(ranges)
+        {
+            if min <= max {
+                buckets.offset = (min >> shift) as i32;
+                buckets.counts = vec![0; ((max >> shift) - (min >> shift) + 1) as usize];
+            }
+        }
+        for (value, count) in values {
+            if *value == 0. || !value.is_finite() {
+                histogram.zero_count += if *value == 0. { *count } else { 0 };
+                continue;
+            }
+            let buckets = if *value > 0. {
+                &mut histogram.positive
+            } else {
+                &mut histogram.negative
+            };
+            let idx = (index(*value) >> shift) as i32 - buckets.offset;
+            buckets.counts[idx as usize] += count;
+        }
+
+        histogram
+    }
+}
+
+/// Writes the global tags and the metrics tags as `attributes` of a data point.
+///
+/// The field number of the `attributes` differs between the data point types.
+fn write_attributes(
+    buf: &mut ProtoBuf,
+    field: u32,
+    global_tags: &[(String, String)],
+    meta: &AggregatedMetric,
+) {
+    for (key, value) in global_tags {
+        write_attribute(buf, field, key, value);
+    }
+    for (key, value) in meta.tags() {
+        write_attribute(buf, field, key, value);
+    }
+}
+
+/// Writes a `KeyValue` message with a string value.
+fn write_attribute(buf: &mut ProtoBuf, field: u32, key: &str, value: &str) {
+    buf.message(field, |kv| {
+        kv.string(1, key);
+        kv.message(2, |any_value| any_value.string(1, value));
+    });
+}
+
+/// The unit of the metric, following the UCUM conventions used by OpenTelemetry.
+fn unit_name(meta: &MetricMeta) -> Option<&'static str> {
+    Some(match meta.value_unit() {
+        MetricUnit::Nanoseconds => "ns",
+        MetricUnit::Microseconds => "us",
+        MetricUnit::Milliseconds => "ms",
+        MetricUnit::Seconds => "s",
+        MetricUnit::Bytes => "By",
+        MetricUnit::Kibibytes => "KiBy",
+        MetricUnit::Mebibytes => "MiBy",
+        MetricUnit::Percent => "%",
+        MetricUnit::Ratio => "1",
+        MetricUnit::Custom(unit) => unit,
+        _ => return None,
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> io::Result<u64> {
+    let duration = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(io::Error::other)?;
+    Ok(duration.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    use crate::{counter, distribution, gauge, set_local_dispatcher};
+
+    use super::*;
+
+    #[test]
+    fn buckets_exponential_histograms() {
+        let values = HistogramValues::precise([1., 2., 4., 0., -3.].map(|v| (v, 1)));
+        let histogram = ExponentialHistogram::new(&values.values, 160);
+        assert_eq!(histogram.zero_count, 1);
+        assert_eq!(histogram.positive.counts.iter().sum::<u64>(), 3);
+        assert!(histogram.positive.counts.len() <= 160);
+        assert_eq!(histogram.negative.counts, &[1]);
+
+        // `1`, `2` and `4` are exact powers of two, and thus the upper bound of their buckets
+        let values = HistogramValues::precise([1., 2., 4.].map(|v| (v, 1)));
+        let histogram = ExponentialHistogram::new(&values.values, 4);
+        assert_eq!(histogram.scale, 0);
+        assert_eq!(histogram.positive.offset, -1);
+        assert_eq!(histogram.positive.counts, &[1, 1, 1]);
+    }
+
+    #[test]
+    fn limits_exponential_histogram_scale() {
+        // the bucket indexes of values on both sides of `1` never converge
+        let values = HistogramValues::precise([0.5, 2.].map(|v| (v, 1)));
+        let histogram = ExponentialHistogram::new(&values.values, 1);
+        assert_eq!(histogram.scale, MIN_SCALE);
+        assert_eq!(histogram.positive.offset, -1);
+        assert_eq!(histogram.positive.counts, &[1, 1]);
+
+        let histogram = ExponentialHistogram::new(&values.values, 2);
+        assert_eq!(histogram.scale, -1);
+        assert_eq!(histogram.positive.counts, &[1, 1]);
+    }
+
+    const OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    /// Accepts a single HTTP request, returning its path, headers and body.
+    fn mock_collector(listener: TcpListener, response: &[u8]) -> (String, Vec<String>, Vec<u8>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let path = request_line.split_whitespace().nth(1).unwrap().to_owned();
+
+        let mut headers = vec![];
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end().to_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            headers.push(header);
+        }
+        let content_length = headers
+            .iter()
+            .find_map(|h| h.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        stream.write_all(response).unwrap();
+        (path, headers, body)
+    }
+
+    #[tokio::test]
+    async fn exports_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/metrics", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || mock_collector(listener, OK_RESPONSE));
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let captured = Arc::clone(&events);
+        let sink = otlp(endpoint.as_str())
+            .on_event(move |event| captured.lock().unwrap().push(event.to_string()))
+            .compression(OtlpCompression::Zstd)
+            .resource_attribute("service.name", "merni-test")
+            .global_tag("env", "test")
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+
+        counter!("some.counter": 1, "tag" => "value");
+        gauge!("some.gauge": 2);
+        distribution!("some.distribution"@s: 3);
+        drop(guard);
+
+        let flusher = OtlpFlusher { aggregator };
+        flusher.flush(None).await.unwrap();
+
+        let (path, headers, body) = collector.join().unwrap();
+        assert_eq!(path, "/v1/metrics");
+        assert!(headers.contains(&"content-type: application/x-protobuf".into()));
+        assert!(headers.contains(&"content-encoding: zstd".into()));
+
+        let body = zstd::decode_all(body.as_slice()).unwrap();
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"merni-test"));
+        assert!(contains(b"some.counter"));
+        assert!(contains(b"some.gauge"));
+        assert!(contains(b"some.distribution"));
+        assert!(contains(b"\x1a\x01s")); // the `s` unit
+        assert!(contains(b"env"));
+        assert!(contains(b"value"));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("submitted metrics to OTLP collector (status=200"));
+    }
+
+    #[tokio::test]
+    async fn reports_grpc_status_in_trailers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/metrics", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+                Trailer: grpc-status, grpc-message\r\nConnection: close\r\n\r\n\
+                0\r\ngrpc-status: 8\r\ngrpc-message: too large\r\n\r\n";
+            mock_collector(listener, response)
+        });
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let captured = Arc::clone(&events);
+        let sink = otlp(endpoint.as_str())
+            .on_event(move |event| captured.lock().unwrap().push(event.to_string()))
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+        counter!("some.counter": 1);
+        drop(guard);
+
+        let flusher = OtlpFlusher { aggregator };
+        flusher.flush(None).await.unwrap();
+        collector.join().unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("error submitting metrics to OTLP collector (status=200"));
+        assert!(events[0].ends_with("err=grpc-status=8, grpc-message=too large)"));
+    }
+}
@@ -0,0 +1,702 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{
+    AggregatedMetric, AggregationSink, Aggregations, Dispatcher, MetricMeta, MetricUnit,
+    ThreadLocalAggregator, set_global_dispatcher,
+};
+
+type PrometheusAggregator = Arc<ThreadLocalAggregator<()>>;
+type SharedState = Arc<Mutex<PrometheusState>>;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SCRAPE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates a [`PrometheusBuilder`] with sensible defaults.
+///
+/// By default, it aggregates metrics every 10 seconds, and uses the default
+/// Prometheus histogram buckets for distributions.
+/// No HTTP endpoint is being served unless one is configured using [`listen`](PrometheusBuilder::listen).
+///
+/// Calling [`try_init`](PrometheusBuilder::try_init) will configure a global dispatcher and return a [`PrometheusExporter`].
+pub fn prometheus() -> PrometheusBuilder {
+    PrometheusBuilder {
+        flush_interval: Duration::from_secs(10),
+        listen: None,
+
+        prefix: String::new(),
+        global_labels: String::new(),
+        buckets: DEFAULT_BUCKETS.into(),
+    }
+}
+
+/// A builder for configuring common Prometheus options.
+pub struct PrometheusBuilder {
+    flush_interval: Duration,
+    listen: Option<String>,
+
+    prefix: String,
+    global_labels: String,
+    buckets: Vec<f64>,
+}
+
+impl PrometheusBuilder {
+    /// Sets a global prefix to all the exposed metrics.
+    ///
+    /// For example, this could be `"myservice_"`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Adds a global label to all the exposed metrics.
+    ///
+    /// For example, this could be something like `"hostname"`, or similar.
+    pub fn global_tag(mut self, key: &str, value: &str) -> Self {
+        write_label(&mut self.global_labels, key, value);
+        self
+    }
+
+    /// Sets the upper bounds of the histogram buckets used for distributions.
+    ///
+    /// This defaults to the default buckets of the official Prometheus clients.
+    /// The bounds of timers and other durations are in seconds, which they are exported in.
+    pub fn buckets(mut self, buckets: &[f64]) -> Self {
+        self.buckets = buckets.into();
+        self.buckets.sort_by(f64::total_cmp);
+        self.buckets.dedup();
+        self
+    }
+
+    /// Serves a minimal HTTP `/metrics` endpoint on the given address.
+    ///
+    /// The endpoint is served until the [`PrometheusExporter`] is dropped.
+    pub fn listen(mut self, addr: &str) -> Self {
+        self.listen = Some(addr.into());
+        self
+    }
+
+    /// Explicitly sets a flush interval.
+    ///
+    /// This defaults to 10 seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Turns the builder into a [`PrometheusSink`].
+    pub fn into_sink(self) -> PrometheusSink {
+        let state = PrometheusState {
+            prefix: self.prefix,
+            global_labels: self.global_labels,
+            buckets: self.buckets,
+            families: Default::default(),
+        };
+        PrometheusSink {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Creates the Prometheus sink and aggregator, and starts the HTTP endpoint if configured.
+    ///
+    /// Other than [`try_init`](Self::try_init), this does not register a global dispatcher.
+    pub fn into_exporter(mut self) -> io::Result<PrometheusExporter> {
+        let flush_interval = self.flush_interval;
+        let listen = self.listen.take();
+        let sink = self.into_sink();
+        let handle = sink.handle();
+
+        let aggregator = Arc::new(ThreadLocalAggregator::new(flush_interval, sink));
+        let server = match listen {
+            Some(addr) => Some(serve(&addr, Arc::downgrade(&aggregator), handle.clone())?),
+            None => None,
+        };
+
+        Ok(PrometheusExporter {
+            aggregator,
+            handle,
+            server,
+        })
+    }
+
+    /// Initializes the Prometheus sink and aggregator, registering it as a global dispatcher.
+    pub fn try_init(self) -> io::Result<PrometheusExporter> {
+        let exporter = self.into_exporter()?;
+
+        let dispatcher = Dispatcher::new(Arc::clone(&exporter.aggregator));
+        set_global_dispatcher(dispatcher)
+            .map_err(|_| io::Error::other("unable to set global dispatcher"))?;
+        Ok(exporter)
+    }
+}
+
+/// This is a wrapper struct that allows rendering the aggregated metrics in the Prometheus text format.
+///
+/// Dropping the exporter stops the HTTP endpoint, if one is configured.
+pub struct PrometheusExporter {
+    aggregator: PrometheusAggregator,
+    handle: PrometheusHandle,
+    server: Option<HttpServer>,
+}
+impl PrometheusExporter {
+    /// Flushes the aggregated metrics, and renders all metrics in the Prometheus text format.
+    pub fn render(&self, timeout: Option<Duration>) -> io::Result<String> {
+        self.aggregator.flush(timeout).map_err(io::Error::other)?;
+        Ok(self.handle.render())
+    }
+
+    /// Returns a [`PrometheusHandle`] which can be used to render metrics without flushing.
+    pub fn handle(&self) -> PrometheusHandle {
+        self.handle.clone()
+    }
+
+    /// The address the HTTP endpoint is listening on, if configured.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.as_ref().map(|server| server.local_addr)
+    }
+}
+
+/// A handle to the cumulative state of a [`PrometheusSink`].
+#[derive(Clone)]
+pub struct PrometheusHandle {
+    state: SharedState,
+}
+impl PrometheusHandle {
+    /// Renders all the metrics in the Prometheus text format.
+    ///
+    /// This only includes metrics that have been flushed to the sink.
+    pub fn render(&self) -> String {
+        self.state.lock().unwrap().render()
+    }
+}
+
+/// An aggregator sink which keeps cumulative metrics, to be scraped by Prometheus.
+///
+/// Counters are summed up across flushes, gauges keep their last value,
+/// distributions are turned into histograms, and sets into gauges of their cardinality.
+pub struct PrometheusSink {
+    state: SharedState,
+}
+
+impl PrometheusSink {
+    /// Returns a [`PrometheusHandle`] which can render the metrics aggregated by this sink.
+    pub fn handle(&self) -> PrometheusHandle {
+        PrometheusHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl AggregationSink for PrometheusSink {
+    type Output = ();
+
+    fn emit(&mut self, metrics: Aggregations) -> Self::Output {
+        self.state.lock().unwrap().merge(metrics)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FamilyType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl FamilyType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FamilyType::Counter => "counter",
+            FamilyType::Gauge => "gauge",
+            FamilyType::Histogram => "histogram",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    /// Counts `count` observations of `value`, not including them in the `sum`.
+    fn observe(&mut self, bounds: &[f64], value: f64, count: u64) {
+        let idx = bounds.partition_point(|bound| *bound < value);
+        if let Some(bucket) = self.buckets.get_mut(idx) {
+            *bucket += count;
+        }
+        self.count += count;
+    }
+}
+
+enum Series {
+    Value(f64),
+    Histogram(Histogram),
+}
+
+struct Family {
+    ty: FamilyType,
+    /// The series of this family, keyed by their rendered labels.
+    series: BTreeMap<String, Series>,
+}
+
+struct PrometheusState {
+    prefix: String,
+    global_labels: String,
+    buckets: Vec<f64>,
+
+    families: BTreeMap<String, Family>,
+}
+
+impl PrometheusState {
+    fn merge(&mut self, metrics: Aggregations) {
+        for (meta, value) in metrics.counters {
+            let scale = unit_scale(&meta);
+            if let Some(Series::Value(counter)) =
+                self.series(&meta, FamilyType::Counter, || Series::Value(0.))
+            {
+                *counter += value * scale;
+            }
+        }
+        for (meta, value) in metrics.gauges {
+            let scale = unit_scale(&meta);
+            if let Some(Series::Value(gauge)) =
+                self.series(&meta, FamilyType::Gauge, || Series::Value(0.))
+            {
+                *gauge = value.last * scale;
+            }
+        }
+        for (meta, set) in metrics.sets {
+            if let Some(Series::Value(gauge)) =
+                self.series(&meta, FamilyType::Gauge, || Series::Value(0.))
+            {
+                *gauge = set.cardinality() as f64;
+            }
+        }
+        let buckets = std::mem::take(&mut self.buckets);
+        for (meta, values) in metrics.distributions {
+            let scale = unit_scale(&meta);
+            if let Some(histogram) = self.histogram(&meta, buckets.len()) {
+                for (value, weight) in values.weighted_values() {
+                    let value = value * scale;
+                    histogram.observe(&buckets, value, weight);
+                    histogram.sum += value * weight as f64;
+                }
+            }
+        }
+        for (meta, sketch) in metrics.sketches {
+            let scale = unit_scale(&meta);
+            if let Some(histogram) = self.histogram(&meta, buckets.len()) {
+                for (value, count) in sketch.values() {
+                    histogram.observe(&buckets, value * scale, count);
+                }
+                histogram.sum += sketch.sum() * scale;
+            }
+        }
+        self.buckets = buckets;
+    }
+
+    fn histogram(&mut self, meta: &AggregatedMetric, num_buckets: usize) -> Option<&mut Histogram> {
+        let series = self.series(meta, FamilyType::Histogram, || {
+            Series::Histogram(Histogram {
+                buckets: vec![0; num_buckets],
+                sum: 0.,
+                count: 0,
+            })
+        });
+        match series {
+            Some(Series::Histogram(histogram)) => Some(histogram),
+            _ => None,
+        }
+    }
+
+    /// Looks up or creates the series for the given metric.
+    ///
+    /// Returns [`None`] if a metric with the same name but a different type already exists.
+    fn series(
+        &mut self,
+        meta: &AggregatedMetric,
+        ty: FamilyType,
+        create: impl FnOnce() -> Series,
+    ) -> Option<&mut Series> {
+        let mut name = String::new();
+        write_name(&mut name, &self.prefix);
+        write_name(&mut name, meta.key());
+        if let Some((unit, _)) = unit_suffix(meta) {
+            let mut suffix = String::from("_");
+            write_name(&mut suffix, unit);
+            if !name.ends_with(&suffix) && !name.ends_with(&format!("{suffix}_total")) {
+                name.push_str(&suffix);
+            }
+        }
+        if ty == FamilyType::Counter && !name.ends_with("_total") {
+            name.push_str("_total");
+        }
+
+        let mut labels = self.global_labels.clone();
+        for (key, value) in meta.tags() {
+            write_label(&mut labels, key, value);
+        }
+
+        let family = self.families.entry(name).or_insert_with(|| Family {
+            ty,
+            series: Default::default(),
+        });
+        if family.ty != ty {
+            return None;
+        }
+        Some(family.series.entry(labels).or_insert_with(create))
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(output, "# TYPE {name} {}", family.ty.as_str());
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        write_sample(&mut output, name, "", labels, None, *value);
+                    }
+                    Series::Histogram(histogram) => {
+                        let mut cumulative = 0;
+                        for (bound, count) in self.buckets.iter().zip(&histogram.buckets) {
+                            cumulative += count;
+                            let le = format_value(*bound);
+                            let value = cumulative as f64;
+                            write_sample(&mut output, name, "_bucket", labels, Some(&le), value);
+                        }
+                        let count = histogram.count as f64;
+                        write_sample(&mut output, name, "_bucket", labels, Some("+Inf"), count);
+                        write_sample(&mut output, name, "_sum", labels, None, histogram.sum);
+                        write_sample(&mut output, name, "_count", labels, None, count);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &str,
+    le: Option<&str>,
+    value: f64,
+) {
+    output.push_str(name);
+    output.push_str(suffix);
+    if !labels.is_empty() || le.is_some() {
+        output.push('{');
+        output.push_str(labels);
+        if let Some(le) = le {
+            if !labels.is_empty() {
+                output.push(',');
+            }
+            let _ = write!(output, "le=\"{le}\"");
+        }
+        output.push('}');
+    }
+    output.push(' ');
+    output.push_str(&format_value(value));
+    output.push('\n');
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf" } else { "-Inf" }.into()
+    } else {
+        value.to_string()
+    }
+}
+
+/// The unit of the metric, which is appended to its name following the Prometheus conventions,
+/// along with the factor its values are multiplied with.
+///
+/// Durations are exported in seconds, the Prometheus base unit, which the default buckets are in.
+fn unit_suffix(meta: &MetricMeta) -> Option<(&'static str, f64)> {
+    match meta.value_unit() {
+        MetricUnit::Unknown => None,
+        MetricUnit::Nanoseconds => Some(("seconds", 1e-9)),
+        MetricUnit::Microseconds => Some(("seconds", 1e-6)),
+        MetricUnit::Milliseconds => Some(("seconds", 1e-3)),
+        unit => Some((unit.as_str(), 1.)),
+    }
+}
+
+fn unit_scale(meta: &MetricMeta) -> f64 {
+    unit_suffix(meta).map_or(1., |(_, scale)| scale)
+}
+
+/// Writes a metric name, replacing all characters that are not valid within Prometheus names.
+fn write_name(buf: &mut String, name: &str) {
+    for c in name.chars() {
+        let valid = c.is_ascii_alphanumeric() || c == '_' || c == ':';
+        let valid = valid && !(buf.is_empty() && c.is_ascii_digit());
+        buf.push(if valid { c } else { '_' });
+    }
+}
+
+/// Writes a `key="value"` label pair, separated by `,` from existing labels.
+fn write_label(buf: &mut String, key: &str, value: &str) {
+    if !buf.is_empty() {
+        buf.push(',');
+    }
+    let key_start = buf.len();
+    for c in key.chars() {
+        let valid = c.is_ascii_alphanumeric() || c == '_';
+        let valid = valid && !(buf.len() == key_start && c.is_ascii_digit());
+        buf.push(if valid { c } else { '_' });
+    }
+    buf.push_str("=\"");
+    for c in value.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '"' => buf.push_str("\\\""),
+            '\n' => buf.push_str("\\n"),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// A minimal HTTP server running on a background thread, which is stopped on [`Drop`].
+struct HttpServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wake up the blocking `accept` with a connection of our own
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT).is_ok()
+            && let Some(thread) = self.thread.take()
+        {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts a minimal HTTP server on a background thread, serving the `/metrics` endpoint.
+fn serve(
+    addr: &str,
+    aggregator: Weak<ThreadLocalAggregator<()>>,
+    handle: PrometheusHandle,
+) -> io::Result<HttpServer> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or(io::ErrorKind::AddrNotAvailable)?;
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let thread = std::thread::Builder::new()
+        .name("merni-prometheus".into())
+        .spawn({
+            let stopped = Arc::clone(&stopped);
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let _ = handle_request(stream, &aggregator, &handle);
+                }
+            }
+        })?;
+
+    Ok(HttpServer {
+        local_addr,
+        stopped,
+        thread: Some(thread),
+    })
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    aggregator: &Weak<ThreadLocalAggregator<()>>,
+    handle: &PrometheusHandle,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip over all the request headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(|path| path.split('?').next().unwrap_or_default());
+
+    if method != Some("GET") || path != Some("/metrics") {
+        let body = "not found\n";
+        return write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+    }
+
+    if let Some(aggregator) = aggregator.upgrade() {
+        let _ = aggregator.flush(Some(SCRAPE_FLUSH_TIMEOUT));
+    }
+    let body = handle.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::{counter, distribution, gauge, set_local_dispatcher, timer};
+
+    use super::*;
+
+    #[test]
+    fn keeps_cumulative_state() {
+        let exporter = prometheus()
+            .prefix("merni_")
+            .global_tag("env", "test")
+            .buckets(&[1., 5.])
+            .into_exporter()
+            .unwrap();
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&exporter.aggregator)));
+
+        for i in 1..=2 {
+            counter!("some.counter": i, "tag" => "a\"b");
+            gauge!("some.gauge": i);
+            distribution!("some.distribution": i * 3);
+        }
+        exporter.render(None).unwrap();
+
+        for i in 3..=4 {
+            counter!("some.counter": i, "tag" => "a\"b");
+            gauge!("some.gauge": i);
+            distribution!("some.distribution": i * 3);
+        }
+        drop(guard);
+
+        let output = exporter.render(None).unwrap();
+        assert_eq!(
+            output,
+            r#"# TYPE merni_some_counter_total counter
+merni_some_counter_total{env="test",tag="a\"b"} 10
+# TYPE merni_some_distribution histogram
+merni_some_distribution_bucket{env="test",le="1"} 0
+merni_some_distribution_bucket{env="test",le="5"} 1
+merni_some_distribution_bucket{env="test",le="+Inf"} 4
+merni_some_distribution_sum{env="test"} 30
+merni_some_distribution_count{env="test"} 4
+# TYPE merni_some_gauge gauge
+merni_some_gauge{env="test"} 4
+"#
+        );
+    }
+
+    #[test]
+    fn appends_unit_suffixes() {
+        let exporter = prometheus().into_exporter().unwrap();
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&exporter.aggregator)));
+        counter!("sent"@b: 1);
+        gauge!("memory_mebibytes"@mib: 2);
+        gauge!("some.load"@"jobs": 3);
+        drop(guard);
+
+        let output = exporter.render(None).unwrap();
+        assert_eq!(
+            output,
+            "# TYPE memory_mebibytes gauge\nmemory_mebibytes 2\n\
+             # TYPE sent_bytes_total counter\nsent_bytes_total 1\n\
+             # TYPE some_load_jobs gauge\nsome_load_jobs 3\n"
+        );
+    }
+
+    #[test]
+    fn exports_durations_in_seconds() {
+        let exporter = prometheus()
+            .buckets(&[0.01, 0.1, 1.])
+            .into_exporter()
+            .unwrap();
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&exporter.aggregator)));
+        timer!("request.duration": Duration::from_millis(50));
+        counter!("busy"@us: 1500);
+        drop(guard);
+
+        let output = exporter.render(None).unwrap();
+        assert_eq!(
+            output,
+            r#"# TYPE busy_seconds_total counter
+busy_seconds_total 0.0015
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le="0.01"} 0
+request_duration_seconds_bucket{le="0.1"} 1
+request_duration_seconds_bucket{le="1"} 1
+request_duration_seconds_bucket{le="+Inf"} 1
+request_duration_seconds_sum 0.05
+request_duration_seconds_count 1
+"#
+        );
+    }
+
+    #[test]
+    fn serves_metrics_endpoint() {
+        let exporter = prometheus().listen("127.0.0.1:0").into_exporter().unwrap();
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&exporter.aggregator)));
+        counter!("some.counter": 1);
+        drop(guard);
+
+        let request = |path: &str| {
+            let mut stream = TcpStream::connect(exporter.local_addr().unwrap()).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = request("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("some_counter_total 1\n"));
+
+        let response = request("/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // dropping the exporter stops the server, and releases the port
+        let addr = exporter.local_addr().unwrap();
+        drop(exporter);
+        TcpListener::bind(addr).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+// Not all of the encoding functions are needed by all of the sinks.
+#![cfg_attr(not(all(feature = "datadog", feature = "otlp")), allow(dead_code))]
+
+const VARINT: u32 = 0;
+const I64: u32 = 1;
+const LEN: u32 = 2;
+
+/// A minimal protobuf encoder, sufficient to write the messages of the supported wire formats.
+#[derive(Default)]
+pub(crate) struct ProtoBuf {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl ProtoBuf {
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear()
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    pub fn uint64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, VARINT);
+            self.varint(value);
+        }
+    }
+
+    pub fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    pub fn sint32(&mut self, field: u32, value: i32) {
+        self.uint64(field, zigzag(value) as u64);
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) {
+        self.uint64(field, value as u64);
+    }
+
+    pub fn fixed64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, I64);
+            self.buf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub fn double(&mut self, field: u32, value: f64) {
+        if value != 0. {
+            self.optional_double(field, value);
+        }
+    }
+
+    /// Writes a double, even if it has the default value of `0`.
+    pub fn optional_double(&mut self, field: u32, value: f64) {
+        self.key(field, I64);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, LEN);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// Writes a nested message, which is encoded by the given closure.
+    pub fn message(&mut self, field: u32, f: impl FnOnce(&mut Self)) {
+        let mut nested = Self::default();
+        f(&mut nested);
+        self.bytes(field, &nested.buf);
+    }
+
+    pub fn packed_fixed64(&mut self, field: u32, values: impl ExactSizeIterator<Item = u64>) {
+        if values.len() > 0 {
+            self.key(field, LEN);
+            self.varint(values.len() as u64 * 8);
+            for value in values {
+                self.buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn packed_double(&mut self, field: u32, values: &[f64]) {
+        self.packed_fixed64(field, values.iter().map(|v| v.to_bits()));
+    }
+
+    pub fn packed_sint32(&mut self, field: u32, values: impl Iterator<Item = i32>) {
+        self.packed_uint64(field, values.map(|value| zigzag(value) as u64));
+    }
+
+    pub fn packed_uint64(&mut self, field: u32, values: impl Iterator<Item = u64>) {
+        let mut nested = Self::default();
+        for value in values {
+            nested.varint(value);
+        }
+        if !nested.buf.is_empty() {
+            self.bytes(field, &nested.buf);
+        }
+    }
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
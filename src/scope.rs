@@ -0,0 +1,281 @@
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Display};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+use smallvec::SmallVec;
+use smol_buf::Str24;
+
+use crate::MetricMeta;
+use crate::interner::{AddressMap, Interner};
+use crate::tags::TagValues;
+
+// because accessing a static global is faster than a thread local,
+// this is set once any scope has been entered, and never reset
+static SCOPES_ENTERED: AtomicBool = AtomicBool::new(false);
+thread_local! {
+    /// The entered scopes, each with the tags of all the scopes below it merged in.
+    static SCOPE_STACK: RefCell<Vec<ScopedTags>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A metric with scoped tags applied, along with the values of those tags.
+struct ScopedMeta {
+    meta: &'static MetricMeta,
+    tag_values: Box<[Str24]>,
+}
+
+/// Tags which are added to all the metrics emitted within a scope.
+///
+/// Scopes can be nested, in which case inner tags override outer tags with the same key.
+/// Tags of the metric itself take precedence over scoped tags.
+///
+/// The metrics with scoped tags applied are interned just like the metrics of
+/// [`Dispatcher::emit_dynamic`](crate::Dispatcher::emit_dynamic), and share its limit.
+/// Once that limit is reached, metrics which would need new scoped metrics are dropped,
+/// and counted by the `merni.interner.dropped_metrics` counter.
+///
+/// Scopes are thread-local. To propagate them to other threads, capture the
+/// [`current`](Self::current) tags and enter them on the other thread.
+/// Futures can be wrapped using [`instrument`](Self::instrument), which enters the scope
+/// whenever the future is being polled.
+#[derive(Clone, Default)]
+pub struct ScopedTags {
+    inner: Arc<ScopeInner>,
+}
+
+#[derive(Default)]
+struct ScopeInner {
+    tags: Vec<(String, String)>,
+    /// The metrics with these tags applied, keyed by the address of the original.
+    ///
+    /// These are cached along with the tags, so that futures which are instrumented with
+    /// the same tags keep using them across polls.
+    /// [`None`] if the metric was dropped because of the limit of interned metrics.
+    metas: RwLock<AddressMap<Option<ScopedMeta>>>,
+}
+
+impl Debug for ScopedTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedTags")
+            .field("tags", &self.inner.tags)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScopedTags {
+    /// Creates a new set of scoped tags.
+    pub fn new<K: AsRef<str>, V: Display>(tags: impl IntoIterator<Item = (K, V)>) -> Self {
+        let mut merged = vec![];
+        for (key, value) in tags {
+            merge_tag(&mut merged, key.as_ref(), value.to_string());
+        }
+        Self::from_tags(merged)
+    }
+
+    fn from_tags(tags: Vec<(String, String)>) -> Self {
+        let inner = ScopeInner {
+            tags,
+            metas: Default::default(),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Captures all the tags of the scopes currently entered on this thread.
+    pub fn current() -> Self {
+        if !SCOPES_ENTERED.load(Ordering::Relaxed) {
+            return Self::default();
+        }
+        SCOPE_STACK.with_borrow(|stack| stack.last().cloned().unwrap_or_default())
+    }
+
+    /// Enters the scope on the current thread, until the returned guard is dropped.
+    pub fn enter(&self) -> ScopedTagsGuard {
+        if !SCOPES_ENTERED.load(Ordering::Relaxed) {
+            SCOPES_ENTERED.store(true, Ordering::Relaxed);
+        }
+        SCOPE_STACK.with_borrow_mut(|stack| {
+            let scope = match stack.last() {
+                Some(outer) => outer.merged(self),
+                None => self.clone(),
+            };
+            stack.push(scope);
+        });
+        ScopedTagsGuard {
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Runs the closure within the scope.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.enter();
+        f()
+    }
+
+    /// Wraps the [`Future`], entering the scope whenever it is being polled.
+    pub fn instrument<F: Future>(self, future: F) -> WithTags<F> {
+        WithTags { future, tags: self }
+    }
+
+    /// Merges the `inner` tags into these outer tags.
+    ///
+    /// This reuses either of the tags if the other one is empty, along with its cached metrics.
+    fn merged(&self, inner: &ScopedTags) -> ScopedTags {
+        if inner.inner.tags.is_empty() {
+            return self.clone();
+        }
+        if self.inner.tags.is_empty() {
+            return inner.clone();
+        }
+        let mut merged = self.inner.tags.clone();
+        for (key, value) in &inner.inner.tags {
+            merge_tag(&mut merged, key, value.clone());
+        }
+        Self::from_tags(merged)
+    }
+
+    /// Adds these tags to the metric, looking up or interning the scoped metric.
+    ///
+    /// Returns [`None`] if the limit of interned metrics has been reached.
+    fn apply(
+        &self,
+        meta: &'static MetricMeta,
+        tag_values: TagValues,
+    ) -> Option<(&'static MetricMeta, TagValues)> {
+        let address = meta as *const MetricMeta as usize;
+        if let Some(scoped) = self.inner.metas.read().unwrap().get(&address) {
+            return Some(scoped.as_ref()?.apply(tag_values));
+        }
+
+        let scoped = scope_meta(meta, &self.inner.tags);
+        let mut metas = self.inner.metas.write().unwrap();
+        let scoped = metas.entry(address).or_insert(scoped);
+        Some(scoped.as_ref()?.apply(tag_values))
+    }
+}
+
+impl ScopedMeta {
+    /// Prepends the scoped tag values to the tag values of the metric.
+    fn apply(&self, tag_values: TagValues) -> (&'static MetricMeta, TagValues) {
+        if self.tag_values.is_empty() {
+            return (self.meta, tag_values);
+        }
+        let scoped_values = self.tag_values.iter().cloned();
+        let tag_values = tag_values.as_deref().unwrap_or_default().iter().cloned();
+        (self.meta, Some(scoped_values.chain(tag_values).collect()))
+    }
+}
+
+/// Adds a tag, overriding the value of an existing tag with the same key in place.
+fn merge_tag(tags: &mut Vec<(String, String)>, key: &str, value: String) {
+    match tags.iter_mut().find(|(k, _)| k == key) {
+        Some(tag) => tag.1 = value,
+        None => tags.push((key.to_owned(), value)),
+    }
+}
+
+/// Runs the closure with the given tags added to all the metrics emitted within it.
+///
+/// ```
+/// merni::with_tags([("tenant", "sentry")], || {
+///     merni::counter!("some.counter": 1);
+/// });
+/// ```
+pub fn with_tags<K: AsRef<str>, V: Display, R>(
+    tags: impl IntoIterator<Item = (K, V)>,
+    f: impl FnOnce() -> R,
+) -> R {
+    ScopedTags::new(tags).in_scope(f)
+}
+
+/// A guard for entered [`ScopedTags`], which leaves the scope on [`Drop`].
+pub struct ScopedTagsGuard {
+    // the scope is entered on the current thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ScopedTagsGuard {
+    fn drop(&mut self) {
+        SCOPE_STACK.with_borrow_mut(|stack| stack.pop());
+    }
+}
+
+pin_project! {
+    /// A [`Future`] which enters its [`ScopedTags`] whenever it is being polled.
+    ///
+    /// This is created using [`ScopedTags::instrument`].
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    #[derive(Debug)]
+    pub struct WithTags<F> {
+        #[pin]
+        future: F,
+        tags: ScopedTags,
+    }
+}
+
+impl<F: Future> Future for WithTags<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = this.tags.enter();
+        this.future.poll(cx)
+    }
+}
+
+/// Adds the currently scoped tags to the metric.
+///
+/// The scoped tags come before the tags of the metric itself.
+/// Returns [`None`] if the metric should be dropped, as the limit of interned metrics has been reached.
+pub(crate) fn apply_scoped_tags(
+    meta: &'static MetricMeta,
+    tag_values: TagValues,
+) -> Option<(&'static MetricMeta, TagValues)> {
+    if !SCOPES_ENTERED.load(Ordering::Relaxed) {
+        return Some((meta, tag_values));
+    }
+    SCOPE_STACK.with_borrow(|stack| match stack.last() {
+        Some(scope) => scope.apply(meta, tag_values),
+        None => Some((meta, tag_values)),
+    })
+}
+
+/// Whether any tags are scoped on the current thread.
+pub(crate) fn has_scoped_tags() -> bool {
+    SCOPES_ENTERED.load(Ordering::Relaxed)
+        && SCOPE_STACK.with_borrow(|stack| {
+            stack
+                .last()
+                .is_some_and(|scope| !scope.inner.tags.is_empty())
+        })
+}
+
+/// Interns the metric with the given scoped tags applied,
+/// skipping the tags which are already defined on the metric.
+fn scope_meta(meta: &'static MetricMeta, tags: &[(String, String)]) -> Option<ScopedMeta> {
+    let tags: SmallVec<&(String, String), 8> = tags
+        .iter()
+        .filter(|(key, _)| !meta.tag_keys.contains(&key.as_str()))
+        .collect();
+    if tags.is_empty() {
+        return Some(ScopedMeta {
+            meta,
+            tag_values: Default::default(),
+        });
+    }
+
+    let tag_keys = tags.iter().map(|(key, _)| key.as_str());
+    let tag_keys = tag_keys.chain(meta.tag_keys.iter().copied());
+    let scoped_meta = Interner::global().intern(meta.ty(), meta.unit(), meta.key(), tag_keys)?;
+
+    Some(ScopedMeta {
+        meta: scoped_meta,
+        tag_values: tags.iter().map(|(_, value)| Str24::new(value)).collect(),
+    })
+}
@@ -0,0 +1,154 @@
+use rustc_hash::FxHashSet as HashSet;
+
+/// The maximum number of unique values kept in an exact set.
+const EXACT_LIMIT: usize = 1024;
+/// The number of bits of the hash used to select a HyperLogLog register.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// An aggregated set, counting the number of unique values.
+///
+/// Up to 1024 unique values are kept in an exact set. Beyond that, the set turns into a
+/// [HyperLogLog](https://en.wikipedia.org/wiki/HyperLogLog), which estimates the number of
+/// unique values with a standard error of about 1.6%, using a constant 4 KiB of memory.
+#[derive(Debug, Clone)]
+pub struct AggregatedSet {
+    repr: SetRepr,
+}
+
+#[derive(Debug, Clone)]
+enum SetRepr {
+    Exact(HashSet<u64>),
+    HyperLogLog(Box<[u8; REGISTERS]>),
+}
+
+impl Default for AggregatedSet {
+    fn default() -> Self {
+        Self {
+            repr: SetRepr::Exact(Default::default()),
+        }
+    }
+}
+
+impl AggregatedSet {
+    /// Adds a value to the set.
+    pub fn insert(&mut self, value: u64) {
+        match &mut self.repr {
+            SetRepr::Exact(values) => {
+                values.insert(value);
+                if values.len() > EXACT_LIMIT {
+                    self.hyperloglog_registers();
+                }
+            }
+            SetRepr::HyperLogLog(registers) => add_hash(registers, value),
+        }
+    }
+
+    /// Merges the `other` set into this one.
+    pub fn merge(&mut self, other: &AggregatedSet) {
+        match &other.repr {
+            SetRepr::Exact(values) => {
+                for value in values {
+                    self.insert(*value);
+                }
+            }
+            SetRepr::HyperLogLog(other) => {
+                let registers = self.hyperloglog_registers();
+                for (register, other) in registers.iter_mut().zip(other.iter()) {
+                    *register = (*register).max(*other);
+                }
+            }
+        }
+    }
+
+    /// The number of unique values in this set.
+    ///
+    /// This is an estimate once the set has turned into a HyperLogLog.
+    pub fn cardinality(&self) -> u64 {
+        match &self.repr {
+            SetRepr::Exact(values) => values.len() as u64,
+            SetRepr::HyperLogLog(registers) => estimate(registers),
+        }
+    }
+
+    /// Iterates over the unique values, as long as the set is exact.
+    pub fn values(&self) -> Option<impl ExactSizeIterator<Item = u64> + '_> {
+        match &self.repr {
+            SetRepr::Exact(values) => Some(values.iter().copied()),
+            SetRepr::HyperLogLog(_) => None,
+        }
+    }
+
+    fn hyperloglog_registers(&mut self) -> &mut [u8; REGISTERS] {
+        if let SetRepr::Exact(values) = &self.repr {
+            let mut registers = Box::new([0; REGISTERS]);
+            for value in values {
+                add_hash(&mut registers, *value);
+            }
+            self.repr = SetRepr::HyperLogLog(registers);
+        }
+        match &mut self.repr {
+            SetRepr::HyperLogLog(registers) => registers,
+            SetRepr::Exact(_) => unreachable!(),
+        }
+    }
+}
+
+fn add_hash(registers: &mut [u8; REGISTERS], value: u64) {
+    let hash = mix(value);
+    let index = (hash >> (64 - PRECISION)) as usize;
+    // the remaining bits, with a sentinel bit limiting the rank
+    let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+    let rank = rest.leading_zeros() as u8 + 1;
+    registers[index] = registers[index].max(rank);
+}
+
+fn estimate(registers: &[u8; REGISTERS]) -> u64 {
+    let m = REGISTERS as f64;
+    let alpha = 0.7213 / (1. + 1.079 / m);
+    let sum: f64 = registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+    let estimate = alpha * m * m / sum;
+
+    let zeros = registers.iter().filter(|r| **r == 0).count();
+    if estimate <= 2.5 * m && zeros > 0 {
+        // linear counting gives better estimates for small cardinalities
+        return (m * (m / zeros as f64).ln()).round() as u64;
+    }
+    estimate.round() as u64
+}
+
+/// Mixes the bits of the value, as the HyperLogLog requires uniformly distributed hashes.
+///
+/// This is the finalizer of `SplitMix64`.
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unique_values() {
+        let mut set = AggregatedSet::default();
+        for i in 0..100 {
+            set.insert(i % 10);
+        }
+        assert_eq!(set.cardinality(), 10);
+        assert_eq!(set.values().unwrap().len(), 10);
+
+        let mut other = AggregatedSet::default();
+        for i in 0..100_000 {
+            other.insert(i);
+        }
+        assert!(other.values().is_none());
+        let error = (other.cardinality() as f64 - 100_000.).abs() / 100_000.;
+        assert!(error < 0.05, "{}", other.cardinality());
+
+        set.merge(&other);
+        assert!(set.values().is_none());
+        assert_eq!(set.cardinality(), other.cardinality());
+    }
+}
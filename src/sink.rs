@@ -1,21 +1,205 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::Arc;
 
-use crate::Metric;
+#[cfg(feature = "aggregator")]
+use crate::{AggregatedMetric, AggregationSink, Aggregations};
+use crate::{Metric, MetricCell, MetricMeta, MetricType, MetricValue};
 
 /// A Sink for metrics emmission.
 pub trait Sink {
     /// This fn is being called when a metric is emitted.
     fn emit(&self, metric: Metric);
+
+    /// This fn is being called when a pre-registered metric handle is first used.
+    ///
+    /// Sinks returning `true` take over reading the values of the [`MetricCell`].
+    /// Otherwise, which is the default, each value is emitted as a regular [`Metric`].
+    fn register(&self, cell: &Arc<MetricCell>) -> bool {
+        let _ = cell;
+        false
+    }
 }
 
 impl<S: Sink> Sink for Arc<S> {
     fn emit(&self, metric: Metric) {
         (**self).emit(metric)
     }
+    fn register(&self, cell: &Arc<MetricCell>) -> bool {
+        (**self).register(cell)
+    }
 }
 impl<S: Sink> Sink for &S {
     #[inline]
     fn emit(&self, metric: Metric) {
         (**self).emit(metric)
     }
+    fn register(&self, cell: &Arc<MetricCell>) -> bool {
+        (**self).register(cell)
+    }
+}
+
+/// A sink which sends all metrics to both of its sinks.
+///
+/// This works both as a [`Sink`], and as an `AggregationSink`,
+/// in which case the output is a tuple of both outputs.
+#[derive(Debug, Clone)]
+pub struct Tee<A, B>(pub A, pub B);
+
+impl<A: Sink, B: Sink> Sink for Tee<A, B> {
+    fn emit(&self, metric: Metric) {
+        self.0.emit(metric.clone());
+        self.1.emit(metric);
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl<A: AggregationSink, B: AggregationSink> AggregationSink for Tee<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn emit(&mut self, metrics: Aggregations) -> Self::Output {
+        let a = self.0.emit(metrics.clone());
+        (a, self.1.emit(metrics))
+    }
+}
+
+/// A sink which only passes on the metrics matching a predicate, for example by key prefix or type.
+///
+/// This works both as a [`Sink`], and as an `AggregationSink`.
+#[derive(Debug, Clone)]
+pub struct Filter<S, F> {
+    sink: S,
+    predicate: F,
+}
+
+impl<S, F: Fn(&MetricMeta) -> bool> Filter<S, F> {
+    /// Creates a new filter, passing on the metrics for which the `predicate` returns `true`.
+    pub fn new(sink: S, predicate: F) -> Self {
+        Self { sink, predicate }
+    }
+}
+
+impl<S: Sink, F: Fn(&MetricMeta) -> bool> Sink for Filter<S, F> {
+    fn emit(&self, metric: Metric) {
+        if (self.predicate)(&metric) {
+            self.sink.emit(metric)
+        }
+    }
+
+    fn register(&self, cell: &Arc<MetricCell>) -> bool {
+        (self.predicate)(cell.key()) && self.sink.register(cell)
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl<S, F> AggregationSink for Filter<S, F>
+where
+    S: AggregationSink,
+    F: Fn(&MetricMeta) -> bool + Send + 'static,
+{
+    type Output = S::Output;
+
+    fn emit(&mut self, mut metrics: Aggregations) -> Self::Output {
+        metrics.retain(|metric| (self.predicate)(metric));
+        self.sink.emit(metrics)
+    }
+}
+
+/// A sink which maps all metrics before passing them on, for example to rename or retag them.
+///
+/// As a [`Sink`], the mapping function receives and returns a [`Metric`].
+/// As an `AggregationSink`, it receives and returns an `AggregatedMetric`,
+/// and metrics which are mapped to the same key are merged.
+#[derive(Debug, Clone)]
+pub struct Map<S, F> {
+    sink: S,
+    f: F,
+}
+
+impl<S, F> Map<S, F> {
+    /// Creates a new sink, mapping all metrics using `f`.
+    pub fn new(sink: S, f: F) -> Self {
+        Self { sink, f }
+    }
+}
+
+impl<S: Sink, F: Fn(Metric) -> Metric> Sink for Map<S, F> {
+    fn emit(&self, metric: Metric) {
+        self.sink.emit((self.f)(metric))
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl<S, F> AggregationSink for Map<S, F>
+where
+    S: AggregationSink,
+    F: Fn(AggregatedMetric) -> AggregatedMetric + Send + 'static,
+{
+    type Output = S::Output;
+
+    fn emit(&mut self, metrics: Aggregations) -> Self::Output {
+        self.sink.emit(metrics.map_metrics(&self.f))
+    }
+}
+
+/// A sink which randomly drops a fraction of metrics.
+///
+/// Counters which are passed on are scaled up by the inverse of the sample rate,
+/// so that their totals stay correct on average.
+///
+/// As a [`Sink`], each emitted metric is sampled individually.
+/// As an `AggregationSink`, each aggregated metric and tag combination is sampled as a whole.
+#[derive(Debug, Clone)]
+pub struct Sample<S> {
+    sink: S,
+    rate: f64,
+}
+
+impl<S> Sample<S> {
+    /// Creates a new sink, passing on metrics with a probability of `rate`, in the range `0..=1`.
+    pub fn new(sink: S, rate: f64) -> Self {
+        Self {
+            sink,
+            rate: rate.clamp(0., 1.),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        self.rate >= 1. || random() < self.rate
+    }
+}
+
+impl<S: Sink> Sink for Sample<S> {
+    fn emit(&self, metric: Metric) {
+        if !self.sample() {
+            return;
+        }
+        let metric = match metric.ty() {
+            MetricType::Counter => {
+                let value = MetricValue::new(metric.value().get() / self.rate);
+                metric.with_value(value)
+            }
+            _ => metric,
+        };
+        self.sink.emit(metric)
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl<S: AggregationSink> AggregationSink for Sample<S> {
+    type Output = S::Output;
+
+    fn emit(&mut self, mut metrics: Aggregations) -> Self::Output {
+        metrics.retain(|_| self.sample());
+        for value in metrics.counters.values_mut() {
+            *value /= self.rate;
+        }
+        self.sink.emit(metrics)
+    }
+}
+
+/// Returns a random number in the range `0..1`.
+fn random() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
 }
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// The relative accuracy of the sketch, as used by the Datadog agent.
+const EPS: f64 = 1. / 128.;
+/// The smallest positive value that can be distinguished from `0`.
+const MIN_VALUE: f64 = 1e-9;
+const MAX_KEY: i16 = i16::MAX;
+const DEFAULT_BIN_LIMIT: usize = 4096;
+
+/// The logarithmic mapping of values to sketch keys.
+///
+/// This is compatible with the mapping used by the Datadog agent,
+/// so sketches can be submitted to Datadog as-is.
+struct Mapping {
+    gamma_ln: f64,
+    bias: i32,
+    min: f64,
+}
+
+impl Mapping {
+    fn get() -> &'static Self {
+        static MAPPING: OnceLock<Mapping> = OnceLock::new();
+        MAPPING.get_or_init(|| {
+            let gamma_ln = (2. * EPS).ln_1p();
+            let bias = -(MIN_VALUE.ln() / gamma_ln).floor() as i32 + 1;
+            let mut mapping = Mapping {
+                gamma_ln,
+                bias,
+                min: 0.,
+            };
+            mapping.min = mapping.value(1);
+            mapping
+        })
+    }
+
+    fn key(&self, value: f64) -> i16 {
+        if value < 0. {
+            return -self.key(-value);
+        }
+        if value < self.min || value.is_nan() {
+            return 0;
+        }
+        let key = (value.ln() / self.gamma_ln).round() as i32 + self.bias;
+        key.clamp(1, MAX_KEY as i32) as i16
+    }
+
+    fn value(&self, key: i16) -> f64 {
+        if key < 0 {
+            return -self.value(-key);
+        }
+        if key == 0 {
+            return 0.;
+        }
+        ((key as i32 - self.bias) as f64 * self.gamma_ln).exp()
+    }
+}
+
+/// A distribution sketch with bounded memory, based on [DDSketch](https://arxiv.org/abs/1908.10693).
+///
+/// Values are being mapped to logarithmically sized bins, guaranteeing a relative accuracy
+/// of less than 1% for all quantiles, while keeping exact values for count, sum, min and max.
+#[derive(Debug, Clone)]
+pub struct DDSketch {
+    bins: BTreeMap<i16, u64>,
+    bin_limit: usize,
+
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for DDSketch {
+    fn default() -> Self {
+        Self::with_bin_limit(DEFAULT_BIN_LIMIT)
+    }
+}
+
+impl DDSketch {
+    /// Creates a new, empty sketch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new sketch, which holds at most `bin_limit` bins.
+    ///
+    /// Once the limit is reached, the bins of the lowest values are collapsed.
+    pub fn with_bin_limit(bin_limit: usize) -> Self {
+        Self {
+            bins: Default::default(),
+            bin_limit: bin_limit.max(1),
+
+            count: 0,
+            sum: 0.,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Adds a value to the sketch.
+    ///
+    /// Non-finite values are ignored, as they would poison the sum.
+    pub fn add(&mut self, value: f64) {
+        self.add_with_count(value, 1);
+    }
+
+    /// Adds a value to the sketch, as if it was added `count` times.
+    ///
+    /// Non-finite values are ignored, as they would poison the sum.
+    pub fn add_with_count(&mut self, value: f64, count: u64) {
+        if count == 0 || !value.is_finite() {
+            return;
+        }
+        let key = Mapping::get().key(value);
+        self.insert(key, count);
+
+        self.count += count;
+        self.sum += value * count as f64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Merges the `other` sketch into this one.
+    pub fn merge(&mut self, other: &DDSketch) {
+        for (key, count) in &other.bins {
+            self.insert(*key, *count);
+        }
+
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn insert(&mut self, key: i16, count: u64) {
+        *self.bins.entry(key).or_default() += count;
+
+        if self.bins.len() > self.bin_limit {
+            let (_lowest, lowest_count) = self.bins.pop_first().unwrap();
+            *self.bins.first_entry().unwrap().get_mut() += lowest_count;
+        }
+    }
+
+    /// The total number of values added to this sketch.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The total sum of values added to this sketch.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// The minimum value added to this sketch.
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// The maximum value added to this sketch.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Estimates the value at quantile `q`, which should be in the range `0..=1`.
+    ///
+    /// Returns [`None`] if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        if q <= 0. {
+            return Some(self.min);
+        }
+        if q >= 1. {
+            return Some(self.max);
+        }
+
+        let rank = (q * (self.count - 1) as f64) as u64;
+        let mut seen = 0;
+        for (value, count) in self.values() {
+            seen += count;
+            if seen > rank {
+                return Some(value.clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Iterates over the bins of this sketch, in ascending order of their representative value.
+    ///
+    /// Each bin is represented by a value and the number of observed values in that bin.
+    pub fn values(&self) -> impl ExactSizeIterator<Item = (f64, u64)> + '_ {
+        let mapping = Mapping::get();
+        self.bins
+            .iter()
+            .map(|(key, count)| (mapping.value(*key), *count))
+    }
+
+    /// Iterates over the raw keys and counts of the bins of this sketch.
+    ///
+    /// The keys are compatible with the ones used by the Datadog agent.
+    pub fn bins(&self) -> impl ExactSizeIterator<Item = (i16, u64)> + '_ {
+        self.bins.iter().map(|(key, count)| (*key, *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_are_accurate() {
+        let mut sketch = DDSketch::new();
+        for i in 1..=1000 {
+            sketch.add(i as f64);
+        }
+        sketch.add(0.);
+        sketch.add(-5.);
+
+        assert_eq!(sketch.count(), 1002);
+        assert_eq!(sketch.min(), -5.);
+        assert_eq!(sketch.max(), 1000.);
+        assert_eq!(sketch.quantile(0.), Some(-5.));
+        assert_eq!(sketch.quantile(1.), Some(1000.));
+
+        for q in [0.25, 0.5, 0.9, 0.99] {
+            let expected = (q * 1001.0f64).floor() - 1.;
+            let actual = sketch.quantile(q).unwrap();
+            assert!((actual - expected).abs() <= expected * 2. * EPS + 1.);
+        }
+    }
+
+    #[test]
+    fn merges_and_collapses_bins() {
+        let mut sketch = DDSketch::with_bin_limit(10);
+        let mut other = DDSketch::new();
+        for i in 1..=100 {
+            sketch.add(i as f64);
+            other.add(i as f64 * 100.);
+        }
+        assert_eq!(sketch.bins().len(), 10);
+        assert_eq!(sketch.bins().map(|(_, count)| count).sum::<u64>(), 100);
+
+        sketch.merge(&other);
+        assert_eq!(sketch.bins().len(), 10);
+        assert_eq!(sketch.count(), 200);
+        assert_eq!(sketch.min(), 1.);
+        assert_eq!(sketch.max(), 10000.);
+        assert_eq!(sketch.quantile(1.), Some(10000.));
+    }
+
+    #[test]
+    fn ignores_non_finite_values() {
+        let mut sketch = DDSketch::new();
+        sketch.add(f64::NAN);
+        sketch.add_with_count(f64::INFINITY, 2);
+        assert_eq!(sketch.count(), 0);
+        assert_eq!(sketch.bins().len(), 0);
+
+        sketch.add(1.);
+        sketch.add(f64::NEG_INFINITY);
+        assert_eq!(sketch.count(), 1);
+        assert_eq!(sketch.sum(), 1.);
+        assert_eq!(sketch.min(), 1.);
+        assert_eq!(sketch.max(), 1.);
+    }
+
+    #[test]
+    fn keys_are_datadog_compatible() {
+        let mapping = Mapping::get();
+        assert_eq!(mapping.bias, 1338);
+        assert_eq!(mapping.key(0.), 0);
+        assert_eq!(mapping.key(1.), 1338);
+        assert_eq!(mapping.key(-1.), -1338);
+        let value = mapping.value(mapping.key(42.));
+        assert!((value - 42.).abs() / 42. <= EPS);
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    AggregatedMetric, AggregationSink, Aggregations, Dispatcher, ThreadLocalAggregator,
+    set_global_dispatcher,
+};
+
+type DogStatsdAggregator = Arc<ThreadLocalAggregator<io::Result<()>>>;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "8125";
+const DEFAULT_UDP_MTU: usize = 1432;
+#[cfg(unix)]
+const DEFAULT_UDS_MTU: usize = 8192;
+#[cfg(unix)]
+const UDS_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Creates a [`DogStatsdBuilder`] with sensible defaults.
+///
+/// The `addr` can either be a plain `host:port` or `udp://host:port` UDP address,
+/// or a Unix domain socket path in the form of `unix:///path` or `unixgram:///path`
+/// for datagram sockets, and `unixstream:///path` for stream sockets.
+///
+/// By default, it flushes metrics every 10 seconds, and defaults to the `DD_DOGSTATSD_URL`,
+/// `DD_DOGSTATSD_SOCKET`, or `DD_AGENT_HOST` and `DD_DOGSTATSD_PORT` env variables
+/// if no explicit address has been given, falling back to `127.0.0.1:8125`.
+///
+/// Calling [`try_init`](DogStatsdBuilder::try_init) will configure a global dispatcher and return a [`DogStatsdFlusher`].
+pub fn dogstatsd<'a>(addr: impl Into<Option<&'a str>>) -> DogStatsdBuilder {
+    let addr = addr.into().map(String::from).unwrap_or_else(|| {
+        if let Ok(url) = std::env::var("DD_DOGSTATSD_URL") {
+            return url;
+        }
+        if let Ok(socket) = std::env::var("DD_DOGSTATSD_SOCKET") {
+            return format!("unix://{socket}");
+        }
+        let host = std::env::var("DD_AGENT_HOST").unwrap_or_else(|_| DEFAULT_HOST.into());
+        let port = std::env::var("DD_DOGSTATSD_PORT").unwrap_or_else(|_| DEFAULT_PORT.into());
+        format!("{host}:{port}")
+    });
+
+    DogStatsdBuilder {
+        flush_interval: Duration::from_secs(10),
+
+        addr,
+        mtu: None,
+
+        prefix: String::new(),
+        global_tags: String::new(),
+    }
+}
+
+/// A builder for configuring common DogStatsD options.
+pub struct DogStatsdBuilder {
+    flush_interval: Duration,
+
+    addr: String,
+    mtu: Option<usize>,
+
+    prefix: String,
+    global_tags: String,
+}
+
+impl DogStatsdBuilder {
+    /// Sets a global prefix to all the emitted metrics.
+    ///
+    /// For example, this could be `"myservice."`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Adds a global tag to all the emitted metrics.
+    ///
+    /// For example, this could be something like `"hostname"`, or similar.
+    pub fn global_tag(mut self, key: &str, value: &str) -> Self {
+        if !self.global_tags.is_empty() {
+            self.global_tags.push(',');
+        }
+        write_sanitized(&mut self.global_tags, key, TAG_RESERVED);
+        self.global_tags.push(':');
+        write_sanitized(&mut self.global_tags, value, TAG_RESERVED);
+        self
+    }
+
+    /// Explicitly sets the maximum size of a single datagram.
+    ///
+    /// Multiple metric lines are packed into a single datagram as long as they fit.
+    /// This defaults to 1432 bytes for UDP, which is safe for most networks,
+    /// and 8192 bytes for Unix domain sockets.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Explicitly sets a flush interval.
+    ///
+    /// This defaults to 10 seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Turns the builder into a [`DogStatsdSink`].
+    pub fn into_sink(self) -> io::Result<DogStatsdSink> {
+        let transport = Transport::new(&self.addr)?;
+        let mtu = self.mtu.unwrap_or(transport.default_mtu());
+
+        Ok(DogStatsdSink {
+            transport,
+            mtu,
+
+            prefix: self.prefix,
+            global_tags: self.global_tags,
+
+            datagram: Vec::with_capacity(mtu),
+            line: Vec::with_capacity(mtu),
+            suffix: String::new(),
+            scratch_buf: String::new(),
+        })
+    }
+
+    /// Initializes the DogStatsD sink and aggregator, registering it as a global dispatcher.
+    pub fn try_init(self) -> io::Result<DogStatsdFlusher> {
+        let flush_interval = self.flush_interval;
+        let sink = self.into_sink()?;
+
+        let aggregator = Arc::new(ThreadLocalAggregator::new(flush_interval, sink));
+        let dispatcher = Dispatcher::new(Arc::clone(&aggregator));
+        set_global_dispatcher(dispatcher)
+            .map_err(|_| io::Error::other("unable to set global dispatcher"))?;
+        Ok(DogStatsdFlusher { aggregator })
+    }
+}
+
+/// This is a wrapper struct that allows flushing aggregated metrics to DogStatsD.
+pub struct DogStatsdFlusher {
+    aggregator: DogStatsdAggregator,
+}
+impl DogStatsdFlusher {
+    /// Flushes aggregated metrics to DogStatsD
+    pub fn flush(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.aggregator.flush(timeout).map_err(io::Error::other)?
+    }
+}
+
+/// An aggregator sink which sends metrics to a DogStatsD server, like the Datadog agent.
+///
+/// Metrics are formatted as DogStatsD lines (`name:value|type|#tag:value`),
+/// and packed into datagrams up to the configured MTU.
+///
+/// Units are not part of the DogStatsD protocol, and are thus not being submitted.
+/// Sets are sent with their unique values, unless they have grown too large and only
+/// an estimate of their cardinality is known, which is then sent as a gauge.
+///
+/// Datagrams are sent via UDP, or a Unix datagram or stream socket.
+/// When using a stream socket, each datagram is prefixed with its length as a
+/// 32-bit little-endian integer.
+pub struct DogStatsdSink {
+    transport: Transport,
+    mtu: usize,
+
+    prefix: String,
+    global_tags: String,
+
+    datagram: Vec<u8>,
+    line: Vec<u8>,
+    suffix: String,
+    scratch_buf: String,
+}
+
+impl AggregationSink for DogStatsdSink {
+    type Output = io::Result<()>;
+
+    fn emit(&mut self, metrics: Aggregations) -> Self::Output {
+        self.emit_metrics(metrics)
+    }
+}
+
+const NAME_RESERVED: &[char] = &[':', '|', '@', '#', ',', '\n'];
+const TAG_RESERVED: &[char] = &['|', '#', ',', '\n'];
+
+impl DogStatsdSink {
+    fn emit_metrics(&mut self, metrics: Aggregations) -> io::Result<()> {
+        let mut result = Ok(());
+        let mut record = |res: io::Result<()>| {
+            if let Err(err) = res {
+                result = Err(err);
+            }
+        };
+
+        for (meta, value) in metrics.counters {
+            record(self.push_metric(&meta, "c", &[value], None));
+        }
+        for (meta, value) in metrics.gauges {
+            record(self.push_metric(&meta, "g", &[value.last], None));
+        }
+        for (meta, values) in metrics.distributions {
+            if values.weights.is_empty() {
+                record(self.push_metric(&meta, "d", &values.values, None));
+                continue;
+            }
+            // Sampled values are grouped by their weight, and sent along with their sample rate.
+            let mut by_weight: BTreeMap<u64, Vec<f64>> = BTreeMap::new();
+            for (value, weight) in values.weighted_values() {
+                by_weight.entry(weight).or_default().push(value);
+            }
+            for (weight, values) in by_weight {
+                let rate = (weight > 1).then(|| 1. / weight as f64);
+                record(self.push_metric(&meta, "d", &values, rate));
+            }
+        }
+        for (meta, set) in metrics.sets {
+            match set.values() {
+                Some(values) => {
+                    let values: Vec<_> = values.map(f64::from_bits).collect();
+                    record(self.push_metric(&meta, "s", &values, None));
+                }
+                None => record(self.push_metric(&meta, "g", &[set.cardinality() as f64], None)),
+            }
+        }
+        for (meta, sketch) in metrics.sketches {
+            // Each bin is sent as its representative value, with a sample rate
+            // which lets the server restore the number of values in that bin.
+            for (value, count) in sketch.values() {
+                let rate = (count > 1).then(|| 1. / count as f64);
+                record(self.push_metric(&meta, "d", &[value], rate));
+            }
+        }
+        record(self.send_datagram());
+
+        result
+    }
+
+    /// Writes one or more lines for the given metric.
+    ///
+    /// Multiple values are packed into a single line (`name:1:2:3|d`), up to the configured MTU.
+    fn push_metric(
+        &mut self,
+        meta: &AggregatedMetric,
+        ty: &str,
+        values: &[f64],
+        rate: Option<f64>,
+    ) -> io::Result<()> {
+        self.write_suffix(meta, ty, rate);
+
+        let mut name = std::mem::take(&mut self.scratch_buf);
+        name.clear();
+        write_sanitized(&mut name, &self.prefix, NAME_RESERVED);
+        write_sanitized(&mut name, meta.key(), NAME_RESERVED);
+
+        let mut result = Ok(());
+        self.line.clear();
+        for value in values {
+            let line_len = self.line.len();
+            if line_len == 0 {
+                self.line.extend_from_slice(name.as_bytes());
+            }
+            write!(&mut self.line, ":{value}")?;
+
+            let has_values = line_len > name.len();
+            if has_values && self.line.len() + self.suffix.len() > self.mtu {
+                self.line.truncate(line_len);
+                if let Err(err) = self.finish_line() {
+                    result = Err(err);
+                }
+                self.line.extend_from_slice(name.as_bytes());
+                write!(&mut self.line, ":{value}")?;
+            }
+        }
+        if !self.line.is_empty()
+            && let Err(err) = self.finish_line()
+        {
+            result = Err(err);
+        }
+        self.scratch_buf = name;
+
+        result
+    }
+
+    fn write_suffix(&mut self, meta: &AggregatedMetric, ty: &str, rate: Option<f64>) {
+        self.suffix.clear();
+        self.suffix.push('|');
+        self.suffix.push_str(ty);
+        if let Some(rate) = rate {
+            let _ = write!(&mut self.suffix, "|@{rate}");
+        }
+
+        let tags = meta.tags();
+        if tags.len() > 0 || !self.global_tags.is_empty() {
+            self.suffix.push_str("|#");
+            self.suffix.push_str(&self.global_tags);
+            if !self.global_tags.is_empty() && tags.len() > 0 {
+                self.suffix.push(',');
+            }
+            for (i, (key, value)) in tags.enumerate() {
+                if i > 0 {
+                    self.suffix.push(',');
+                }
+                write_sanitized(&mut self.suffix, key, TAG_RESERVED);
+                self.suffix.push(':');
+                write_sanitized(&mut self.suffix, value, TAG_RESERVED);
+            }
+        }
+    }
+
+    /// Appends the current line to the datagram, sending it first in case the line would not fit.
+    fn finish_line(&mut self) -> io::Result<()> {
+        self.line.extend_from_slice(self.suffix.as_bytes());
+
+        let mut result = Ok(());
+        if !self.datagram.is_empty() && self.datagram.len() + 1 + self.line.len() > self.mtu {
+            result = self.send_datagram();
+        }
+        if !self.datagram.is_empty() {
+            self.datagram.push(b'\n');
+        }
+        self.datagram.extend_from_slice(&self.line);
+        self.line.clear();
+
+        result
+    }
+
+    fn send_datagram(&mut self) -> io::Result<()> {
+        if self.datagram.is_empty() {
+            return Ok(());
+        }
+        let result = self.transport.send(&self.datagram);
+        self.datagram.clear();
+
+        result
+    }
+}
+
+/// The socket used to send datagrams.
+///
+/// Unix sockets are (re-)connected lazily, so that the sink recovers from agent restarts.
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    UnixDatagram {
+        path: PathBuf,
+        socket: Option<UnixDatagram>,
+    },
+    #[cfg(unix)]
+    UnixStream {
+        path: PathBuf,
+        stream: Option<UnixStream>,
+    },
+}
+
+impl Transport {
+    fn new(addr: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            let unix_path = addr
+                .strip_prefix("unix://")
+                .or_else(|| addr.strip_prefix("unixgram://"));
+            if let Some(path) = unix_path {
+                let path = PathBuf::from(path);
+                // The agent might not be up yet, so we will try connecting again on the first flush.
+                let socket = connect_datagram(&path).ok();
+                return Ok(Self::UnixDatagram { path, socket });
+            }
+            if let Some(path) = addr.strip_prefix("unixstream://") {
+                let path = PathBuf::from(path);
+                let stream = connect_stream(&path).ok();
+                return Ok(Self::UnixStream { path, stream });
+            }
+        }
+        let addr = addr.strip_prefix("udp://").unwrap_or(addr);
+
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(io::ErrorKind::AddrNotAvailable)?;
+        let local_addr: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(addr)?;
+
+        Ok(Self::Udp(socket))
+    }
+
+    fn default_mtu(&self) -> usize {
+        match self {
+            Self::Udp(_) => DEFAULT_UDP_MTU,
+            #[cfg(unix)]
+            _ => DEFAULT_UDS_MTU,
+        }
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(datagram).map(|_| ()),
+            #[cfg(unix)]
+            Self::UnixDatagram { path, socket } => {
+                let send = |socket: &UnixDatagram| socket.send(datagram).map(|_| ());
+                if let Some(connected) = socket
+                    && send(connected).is_ok()
+                {
+                    return Ok(());
+                }
+                // The agent might have been restarted, so try again with a fresh socket.
+                *socket = None;
+                let connected = connect_datagram(path)?;
+                send(&connected)?;
+                *socket = Some(connected);
+                Ok(())
+            }
+            #[cfg(unix)]
+            Self::UnixStream { path, stream } => {
+                let len = u32::try_from(datagram.len()).map_err(io::Error::other)?;
+                let send = |mut stream: &UnixStream| {
+                    stream.write_all(&len.to_le_bytes())?;
+                    stream.write_all(datagram)
+                };
+                if let Some(connected) = stream
+                    && send(connected).is_ok()
+                {
+                    return Ok(());
+                }
+                // A partially written frame can not be recovered, so start over with a new connection.
+                *stream = None;
+                let connected = connect_stream(path)?;
+                send(&connected)?;
+                *stream = Some(connected);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_datagram(path: &std::path::Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.set_write_timeout(Some(UDS_WRITE_TIMEOUT))?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn connect_stream(path: &std::path::Path) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_write_timeout(Some(UDS_WRITE_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn sanitize<'s>(s: &'s str, reserved: &[char]) -> std::borrow::Cow<'s, str> {
+    if s.contains(reserved) {
+        s.replace(reserved, "_").into()
+    } else {
+        s.into()
+    }
+}
+
+fn write_sanitized(buf: &mut String, s: &str, reserved: &[char]) {
+    buf.push_str(&sanitize(s, reserved));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{counter, distribution, gauge, set_local_dispatcher};
+
+    use super::*;
+
+    fn recv_all(socket: &UdpSocket) -> Vec<String> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 65536];
+        let mut datagrams = vec![];
+        while let Ok(len) = socket.recv(&mut buf) {
+            datagrams.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+        datagrams
+    }
+
+    #[test]
+    fn sends_dogstatsd_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        let sink = dogstatsd(addr.as_str())
+            .prefix("merni.")
+            .global_tag("env", "test")
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+
+        counter!("some.counter": 1, "tag" => "a|b");
+        counter!("some.counter": 2, "tag" => "a|b");
+        gauge!("some.gauge": 3);
+        gauge!("some.gauge": 4);
+        // the values of separate call sites are merged in arbitrary order
+        for value in [5, 6] {
+            distribution!("some.distribution": value);
+        }
+
+        drop(guard);
+        aggregator.flush(None).unwrap().unwrap();
+
+        let datagrams = recv_all(&server);
+        assert_eq!(datagrams.len(), 1);
+        let mut lines: Vec<_> = datagrams[0].lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            &[
+                "merni.some.counter:3|c|#env:test,tag:a_b",
+                "merni.some.distribution:5:6|d|#env:test",
+                "merni.some.gauge:4|g|#env:test",
+            ]
+        );
+    }
+
+    #[test]
+    fn forwards_sample_rates() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        let sink = dogstatsd(addr.as_str()).into_sink().unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+
+        for _ in 0..100 {
+            counter!("sampled.counter": 1, @rate = 0.25);
+            distribution!("sampled.distribution": 1, "tag" => "value", @rate = 0.25);
+        }
+
+        drop(guard);
+        aggregator.flush(None).unwrap().unwrap();
+
+        let datagrams = recv_all(&server);
+        let lines: Vec<_> = datagrams.iter().flat_map(|d| d.lines()).collect();
+        let (mut counters, mut distributions) = (0, 0);
+        for line in lines {
+            if let Some(value) = line.strip_prefix("sampled.counter:") {
+                let value: f64 = value.strip_suffix("|c").unwrap().parse().unwrap();
+                assert_eq!(value % 4., 0.);
+                counters += 1;
+            } else {
+                assert!(line.starts_with("sampled.distribution:1:1"));
+                assert!(line.ends_with("|d|@0.25|#tag:value"));
+                distributions += 1;
+            }
+        }
+        // each call is sampled with a 1/4 chance, so missing all 100 of them is practically impossible
+        assert_eq!(counters, 1);
+        assert_eq!(distributions, 1);
+    }
+
+    #[test]
+    fn splits_datagrams_by_mtu() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        let sink = dogstatsd(addr.as_str()).mtu(64).into_sink().unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+
+        for i in 0..100 {
+            distribution!("some.distribution": i);
+        }
+
+        drop(guard);
+        aggregator.flush(None).unwrap().unwrap();
+
+        let datagrams = recv_all(&server);
+        assert!(datagrams.len() > 1);
+
+        let mut values = vec![];
+        for datagram in &datagrams {
+            assert!(datagram.len() <= 64);
+            for line in datagram.lines() {
+                let line = line.strip_prefix("some.distribution:").unwrap();
+                let line = line.strip_suffix("|d").unwrap();
+                values.extend(line.split(':').map(|v| v.parse::<u32>().unwrap()));
+            }
+        }
+        assert_eq!(values, (0..100).collect::<Vec<_>>());
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("merni-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sends_via_unix_datagram_and_reconnects() {
+        let path = socket_path("dgram");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let sink = dogstatsd(format!("unix://{}", path.display()).as_str())
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let dispatcher = Dispatcher::new(Arc::clone(&aggregator));
+
+        let recv = |server: &UnixDatagram| {
+            let mut buf = [0; 8192];
+            let len = server.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+
+        let guard = set_local_dispatcher(dispatcher);
+        counter!("some.counter": 1);
+        aggregator.flush(None).unwrap().unwrap();
+        assert_eq!(recv(&server), "some.counter:1|c");
+
+        // simulate an agent restart
+        drop(server);
+        std::fs::remove_file(&path).unwrap();
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        counter!("some.counter": 2);
+        drop(guard);
+        aggregator.flush(None).unwrap().unwrap();
+        assert_eq!(recv(&server), "some.counter:2|c");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sends_length_prefixed_via_unix_stream() {
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let path = socket_path("stream");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let sink = dogstatsd(format!("unixstream://{}", path.display()).as_str())
+            .into_sink()
+            .unwrap();
+        let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), sink));
+        let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+
+        gauge!("some.gauge": 1, "a" => "b");
+        drop(guard);
+        aggregator.flush(None).unwrap().unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut datagram = vec![0; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut datagram).unwrap();
+        assert_eq!(datagram, b"some.gauge:1|g|#a:b");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
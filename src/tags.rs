@@ -23,6 +23,113 @@ pub(crate) fn record_tags(tags: InputTags) -> TagValues {
     Some(collected_tags)
 }
 
+/// A typed set of tags, with keys known at compile time.
+///
+/// Instead of implementing this manually, it can be derived for structs with named fields,
+/// using `#[derive(merni::Tags)]` with the `"derive"` feature enabled.
+/// Each field is a tag named after the field, unless renamed using `#[tag(rename = "...")]`.
+/// Field values have to implement [`ToTagValue`], or be marked with `#[tag(display)]`
+/// to be formatted using their [`Display`] implementation instead.
+///
+/// [`ToTagValue`] can be derived for enums with unit variants using `#[derive(merni::ToTagValue)]`,
+/// which maps each variant to a static string without any formatting.
+/// The variants are named in `snake_case`, unless renamed using `#[tag(rename = "...")]`.
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// #[derive(merni::ToTagValue)]
+/// enum Method {
+///     Get,
+///     Post,
+/// }
+///
+/// #[derive(merni::Tags)]
+/// struct RequestTags {
+///     method: Method,
+///     status: u16,
+/// }
+///
+/// let (method, status) = (Method::Get, 200);
+/// merni::counter!("requests": 1, tags = RequestTags { method, status });
+/// # }
+/// ```
+pub trait Tags {
+    /// The keys of the tags.
+    const KEYS: &'static [&'static str];
+
+    /// Calls the `visitor` with the value of each tag, in the order of [`KEYS`](Self::KEYS).
+    fn visit_values(&self, visitor: &mut dyn FnMut(TagValue<'_>));
+}
+
+/// The value of a tag, as provided by [`Tags`].
+#[derive(Clone, Copy)]
+pub enum TagValue<'a> {
+    /// A string which is used as-is.
+    Str(&'a str),
+    /// A value which is formatted using its [`Display`] implementation.
+    Display(&'a dyn Display),
+}
+
+/// A value which can be used as a field of [`Tags`].
+pub trait ToTagValue {
+    /// Turns the value into a [`TagValue`].
+    fn to_tag_value(&self) -> TagValue<'_>;
+}
+
+impl ToTagValue for str {
+    fn to_tag_value(&self) -> TagValue<'_> {
+        TagValue::Str(self)
+    }
+}
+
+impl ToTagValue for String {
+    fn to_tag_value(&self) -> TagValue<'_> {
+        TagValue::Str(self)
+    }
+}
+
+impl<T: ToTagValue + ?Sized> ToTagValue for &T {
+    fn to_tag_value(&self) -> TagValue<'_> {
+        (**self).to_tag_value()
+    }
+}
+
+macro_rules! display_tag_value {
+    ($($ty:ty),+) => {
+        $(impl ToTagValue for $ty {
+            fn to_tag_value(&self) -> TagValue<'_> {
+                TagValue::Display(self)
+            }
+        })+
+    };
+}
+
+display_tag_value!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+pub(crate) fn record_typed_tags<T: Tags>(tags: &T) -> TagValues {
+    if T::KEYS.is_empty() {
+        return None;
+    }
+
+    let mut string_buf = StringBuf::<128>::default();
+    let mut collected_tags = Vec::with_capacity(T::KEYS.len());
+    tags.visit_values(&mut |value| {
+        let value = match value {
+            TagValue::Str(value) => Str24::new(value),
+            TagValue::Display(value) => {
+                string_buf.clear();
+                write!(&mut string_buf, "{value}").unwrap();
+                Str24::new(string_buf.as_str())
+            }
+        };
+        collected_tags.push(value);
+    });
+    debug_assert_eq!(collected_tags.len(), T::KEYS.len());
+    Some(collected_tags.into_boxed_slice())
+}
+
 #[derive(Default)]
 pub(crate) struct StringBuf<const N: usize> {
     buf: SmallVec<u8, N>,
@@ -1,6 +1,16 @@
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::future::Future;
 use std::sync::{Arc, Mutex};
 
-use crate::{Dispatcher, LocalDispatcherGuard, Metric, Sink, set_local_dispatcher};
+#[cfg(feature = "aggregator")]
+use crate::{Aggregations, AggregatorConfig, ThreadLocalAggregator};
+use crate::{
+    Dispatcher, DispatcherBuilder, LocalDispatcherGuard, Metric, MetricType, Sink, scope,
+    set_local_dispatcher,
+};
+#[cfg(feature = "datadog")]
+use crate::{HttpFuture, HttpRequest, HttpResponse, HttpTransport};
 
 type TestMetrics = Arc<Mutex<Vec<Metric>>>;
 
@@ -27,11 +37,16 @@ pub struct TestDispatcher {
 impl TestDispatcher {
     /// Starts a new test [`Dispatcher`].
     pub fn new() -> Self {
+        Self::with_builder(Dispatcher::builder())
+    }
+
+    /// Starts a new test [`Dispatcher`], configured by the given [`DispatcherBuilder`].
+    pub fn with_builder(builder: DispatcherBuilder) -> Self {
         let metrics: TestMetrics = Default::default();
         let sink = TestSink {
             metrics: metrics.clone(),
         };
-        let dispatcher = Dispatcher::new(sink);
+        let dispatcher = builder.build(sink);
 
         let guard = set_local_dispatcher(dispatcher);
 
@@ -40,6 +55,80 @@ impl TestDispatcher {
             metrics,
         }
     }
+
+    /// Runs the [`Future`] with a test [`Dispatcher`], returning its output and all the
+    /// captured [`Metric`]s.
+    ///
+    /// Contrary to [`new()`](Self::new), the dispatcher is bound to the future using [`scope`]
+    /// instead of the current thread. This makes it safe to use on a multi-threaded runtime,
+    /// where the future might be polled on different threads, and other tests run concurrently.
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "multi_thread")]
+    /// # async fn main() {
+    /// use merni::testing::{AssertMetrics, TestDispatcher};
+    ///
+    /// let ((), metrics) = TestDispatcher::scope(async {
+    ///     merni::counter!("some.counter": 1);
+    ///     tokio::task::yield_now().await;
+    ///     merni::counter!("some.counter": 2);
+    /// })
+    /// .await;
+    /// metrics.assert_counter("some.counter").sum(3.);
+    /// # }
+    /// ```
+    pub async fn scope<F: Future>(future: F) -> (F::Output, Vec<Metric>) {
+        Self::scope_with_builder(Dispatcher::builder(), future).await
+    }
+
+    /// Runs the [`Future`] with a test [`Dispatcher`] configured by the given [`DispatcherBuilder`].
+    ///
+    /// See [`scope()`](Self::scope).
+    pub async fn scope_with_builder<F: Future>(
+        builder: DispatcherBuilder,
+        future: F,
+    ) -> (F::Output, Vec<Metric>) {
+        let metrics: TestMetrics = Default::default();
+        let sink = TestSink {
+            metrics: metrics.clone(),
+        };
+        let output = scope(builder.build(sink), future).await;
+
+        let metrics = Arc::into_inner(metrics)
+            .expect("dispatcher should be dropped")
+            .into_inner()
+            .unwrap();
+        (output, metrics)
+    }
+
+    /// Starts a new test [`Dispatcher`] which aggregates metrics using a [`ThreadLocalAggregator`].
+    ///
+    /// Calling [`finish()`](AggregatedTestDispatcher::finish) returns the [`Aggregations`]
+    /// that would be flushed to an [`AggregationSink`](crate::AggregationSink).
+    #[cfg(feature = "aggregator")]
+    pub fn aggregated() -> AggregatedTestDispatcher {
+        Self::aggregated_with_config(Dispatcher::builder(), AggregatorConfig::default())
+    }
+
+    /// Starts a new aggregating test [`Dispatcher`], configured by the given
+    /// [`DispatcherBuilder`] and [`AggregatorConfig`].
+    ///
+    /// The flush interval of the config is ignored, as metrics are only aggregated on
+    /// [`finish()`](AggregatedTestDispatcher::finish).
+    #[cfg(feature = "aggregator")]
+    pub fn aggregated_with_config(
+        builder: DispatcherBuilder,
+        config: AggregatorConfig,
+    ) -> AggregatedTestDispatcher {
+        let aggregator = Arc::new(ThreadLocalAggregator::without_thread(config));
+        let dispatcher = builder.build(Arc::clone(&aggregator));
+
+        AggregatedTestDispatcher {
+            dispatcher: set_local_dispatcher(dispatcher),
+            aggregator,
+        }
+    }
+
     /// Consumes this guard, returning all the captured [`Metric`]s.
     pub fn finish(self) -> Vec<Metric> {
         drop(self.dispatcher);
@@ -55,3 +144,487 @@ impl Default for TestDispatcher {
         Self::new()
     }
 }
+
+/// A guard for a currently configured test [`Dispatcher`] which aggregates metrics.
+///
+/// See [`TestDispatcher::aggregated`].
+#[cfg(feature = "aggregator")]
+pub struct AggregatedTestDispatcher {
+    dispatcher: LocalDispatcherGuard,
+    aggregator: Arc<ThreadLocalAggregator<()>>,
+}
+
+#[cfg(feature = "aggregator")]
+impl AggregatedTestDispatcher {
+    /// Consumes this guard, returning the [`Aggregations`] of all the emitted metrics.
+    ///
+    /// Only metrics emitted on the current thread, or by metric handles, are being aggregated.
+    pub fn finish(self) -> Aggregations {
+        drop(self.dispatcher);
+        self.aggregator.drain()
+    }
+}
+
+/// Assertions on emitted or aggregated metrics.
+///
+/// ```
+/// use merni::testing::{AssertMetrics, TestDispatcher};
+///
+/// let dispatcher = TestDispatcher::new();
+/// merni::counter!("requests": 1, "route" => "/");
+/// merni::counter!("requests": 2, "route" => "/");
+///
+/// let metrics = dispatcher.finish();
+/// metrics
+///     .assert_counter("requests")
+///     .with_tag("route", "/")
+///     .count(2)
+///     .sum(3.);
+/// ```
+pub trait AssertMetrics {
+    /// Starts an assertion on all the metrics of the given type and key.
+    fn assert_metric(&self, ty: MetricType, key: &str) -> MetricAssertion;
+
+    /// Starts an assertion on a counter metric.
+    fn assert_counter(&self, key: &str) -> MetricAssertion {
+        self.assert_metric(MetricType::Counter, key)
+    }
+
+    /// Starts an assertion on a gauge metric.
+    fn assert_gauge(&self, key: &str) -> MetricAssertion {
+        self.assert_metric(MetricType::Gauge, key)
+    }
+
+    /// Starts an assertion on a distribution metric.
+    fn assert_distribution(&self, key: &str) -> MetricAssertion {
+        self.assert_metric(MetricType::Distribution, key)
+    }
+
+    /// Starts an assertion on a timer metric.
+    fn assert_timer(&self, key: &str) -> MetricAssertion {
+        self.assert_metric(MetricType::Timer, key)
+    }
+
+    /// Starts an assertion on a set metric.
+    fn assert_set(&self, key: &str) -> MetricAssertion {
+        self.assert_metric(MetricType::Set, key)
+    }
+}
+
+impl AssertMetrics for [Metric] {
+    fn assert_metric(&self, ty: MetricType, key: &str) -> MetricAssertion {
+        let mut all_series: Vec<Series> = vec![];
+        for metric in self {
+            let tags: Vec<_> = metric
+                .tags()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            let series = match all_series
+                .iter_mut()
+                .position(|s| s.ty == metric.ty() && s.key == metric.key() && s.tags == tags)
+            {
+                Some(idx) => &mut all_series[idx],
+                None => {
+                    all_series.push(Series::new(metric.ty(), metric.key(), tags));
+                    all_series.last_mut().unwrap()
+                }
+            };
+            let value = metric.value().get();
+            series.add(value, 1);
+            series.distinct.insert(value.to_bits());
+            series.cardinality = series.distinct.len() as u64;
+        }
+        MetricAssertion::new(ty, key, all_series)
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl AssertMetrics for Aggregations {
+    fn assert_metric(&self, ty: MetricType, key: &str) -> MetricAssertion {
+        let mut all_series = vec![];
+        let new_series = |metric: &crate::AggregatedMetric| {
+            let tags = metric
+                .tags()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            Series::new(metric.ty(), metric.key(), tags)
+        };
+
+        for (metric, value) in &self.counters {
+            // aggregated counters only keep their sum, so the number of values is unknown
+            let mut series = new_series(metric);
+            series.add(*value, 1);
+            series.count = None;
+            all_series.push(series);
+        }
+        for (metric, gauge) in &self.gauges {
+            let mut series = new_series(metric);
+            series.count = Some(gauge.count);
+            series.sum = gauge.sum;
+            series.min = gauge.min;
+            series.max = gauge.max;
+            series.last = gauge.last;
+            all_series.push(series);
+        }
+        for (metric, distribution) in &self.distributions {
+            let mut series = new_series(metric);
+            for (value, weight) in distribution.weighted_values() {
+                series.add(value, weight);
+            }
+            all_series.push(series);
+        }
+        for (metric, sketch) in &self.sketches {
+            let mut series = new_series(metric);
+            series.count = Some(sketch.count());
+            series.sum = sketch.sum();
+            series.min = sketch.min();
+            series.max = sketch.max();
+            all_series.push(series);
+        }
+        for (metric, set) in &self.sets {
+            let mut series = new_series(metric);
+            series.cardinality = set.cardinality();
+            all_series.push(series);
+        }
+        all_series.sort_by(|a, b| (&a.key, &a.tags).cmp(&(&b.key, &b.tags)));
+
+        MetricAssertion::new(ty, key, all_series)
+    }
+}
+
+/// The summarized values of a single metric series.
+struct Series {
+    ty: MetricType,
+    key: String,
+    tags: Vec<(String, String)>,
+
+    /// The number of recorded values, if known.
+    count: Option<u64>,
+    sum: f64,
+    min: f64,
+    max: f64,
+    last: f64,
+    cardinality: u64,
+    distinct: HashSet<u64>,
+}
+
+impl Series {
+    fn new(ty: MetricType, key: &str, tags: Vec<(String, String)>) -> Self {
+        Self {
+            ty,
+            key: key.to_owned(),
+            tags,
+
+            count: Some(0),
+            sum: 0.,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            last: f64::NAN,
+            cardinality: 0,
+            distinct: HashSet::new(),
+        }
+    }
+
+    fn add(&mut self, value: f64, weight: u64) {
+        self.count = self.count.map(|count| count + weight);
+        self.sum += value * weight as f64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+}
+
+impl fmt::Display for Series {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} `{}`", self.ty, self.key)?;
+        if !self.tags.is_empty() {
+            f.write_char('{')?;
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{key}={value}")?;
+            }
+            f.write_char('}')?;
+        }
+        match self.count {
+            Some(count) => write!(f, ": count={count}")?,
+            None => f.write_str(": count=?")?,
+        }
+        write!(
+            f,
+            " sum={} min={} max={} last={}",
+            self.sum, self.min, self.max, self.last
+        )?;
+        if self.ty == MetricType::Set {
+            write!(f, " cardinality={}", self.cardinality)?;
+        }
+        Ok(())
+    }
+}
+
+/// An assertion on the metrics of a specific type and key, created by [`AssertMetrics`].
+///
+/// The assertion can be narrowed down to metrics [`with_tag`](Self::with_tag)s,
+/// and each value assertion panics with a summary of all the related metrics if it fails.
+/// Values are summarized across all the matching metrics.
+pub struct MetricAssertion {
+    ty: MetricType,
+    key: String,
+    tags: Vec<(String, String)>,
+    all_series: Vec<Series>,
+}
+
+impl MetricAssertion {
+    fn new(ty: MetricType, key: &str, all_series: Vec<Series>) -> Self {
+        Self {
+            ty,
+            key: key.to_owned(),
+            tags: vec![],
+            all_series,
+        }
+    }
+
+    /// Only considers metrics which have the given tag.
+    pub fn with_tag(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.tags.push((key.to_owned(), value.to_string()));
+        self
+    }
+
+    /// Asserts that at least one matching metric exists.
+    #[track_caller]
+    pub fn exists(self) -> Self {
+        let _ = self.matching();
+        self
+    }
+
+    /// Asserts the total number of recorded values.
+    ///
+    /// # Panics
+    ///
+    /// Aggregated counters only keep the sum of their values,
+    /// so asserting their count panics. Use [`sum`](Self::sum) instead.
+    #[track_caller]
+    pub fn count(self, expected: u64) -> Self {
+        let found: Option<u64> = self.matching().map(|s| s.count).sum();
+        let Some(found) = found else {
+            panic!(
+                "{}",
+                self.failure("the number of values of aggregated counters is unknown")
+            );
+        };
+        self.check("count", expected as f64, found as f64)
+    }
+
+    /// Asserts the sum of all recorded values.
+    #[track_caller]
+    pub fn sum(self, expected: f64) -> Self {
+        let found = self.matching().map(|s| s.sum).sum();
+        self.check("sum", expected, found)
+    }
+
+    /// Asserts the minimum recorded value.
+    #[track_caller]
+    pub fn min(self, expected: f64) -> Self {
+        let found = self.matching().map(|s| s.min).fold(f64::INFINITY, f64::min);
+        self.check("min", expected, found)
+    }
+
+    /// Asserts the maximum recorded value.
+    #[track_caller]
+    pub fn max(self, expected: f64) -> Self {
+        let found = self
+            .matching()
+            .map(|s| s.max)
+            .fold(f64::NEG_INFINITY, f64::max);
+        self.check("max", expected, found)
+    }
+
+    /// Asserts the latest recorded value.
+    ///
+    /// This is only meaningful if a single series matches.
+    #[track_caller]
+    pub fn last(self, expected: f64) -> Self {
+        let found = self.matching().last().map(|s| s.last).unwrap_or(f64::NAN);
+        self.check("last", expected, found)
+    }
+
+    /// Asserts the number of unique values of a set.
+    #[track_caller]
+    pub fn cardinality(self, expected: u64) -> Self {
+        let found: u64 = self.matching().map(|s| s.cardinality).sum();
+        self.check("cardinality", expected as f64, found as f64)
+    }
+
+    fn matches(&self, series: &Series) -> bool {
+        series.ty == self.ty
+            && series.key == self.key
+            && self.tags.iter().all(|tag| series.tags.contains(tag))
+    }
+
+    #[track_caller]
+    fn matching(&self) -> impl Iterator<Item = &Series> {
+        if !self.all_series.iter().any(|s| self.matches(s)) {
+            panic!("{}", self.failure("no matching metric found"));
+        }
+        self.all_series.iter().filter(|s| self.matches(s))
+    }
+
+    #[track_caller]
+    fn check(self, what: &str, expected: f64, found: f64) -> Self {
+        let tolerance = f64::EPSILON * 16. * expected.abs().max(1.);
+        if !((expected - found).abs() <= tolerance || expected.to_bits() == found.to_bits()) {
+            let message = format!("expected {what} {expected}, found {found}");
+            panic!("{}", self.failure(&message));
+        }
+        self
+    }
+
+    fn failure(&self, message: &str) -> String {
+        let mut failure = format!("assertion failed for {:?} `{}`", self.ty, self.key);
+        for (key, value) in &self.tags {
+            write!(&mut failure, " with {key}={value}").unwrap();
+        }
+        write!(&mut failure, ": {message}").unwrap();
+
+        let (matching, other): (Vec<_>, Vec<_>) =
+            self.all_series.iter().partition(|s| self.matches(s));
+        let mut list = |title: &str, series: &[&Series]| {
+            if !series.is_empty() {
+                write!(&mut failure, "\n{title}:").unwrap();
+                for series in series {
+                    write!(&mut failure, "\n    {series}").unwrap();
+                }
+            }
+        };
+        list("matching metrics", &matching);
+        let related: Vec<_> = other
+            .iter()
+            .copied()
+            .filter(|s| s.key == self.key)
+            .collect();
+        if related.is_empty() {
+            list("other metrics", &other);
+        } else {
+            list("other metrics with the same key", &related);
+        }
+        failure
+    }
+}
+
+/// An [`HttpTransport`] which captures all the requests, instead of sending them.
+///
+/// The captured request bodies are decompressed, so the payloads of a sink can be compared
+/// against snapshots. The transport is cheaply cloneable, and all clones share the captured requests.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use merni::AggregationSink;
+/// use merni::testing::{CapturingTransport, TestDispatcher};
+///
+/// let transport = CapturingTransport::new();
+/// let mut sink = merni::datadog("api-key")
+///     .transport(transport.clone())
+///     .into_sink()
+///     .unwrap();
+///
+/// let dispatcher = TestDispatcher::aggregated();
+/// merni::counter!("some.counter": 1);
+/// for task in sink.emit(dispatcher.finish()).unwrap() {
+///     task.await.unwrap();
+/// }
+///
+/// let requests = transport.take();
+/// assert_eq!(requests[0].path(), "/api/v2/series");
+/// assert!(requests[0].text().contains(r#""metric":"some.counter""#));
+/// # }
+/// ```
+#[cfg(feature = "datadog")]
+#[derive(Debug, Clone)]
+pub struct CapturingTransport {
+    status: u16,
+    requests: Arc<Mutex<Vec<CapturedRequest>>>,
+}
+
+#[cfg(feature = "datadog")]
+impl CapturingTransport {
+    /// Creates a transport which responds to all requests with `202 Accepted`.
+    pub fn new() -> Self {
+        Self::with_status(202)
+    }
+
+    /// Creates a transport which responds to all requests with the given status code.
+    pub fn with_status(status: u16) -> Self {
+        Self {
+            status,
+            requests: Default::default(),
+        }
+    }
+
+    /// Returns a copy of all the requests captured so far.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Takes all the requests captured so far.
+    pub fn take(&self) -> Vec<CapturedRequest> {
+        std::mem::take(&mut self.requests.lock().unwrap())
+    }
+}
+
+#[cfg(feature = "datadog")]
+impl Default for CapturingTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "datadog")]
+impl HttpTransport for CapturingTransport {
+    fn send(&self, request: HttpRequest) -> HttpFuture<'_> {
+        Box::pin(async move {
+            let body = match request.header("Content-Encoding") {
+                Some("zstd") => zstd::decode_all(request.body.as_slice())?,
+                _ => request.body.clone(),
+            };
+            self.requests.lock().unwrap().push(CapturedRequest {
+                url: request.url.clone(),
+                content_type: request.header("Content-Type").unwrap_or_default().into(),
+                body,
+            });
+            Ok(HttpResponse::new(self.status))
+        })
+    }
+}
+
+/// A request captured by the [`CapturingTransport`].
+#[cfg(feature = "datadog")]
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    /// The full URL of the request.
+    pub url: String,
+    /// The `Content-Type` of the body.
+    pub content_type: String,
+    /// The decompressed body of the request.
+    pub body: Vec<u8>,
+}
+
+#[cfg(feature = "datadog")]
+impl CapturedRequest {
+    /// The path of the request URL, which identifies the API endpoint.
+    pub fn path(&self) -> &str {
+        let without_scheme = self
+            .url
+            .split_once("://")
+            .map_or(self.url.as_str(), |(_, rest)| rest);
+        without_scheme
+            .find('/')
+            .map_or("/", |idx| &without_scheme[idx..])
+    }
+
+    /// The body of the request as text, replacing any invalid UTF-8.
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+}
@@ -86,6 +86,619 @@ fn test_manual_emit() {
     );
 }
 
+#[test]
+fn test_timer_macros() {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    let dispatcher = TestDispatcher::new();
+
+    timer!("some.timer": Duration::from_millis(5));
+    timer!("some.timer"@s: Duration::from_millis(5), "tag" => "value");
+
+    let table = String::from("users");
+    let guard = time!("guarded.timer", "table" => table);
+    std::thread::sleep(Duration::from_millis(1));
+    drop(guard);
+    time!("cancelled.timer").cancel();
+
+    let mut cx = Context::from_waker(Waker::noop());
+    let future = pin!(time!("future.timer").timed(async { 1 }));
+    assert_eq!(future.poll(&mut cx), Poll::Ready(1));
+    drop(time!("future.timer").timed(async { 1 }));
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 4);
+
+    assert_eq!(metrics[0].ty(), MetricType::Timer);
+    assert_eq!(metrics[0].key(), "some.timer");
+    assert_eq!(metrics[0].value().get(), 5.);
+
+    assert_eq!(metrics[1].unit(), MetricUnit::Seconds);
+    assert_eq!(metrics[1].value().get(), 0.005);
+    assert_eq!(metrics[1].tags().collect::<Vec<_>>(), &[("tag", "value")]);
+
+    assert_eq!(metrics[2].ty(), MetricType::Timer);
+    assert_eq!(metrics[2].key(), "guarded.timer");
+    assert!(metrics[2].value().get() >= 1.);
+    assert_eq!(metrics[2].tags().collect::<Vec<_>>(), &[("table", "users")]);
+
+    assert_eq!(metrics[3].key(), "future.timer");
+}
+
+#[test]
+fn test_metric_units() {
+    use std::time::Duration;
+
+    let dispatcher = TestDispatcher::new();
+
+    let duration = Duration::from_micros(1500);
+    distribution!("unknown": duration);
+    distribution!("ns"@ns: duration);
+    distribution!("us"@us: duration);
+    distribution!("ms"@ms: duration);
+    distribution!("s"@s: duration);
+    timer!("timer": duration);
+    gauge!("custom"@"requests": 1);
+    gauge!("ratio"@ratio: 0.5);
+
+    let metrics = dispatcher.finish();
+    let values: Vec<_> = metrics.iter().map(|m| m.value().get()).collect();
+    assert_eq!(
+        values,
+        &[0.0015, 1_500_000., 1_500., 1.5, 0.0015, 1.5, 1., 0.5]
+    );
+
+    assert_eq!(metrics[0].unit(), MetricUnit::Unknown);
+    assert_eq!(metrics[1].unit(), MetricUnit::Nanoseconds);
+    assert_eq!(metrics[5].unit(), MetricUnit::Unknown);
+    assert_eq!(metrics[5].value_unit(), MetricUnit::Milliseconds);
+    assert_eq!(metrics[6].unit(), MetricUnit::Custom("requests"));
+    assert_eq!(metrics[6].unit().as_str(), "requests");
+    assert_eq!(metrics[7].unit(), MetricUnit::Ratio);
+}
+
+#[test]
+fn test_set_macro() {
+    let dispatcher = TestDispatcher::new();
+
+    let user = String::from("some-user");
+    set!("unique.users": user, "tenant" => "a");
+    set!("unique.users": "some-user");
+    set!("unique.users": 1234);
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(metrics[0].ty(), MetricType::Set);
+    assert_eq!(metrics[0].tags().collect::<Vec<_>>(), &[("tenant", "a")]);
+    // values are stable hashes of the input
+    assert_eq!(metrics[0].value().get(), metrics[1].value().get());
+    assert_ne!(metrics[0].value().get(), metrics[2].value().get());
+    assert!(metrics[0].value().get() < (1u64 << 53) as f64);
+}
+
+#[test]
+fn test_sampled_metrics() {
+    let dispatcher = TestDispatcher::new();
+
+    for _ in 0..1000 {
+        counter!("sampled.counter": 1, @rate = 0.1);
+        distribution!("sampled.distribution": 1, "tag" => "value", @rate = 0.1,);
+        counter!("unsampled.counter": 1, @rate = 1.);
+    }
+
+    let metrics = dispatcher.finish();
+    let sampled = |key| metrics.iter().filter(move |metric| metric.key() == key);
+    assert!(sampled("sampled.counter").all(|metric| metric.sample_rate() == 0.1));
+    assert!((50..150).contains(&sampled("sampled.counter").count()));
+    assert!((50..150).contains(&sampled("sampled.distribution").count()));
+    assert_eq!(sampled("unsampled.counter").count(), 1000);
+    assert_eq!(metrics.last().unwrap().sample_rate(), 1.);
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_sampled_aggregation() {
+    use std::sync::Arc;
+
+    let aggregations = Default::default();
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Default::default(),
+        thread: Default::default(),
+    };
+    let dispatcher = Dispatcher::new(sink);
+
+    let guard = set_local_dispatcher(dispatcher);
+    with_dispatcher(|dispatcher| {
+        static COUNTER: MetricMeta =
+            MetricMeta::new(MetricType::Counter, MetricUnit::Unknown, "sampled.counter");
+        static DISTRIBUTION: MetricMeta = MetricMeta::new(
+            MetricType::Distribution,
+            MetricUnit::Unknown,
+            "sampled.distribution",
+        );
+        dispatcher.emit_sampled(&COUNTER, 1, 0.25);
+        dispatcher.emit(&COUNTER, 1);
+        dispatcher.emit(&DISTRIBUTION, 1);
+        dispatcher.emit_sampled(&DISTRIBUTION, 2, 0.25);
+
+        // rates are rounded to whole weights, and rates out of range are clamped or dropped
+        dispatcher.emit_sampled(&COUNTER, 1, 0.3);
+        dispatcher.emit_sampled(&COUNTER, 1, 2.);
+        dispatcher.emit_sampled(&COUNTER, 1, 0.);
+        dispatcher.emit_sampled(&COUNTER, 1, f64::NAN);
+        dispatcher.emit_sampled(&DISTRIBUTION, 3, 1e-300);
+    });
+    drop(guard);
+
+    let mut total_aggregation = Aggregations::default();
+    for aggregation in aggregations.iter() {
+        let mut aggregation = aggregation.swap();
+        total_aggregation.merge_aggregations(&mut aggregation);
+    }
+
+    let counter = total_aggregation.counters.into_values().next().unwrap();
+    assert_eq!(counter, 9.);
+    let distribution = total_aggregation
+        .distributions
+        .into_values()
+        .next()
+        .unwrap();
+    assert_eq!(distribution.count(), 5 + u32::MAX as u64);
+    assert_eq!(
+        distribution.weighted_values().collect::<Vec<_>>(),
+        &[(1., 1), (2., 4), (3., u32::MAX as u64)]
+    );
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_set_aggregation() {
+    use std::sync::Arc;
+
+    let aggregations = Default::default();
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Default::default(),
+        thread: Default::default(),
+    };
+    let dispatcher = Dispatcher::new(sink);
+
+    let guard = set_local_dispatcher(dispatcher);
+    for i in 0..100 {
+        set!("unique.values": i % 10);
+    }
+    drop(guard);
+
+    let mut total_aggregation = Aggregations::default();
+    for aggregation in aggregations.iter() {
+        let mut aggregation = aggregation.swap();
+        total_aggregation.merge_aggregations(&mut aggregation);
+    }
+
+    assert_eq!(total_aggregation.sets.len(), 1);
+    let set = total_aggregation.sets.into_values().next().unwrap();
+    assert_eq!(set.cardinality(), 10);
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_cardinality_limits() {
+    use std::sync::Arc;
+
+    use crate::aggregator::CardinalityLimiter;
+
+    let config = AggregatorConfig::default()
+        .max_series_per_metric(3)
+        .metric_max_series("collapsed.counter", 2)
+        .overflow(CardinalityOverflow::Collapse);
+    let aggregations = Default::default();
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Arc::new(config),
+        thread: Default::default(),
+    };
+    let config = Arc::clone(&sink.config);
+    let dispatcher = Dispatcher::new(sink);
+
+    let guard = set_local_dispatcher(dispatcher);
+    for i in 0..10 {
+        counter!("limited.counter": 1, "request_id" => i % 5);
+        counter!("collapsed.counter": 1, "request_id" => i % 5);
+    }
+    drop(guard);
+
+    let mut total_aggregation = Aggregations::default();
+    let mut limiter = CardinalityLimiter::new(&config);
+    for aggregation in aggregations.iter() {
+        let mut aggregation = aggregation.swap();
+        total_aggregation.merge_limited(&mut aggregation, &mut limiter);
+    }
+    limiter.record_dropped(&mut total_aggregation);
+
+    let series = |key: &str| {
+        let mut series: Vec<_> = total_aggregation
+            .counters
+            .iter()
+            .filter(|(metric, _)| metric.key() == key)
+            .map(|(metric, value)| (metric.tags().next().unwrap().1.to_owned(), *value))
+            .collect();
+        series.sort_by(|a, b| a.0.cmp(&b.0));
+        series
+    };
+
+    // which series are kept depends on the iteration order of the aggregations
+    let limited = series("limited.counter");
+    assert_eq!(limited.len(), 4);
+    assert!(limited[..3].iter().all(|(_, value)| *value == 2.));
+    assert_eq!(limited[3], (OVERFLOW_TAG_VALUE.to_owned(), 4.));
+
+    let collapsed = series("collapsed.counter");
+    assert_eq!(collapsed.len(), 3);
+    assert_eq!(collapsed[2], (OVERFLOW_TAG_VALUE.to_owned(), 6.));
+
+    let dropped = series("merni.aggregator.dropped_series");
+    assert_eq!(
+        dropped,
+        &[
+            ("collapsed.counter".to_owned(), 3.),
+            ("limited.counter".to_owned(), 2.)
+        ]
+    );
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_cardinality_limits_across_threads() {
+    use std::sync::Arc;
+
+    let config = AggregatorConfig::default().max_series_per_metric(1);
+    let sink = Arc::new(ThreadLocalAggregator::<()>::without_thread(config));
+
+    for _ in 0..2 {
+        let sink = Arc::clone(&sink);
+        std::thread::spawn(move || {
+            let _guard = set_local_dispatcher(Dispatcher::new(sink));
+            counter!("limited.counter": 1, "tag" => "a");
+            counter!("limited.counter": 1, "tag" => "b");
+            counter!("limited.counter": 1, "tag" => "b");
+        })
+        .join()
+        .unwrap();
+    }
+
+    // the limits are already enforced on each thread
+    for aggregations in sink.aggregations.iter() {
+        assert_eq!(aggregations.swap().counters.len(), 1);
+    }
+
+    let aggregations = sink.drain();
+    let counter = |key: &str| {
+        let mut series = aggregations.counters.iter().filter(|(m, _)| m.key() == key);
+        let (_, value) = series.next().unwrap();
+        assert!(series.next().is_none());
+        *value
+    };
+    assert_eq!(counter("limited.counter"), 2.);
+    // the same series dropped on both threads is counted once
+    assert_eq!(counter("merni.aggregator.dropped_series"), 1.);
+}
+
+#[test]
+fn test_emit_dynamic() {
+    let dispatcher = TestDispatcher::new();
+
+    let name = String::from("dynamic.counter");
+    with_dispatcher(|dispatcher| {
+        for value in ["a", "b"] {
+            let tags = [("tag", value)];
+            assert!(dispatcher.emit_dynamic(
+                MetricType::Counter,
+                MetricUnit::Unknown,
+                &name,
+                &tags,
+                1
+            ));
+        }
+    });
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(metrics[0].ty(), MetricType::Counter);
+    assert_eq!(metrics[0].key(), "dynamic.counter");
+    assert_eq!(metrics[0].value().get(), 1.);
+    assert_eq!(metrics[0].tags().collect::<Vec<_>>(), &[("tag", "a")]);
+    assert_eq!(metrics[1].tags().collect::<Vec<_>>(), &[("tag", "b")]);
+    // both metrics share the same interned metadata
+    assert!(std::ptr::eq(metrics[0].key.meta, metrics[1].key.meta));
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn test_typed_tags_in_generic_fn() {
+    #[derive(Tags)]
+    struct A {
+        a: u8,
+    }
+    #[derive(Tags)]
+    struct B {
+        b: u8,
+    }
+
+    // the metric of the call site is shared by all instantiations
+    fn emit<T: Tags>(tags: T) {
+        counter!("generic.counter": 1, tags = tags);
+    }
+
+    let dispatcher = TestDispatcher::new();
+    emit(A { a: 1 });
+    emit(B { b: 2 });
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics[0].tags().collect::<Vec<_>>(), &[("a", "1")]);
+    assert_eq!(metrics[1].tags().collect::<Vec<_>>(), &[("b", "2")]);
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn test_typed_tags() {
+    #[derive(ToTagValue)]
+    enum Method {
+        Get,
+        #[tag(rename = "POST")]
+        Post,
+        NotFound,
+    }
+
+    struct Status(u16);
+    impl std::fmt::Display for Status {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}xx", self.0 / 100)
+        }
+    }
+
+    #[derive(Tags)]
+    struct RequestTags<'a> {
+        method: Method,
+        #[tag(display)]
+        status: Status,
+        #[tag(rename = "route")]
+        path: &'a str,
+        retry: bool,
+    }
+
+    let dispatcher = TestDispatcher::new();
+
+    for (method, status) in [
+        (Method::Get, 200),
+        (Method::Post, 503),
+        (Method::NotFound, 404),
+    ] {
+        let status = Status(status);
+        counter!("requests": 1, tags = RequestTags { method, status, path: "/", retry: false });
+    }
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(metrics[0].key(), "requests");
+    assert_eq!(
+        metrics[0].tags().collect::<Vec<_>>(),
+        &[
+            ("method", "get"),
+            ("status", "2xx"),
+            ("route", "/"),
+            ("retry", "false")
+        ]
+    );
+    assert_eq!(
+        metrics[1].tags().collect::<Vec<_>>(),
+        &[
+            ("method", "POST"),
+            ("status", "5xx"),
+            ("route", "/"),
+            ("retry", "false")
+        ]
+    );
+    assert_eq!(
+        metrics[2].tags().collect::<Vec<_>>(),
+        &[
+            ("method", "not_found"),
+            ("status", "4xx"),
+            ("route", "/"),
+            ("retry", "false")
+        ]
+    );
+    // all metrics of the call site share the same metadata
+    assert!(std::ptr::eq(metrics[0].key.meta, metrics[2].key.meta));
+}
+
+#[test]
+fn test_dispatcher_builder() {
+    let builder = Dispatcher::builder()
+        .prefix("myservice.")
+        .global_tag("env", "test")
+        .global_tag_with("host", || "some-host".into());
+    let dispatcher = TestDispatcher::with_builder(builder);
+
+    for i in 0..2 {
+        counter!("some.counter": i);
+        gauge!("some.gauge": i, "tag" => "value");
+    }
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 4);
+
+    assert_eq!(metrics[0].key(), "myservice.some.counter");
+    assert_eq!(
+        metrics[0].tags().collect::<Vec<_>>(),
+        &[("env", "test"), ("host", "some-host")]
+    );
+    assert_eq!(metrics[1].key(), "myservice.some.gauge");
+    assert_eq!(metrics[1].value().get(), 0.);
+    assert_eq!(
+        metrics[1].tags().collect::<Vec<_>>(),
+        &[("env", "test"), ("host", "some-host"), ("tag", "value")]
+    );
+    // the derived metric metadata is being reused
+    assert!(std::ptr::eq(metrics[0].key.meta, metrics[2].key.meta));
+
+    // and shared with other dispatchers with the same prefix and global tag keys
+    let builder = Dispatcher::builder()
+        .prefix("myservice.")
+        .global_tag("env", "prod")
+        .global_tag("host", "other-host");
+    let dispatcher = TestDispatcher::with_builder(builder);
+    counter!("some.counter": 1);
+    let other = dispatcher.finish();
+    assert_eq!(other[0].tags().next(), Some(("env", "prod")));
+    assert!(std::ptr::eq(metrics[0].key.meta, other[0].key.meta));
+}
+
+#[test]
+fn test_scoped_tags() {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    let dispatcher = TestDispatcher::new();
+
+    let tags = with_tags([("tenant", "a"), ("region", "eu")], || {
+        counter!("scoped.counter": 1);
+        with_tags([("tenant", "b")], || {
+            counter!("scoped.counter": 2, "region" => "us");
+        });
+        // the scoped metric is cached on the outer scope
+        counter!("scoped.counter": 1);
+        ScopedTags::current()
+    });
+
+    // the future is polled outside of the original scope
+    let future = pin!(tags.instrument(async {
+        counter!("scoped.counter": 3);
+    }));
+    let mut cx = Context::from_waker(Waker::noop());
+    assert_eq!(future.poll(&mut cx), Poll::Ready(()));
+    counter!("scoped.counter": 4);
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 5);
+
+    assert_eq!(
+        metrics[0].tags().collect::<Vec<_>>(),
+        &[("tenant", "a"), ("region", "eu")]
+    );
+    assert_eq!(
+        metrics[1].tags().collect::<Vec<_>>(),
+        &[("tenant", "b"), ("region", "us")]
+    );
+    assert_eq!(
+        metrics[2].tags().collect::<Vec<_>>(),
+        &[("tenant", "a"), ("region", "eu")]
+    );
+    assert!(std::ptr::eq(metrics[0].key.meta, metrics[2].key.meta));
+    assert_eq!(
+        metrics[3].tags().collect::<Vec<_>>(),
+        &[("tenant", "a"), ("region", "eu")]
+    );
+    assert_eq!(metrics[4].tags().count(), 0);
+}
+
+#[test]
+fn test_sink_combinators() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Metric>>);
+    impl Sink for Recorder {
+        fn emit(&self, metric: Metric) {
+            self.0.lock().unwrap().push(metric)
+        }
+    }
+
+    let all = Arc::new(Recorder::default());
+    let counters = Arc::new(Recorder::default());
+    let sink = Tee(
+        Map::new(Arc::clone(&all), |metric: Metric| {
+            metric.with_key("renamed").with_tag("env", "test")
+        }),
+        Filter::new(
+            Sample::new(Arc::clone(&counters), 0.5),
+            |meta: &MetricMeta| meta.ty() == MetricType::Counter,
+        ),
+    );
+    let guard = set_local_dispatcher(Dispatcher::new(sink));
+    for _ in 0..1000 {
+        counter!("some.counter": 1, "env" => "prod");
+        gauge!("some.gauge": 1);
+    }
+    drop(guard);
+
+    let all = all.0.lock().unwrap();
+    assert_eq!(all.len(), 2000);
+    assert_eq!(all[0].key(), "renamed");
+    assert_eq!(all[0].tags().collect::<Vec<_>>(), &[("env", "test")]);
+    assert_eq!(all[1].tags().collect::<Vec<_>>(), &[("env", "test")]);
+
+    let counters = counters.0.lock().unwrap();
+    assert!(counters.len() > 300 && counters.len() < 700);
+    assert!(
+        counters
+            .iter()
+            .all(|metric| metric.ty() == MetricType::Counter)
+    );
+    assert!(counters.iter().all(|metric| metric.value().get() == 2.));
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_aggregation_sink_combinators() {
+    struct Collect;
+    impl AggregationSink for Collect {
+        type Output = Aggregations;
+        fn emit(&mut self, metrics: Aggregations) -> Aggregations {
+            metrics
+        }
+    }
+
+    static COUNTER: MetricMeta =
+        MetricMeta::new(MetricType::Counter, MetricUnit::Unknown, "some.counter");
+    static OTHER_COUNTER: MetricMeta =
+        MetricMeta::new(MetricType::Counter, MetricUnit::Unknown, "other.counter");
+    static GAUGE: MetricMeta =
+        MetricMeta::new(MetricType::Gauge, MetricUnit::Unknown, "some.gauge");
+    let key = |meta: &MetricMeta| AggregatedMetric {
+        meta: *meta,
+        tag_values: None,
+    };
+
+    let mut aggregations = Aggregations::default();
+    aggregations.counters.insert(key(&COUNTER), 1.);
+    aggregations.counters.insert(key(&OTHER_COUNTER), 2.);
+    aggregations.gauges.insert(key(&GAUGE), Default::default());
+
+    let mut sink = Tee(
+        Map::new(Collect, |metric: AggregatedMetric| {
+            metric.with_key("merged")
+        }),
+        Filter::new(Sample::new(Collect, 1.), |meta: &MetricMeta| {
+            meta.key().starts_with("some.c")
+        }),
+    );
+    let (mapped, filtered) = sink.emit(aggregations);
+
+    assert_eq!(mapped.counters.len(), 1);
+    let (key, value) = mapped.counters.into_iter().next().unwrap();
+    assert_eq!(key.key(), "merged");
+    assert_eq!(value, 3.);
+    assert_eq!(mapped.gauges.len(), 1);
+
+    assert_eq!(filtered.counters.len(), 1);
+    assert!(filtered.gauges.is_empty());
+}
+
 #[cfg(feature = "aggregator")]
 #[test]
 fn test_aggregation() {
@@ -94,7 +707,8 @@ fn test_aggregation() {
     let aggregations = Default::default();
     let sink = ThreadLocalAggregator::<()> {
         aggregations: Arc::clone(&aggregations),
-        thread: None,
+        config: Default::default(),
+        thread: Default::default(),
     };
     let dispatcher = Dispatcher::new(sink);
 
@@ -109,7 +723,7 @@ fn test_aggregation() {
 
     let mut total_aggregation = Aggregations::default();
     for aggregation in aggregations.iter() {
-        let mut aggregation = aggregation.lock().unwrap();
+        let mut aggregation = aggregation.swap();
         assert_eq!(aggregation.gauges.len(), 4); // implementation detail of `LocalKey`
         total_aggregation.merge_aggregations(&mut aggregation);
     }
@@ -121,4 +735,477 @@ fn test_aggregation() {
     assert_eq!(gauge.min, 1.);
     assert_eq!(gauge.max, 4.);
     assert_eq!(gauge.sum, 10.);
+    // the separate call sites are merged in arbitrary order, but keep the latest value
+    assert_eq!(gauge.last, 4.);
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_double_buffered_aggregation() {
+    use std::sync::Arc;
+
+    let aggregations = Default::default();
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Default::default(),
+        thread: Default::default(),
+    };
+    let dispatcher = Dispatcher::new(sink);
+
+    let guard = set_local_dispatcher(dispatcher);
+    counter!("some.counter": 1);
+    let local = aggregations.iter().next().unwrap();
+    {
+        // emitting does not block while the flusher drains the previous buffer
+        let mut previous = local.swap();
+        counter!("some.counter": 2);
+        assert_eq!(previous.counters.drain().count(), 1);
+    }
+    // values written into a locked buffer are picked up by the next flush
+    let mut current = local.swap();
+    let values: Vec<_> = current.counters.drain().map(|(_, value)| value).collect();
+    assert_eq!(values, &[2.]);
+    drop(current);
+    drop(guard);
+}
+
+#[test]
+fn test_metric_handles() {
+    let dispatcher = TestDispatcher::new();
+
+    let counter = Counter::register("handle.counter", [("tag", "value")]);
+    counter.increment(1);
+    counter.clone().increment(2);
+    Gauge::register("handle.gauge", [("a", 1), ("b", 2)]).set(3);
+
+    let metrics = dispatcher.finish();
+    assert_eq!(metrics.len(), 3);
+
+    assert_eq!(metrics[1].key(), "handle.counter");
+    assert_eq!(metrics[1].value().get(), 2.);
+    assert_eq!(metrics[1].tags().collect::<Vec<_>>(), &[("tag", "value")]);
+    assert_eq!(metrics[2].ty(), MetricType::Gauge);
+    assert_eq!(
+        metrics[2].tags().collect::<Vec<_>>(),
+        &[("a", "1"), ("b", "2")]
+    );
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_aggregated_metric_handles() {
+    use std::sync::Arc;
+
+    use crate::aggregator::CardinalityLimiter;
+
+    let aggregations = Default::default();
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Default::default(),
+        thread: Default::default(),
+    };
+    let builder = Dispatcher::builder().global_tag("env", "test");
+    let dispatcher = builder.build(sink);
+
+    let counter = Counter::register("handle.counter", [("tag", "value")]);
+    let gauge = Gauge::register("handle.gauge", [("tag", "value")]);
+
+    let guard = set_local_dispatcher(dispatcher);
+    counter.increment(1);
+    counter.increment(2);
+    for i in 1..=4 {
+        gauge.set(i);
+    }
+    drop(guard);
+    drop(gauge);
+
+    let mut total_aggregation = Aggregations::default();
+    for aggregation in aggregations.iter() {
+        assert_eq!(aggregation.swap().counters.len(), 0);
+        let mut limiter = CardinalityLimiter::default();
+        total_aggregation.merge_cells(&aggregation.cells, &mut limiter);
+        // the cell of the dropped gauge has been removed
+        assert_eq!(aggregation.cells.lock().unwrap().len(), 1);
+    }
+
+    let (key, counter) = total_aggregation.counters.into_iter().next().unwrap();
+    assert_eq!(counter, 3.);
+    assert_eq!(
+        key.tags().collect::<Vec<_>>(),
+        &[("env", "test"), ("tag", "value")]
+    );
+    let gauge = total_aggregation.gauges.into_values().next().unwrap();
+    assert_eq!(gauge.count, 4);
+    assert_eq!((gauge.min, gauge.max, gauge.last), (1., 4., 4.));
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_metric_handles_follow_dispatcher() {
+    use std::sync::Arc;
+
+    let a = Arc::new(ThreadLocalAggregator::<()>::without_thread(
+        Default::default(),
+    ));
+    let b = Arc::new(ThreadLocalAggregator::<()>::without_thread(
+        Default::default(),
+    ));
+    let counter = Counter::register("handle.counter", [("tag", "value")]);
+
+    for (sink, value) in [(&a, 1), (&b, 2), (&a, 4)] {
+        let _guard = set_local_dispatcher(Dispatcher::new(Arc::clone(sink)));
+        counter.increment(value);
+    }
+
+    let sum = |sink: &ThreadLocalAggregator<()>| sink.drain().counters.into_values().sum::<f64>();
+    assert_eq!(sum(&a), 5.);
+    assert_eq!(sum(&b), 2.);
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_metric_handles_with_scoped_tags() {
+    use std::sync::Arc;
+
+    let sink = Arc::new(ThreadLocalAggregator::<()>::without_thread(
+        Default::default(),
+    ));
+    let _guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&sink)));
+    let counter = Counter::register("handle.counter", [("tag", "value")]);
+
+    // the scoped tags are not baked into the cell registered within the scope
+    with_tags([("tenant", "a")], || counter.increment(1));
+    counter.increment(2);
+    with_tags([("tenant", "b")], || counter.increment(4));
+
+    let mut counters: Vec<_> = sink
+        .drain()
+        .counters
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                key.tags().map(|(_, v)| v.to_owned()).collect::<Vec<_>>(),
+                value,
+            )
+        })
+        .collect();
+    counters.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        counters,
+        [
+            (vec!["a".to_owned(), "value".to_owned()], 1.),
+            (vec!["b".to_owned(), "value".to_owned()], 4.),
+            (vec!["value".to_owned()], 2.),
+        ]
+    );
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_sketch_aggregation() {
+    use std::sync::Arc;
+
+    let aggregations = Default::default();
+    let config = AggregatorConfig::default()
+        .distributions(DistributionAggregation::Sketch)
+        .metric_distributions("some.precise", DistributionAggregation::Precise);
+    let sink = ThreadLocalAggregator::<()> {
+        aggregations: Arc::clone(&aggregations),
+        config: Arc::new(config),
+        thread: Default::default(),
+    };
+    let dispatcher = Dispatcher::new(sink);
+
+    let guard = set_local_dispatcher(dispatcher);
+
+    for i in 1..=100 {
+        distribution!("some.sketch": i);
+        distribution!("some.precise": i);
+    }
+
+    drop(guard);
+
+    let mut total_aggregation = Aggregations::default();
+    for aggregation in aggregations.iter() {
+        let mut aggregation = aggregation.swap();
+        total_aggregation.merge_aggregations(&mut aggregation);
+    }
+
+    assert_eq!(total_aggregation.distributions.len(), 1);
+    let (meta, precise) = total_aggregation.distributions.into_iter().next().unwrap();
+    assert_eq!(meta.key(), "some.precise");
+    assert_eq!(precise.values.len(), 100);
+
+    assert_eq!(total_aggregation.sketches.len(), 1);
+    let (meta, sketch) = total_aggregation.sketches.into_iter().next().unwrap();
+    assert_eq!(meta.key(), "some.sketch");
+    assert_eq!(sketch.count(), 100);
+    assert_eq!(sketch.sum(), 5050.);
+    assert_eq!(sketch.min(), 1.);
+    assert_eq!(sketch.max(), 100.);
+}
+
+#[test]
+fn test_metric_assertions() {
+    use crate::testing::AssertMetrics;
+
+    let dispatcher = TestDispatcher::new();
+
+    counter!("requests": 1, "route" => "/", "status" => 200);
+    counter!("requests": 2, "route" => "/", "status" => 500);
+    counter!("requests": 4, "route" => "/about", "status" => 200);
+    gauge!("queue.size": 3);
+    gauge!("queue.size": 1);
+    set!("users": "a");
+    set!("users": "b");
+    set!("users": "a");
+
+    let metrics = dispatcher.finish();
+    metrics.assert_counter("requests").count(3).sum(7.);
+    metrics
+        .assert_counter("requests")
+        .with_tag("route", "/")
+        .sum(3.)
+        .min(1.)
+        .max(2.);
+    metrics
+        .assert_counter("requests")
+        .with_tag("route", "/")
+        .with_tag("status", 500)
+        .count(1)
+        .last(2.);
+    metrics.assert_gauge("queue.size").last(1.).max(3.);
+    metrics.assert_set("users").count(3).cardinality(2);
+
+    let failure = std::panic::catch_unwind(|| {
+        metrics
+            .assert_counter("requests")
+            .with_tag("route", "/")
+            .sum(4.);
+    })
+    .unwrap_err();
+    let failure = failure.downcast_ref::<String>().unwrap();
+    assert!(failure.starts_with(
+        "assertion failed for Counter `requests` with route=/: expected sum 4, found 3"
+    ));
+    assert!(failure.contains("Counter `requests`{route=/about, status=200}: count=1 sum=4"));
+
+    let failure = std::panic::catch_unwind(|| {
+        metrics.assert_gauge("requests").exists();
+    })
+    .unwrap_err();
+    let failure = failure.downcast_ref::<String>().unwrap();
+    assert!(failure.contains("no matching metric found"));
+    assert!(failure.contains("other metrics with the same key:"));
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_aggregated_test_dispatcher() {
+    use crate::testing::AssertMetrics;
+
+    let builder = Dispatcher::builder().prefix("app.");
+    let dispatcher = TestDispatcher::aggregated_with_config(builder, AggregatorConfig::default());
+
+    for status in [200, 200, 404, 500] {
+        counter!("requests": 1, "status" => status);
+    }
+    distribution!("latency": 1, @rate = 1.);
+    distribution!("latency": 3);
+    let counter = Counter::register("handle.counter", [("tag", "value")]);
+    counter.increment(5);
+
+    let aggregations = dispatcher.finish();
+    aggregations
+        .assert_counter("app.requests")
+        .with_tag("status", 200)
+        .sum(2.);
+    aggregations.assert_counter("app.requests").sum(4.);
+    let failure = std::panic::catch_unwind(|| {
+        aggregations.assert_counter("app.requests").count(4);
+    })
+    .unwrap_err();
+    let failure = failure.downcast_ref::<String>().unwrap();
+    assert!(failure.contains("the number of values of aggregated counters is unknown"));
+    assert!(failure.contains("Counter `app.requests`{status=200}: count=? sum=2"));
+    aggregations
+        .assert_distribution("app.latency")
+        .count(2)
+        .sum(4.)
+        .max(3.);
+    aggregations
+        .assert_counter("app.handle.counter")
+        .with_tag("tag", "value")
+        .sum(5.);
+
+    // nothing is recorded after finishing
+    counter!("requests": 1);
+}
+
+#[cfg(feature = "aggregator")]
+#[test]
+fn test_aggregator_shutdown() {
+    use std::sync::Arc;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    struct Collect;
+    impl AggregationSink for Collect {
+        type Output = Aggregations;
+        fn emit(&mut self, metrics: Aggregations) -> Aggregations {
+            metrics
+        }
+    }
+
+    let aggregator = Arc::new(ThreadLocalAggregator::new(Duration::from_secs(60), Collect));
+    let guard = set_local_dispatcher(Dispatcher::new(Arc::clone(&aggregator)));
+    counter!("some.counter": 1);
+    drop(guard);
+
+    let aggregations = aggregator.shutdown(None).unwrap();
+    assert_eq!(aggregations.counters.len(), 1);
+    assert!(aggregator.thread.lock().unwrap().is_none());
+
+    assert!(matches!(
+        aggregator.flush(None),
+        Err(RecvTimeoutError::Disconnected)
+    ));
+    assert!(matches!(
+        aggregator.shutdown(None),
+        Err(RecvTimeoutError::Disconnected)
+    ));
+}
+
+#[test]
+fn test_replace_global_dispatcher() {
+    use std::sync::{Arc, Mutex};
+
+    // other tests might concurrently emit to the global dispatcher, so only `global.` metrics are collected
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<&'static str>>>);
+    impl Sink for Collect {
+        fn emit(&self, metric: Metric) {
+            if metric.key().starts_with("global.") {
+                self.0.lock().unwrap().push(metric.key());
+            }
+        }
+    }
+
+    let (first, second) = (Collect::default(), Collect::default());
+    set_global_dispatcher(Dispatcher::new(first.clone())).unwrap();
+    counter!("global.first": 1);
+    assert!(set_global_dispatcher(Dispatcher::new(Collect::default())).is_err());
+
+    // a thread which used the previous dispatcher, and is still alive after it was replaced
+    let (emitted_tx, emitted_rx) = std::sync::mpsc::channel();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let idle_thread = std::thread::spawn(move || {
+        counter!("global.idle": 1);
+        emitted_tx.send(()).unwrap();
+        let _ = done_rx.recv();
+    });
+    emitted_rx.recv().unwrap();
+
+    let mut previous = replace_global_dispatcher(Dispatcher::new(second.clone()));
+    assert!(previous.is_some());
+    counter!("global.second": 1);
+    // the previous dispatcher is released once concurrently emitted metrics are done
+    for _ in 0..100 {
+        match Arc::try_unwrap(previous.take().unwrap()) {
+            Ok(_) => break,
+            Err(dispatcher) => previous = Some(dispatcher),
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(previous.is_none());
+    drop(done_tx);
+    idle_thread.join().unwrap();
+    std::thread::spawn(|| {
+        counter!("global.thread": 1);
+    })
+    .join()
+    .unwrap();
+
+    assert!(reset_global_dispatcher().is_some());
+    assert!(reset_global_dispatcher().is_none());
+    counter!("global.dropped": 1);
+
+    assert_eq!(*first.0.lock().unwrap(), &["global.first", "global.idle"]);
+    assert_eq!(
+        *second.0.lock().unwrap(),
+        &["global.second", "global.thread"]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_scoped_dispatcher() {
+    use crate::testing::AssertMetrics;
+
+    async fn emit(key: &'static str) {
+        for _ in 0..10 {
+            counter!("scoped.dispatcher": 1, "key" => key);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // the tasks are moved between the worker threads, but only see their own dispatcher
+    let tasks = ["a", "b", "c"].map(|key| tokio::spawn(TestDispatcher::scope(emit(key))));
+    for (task, key) in tasks.into_iter().zip(["a", "b", "c"]) {
+        let ((), metrics) = task.await.unwrap();
+        assert_eq!(metrics.len(), 10);
+        metrics
+            .assert_counter("scoped.dispatcher")
+            .with_tag("key", key)
+            .count(10);
+    }
+
+    // the scope takes precedence over the thread-local dispatcher
+    let dispatcher = TestDispatcher::new();
+    let ((), scoped) = TestDispatcher::scope(async {
+        counter!("scoped.dispatcher": 1);
+    })
+    .await;
+    counter!("local.dispatcher": 1);
+
+    scoped.assert_counter("scoped.dispatcher").sum(1.);
+    let metrics = dispatcher.finish();
+    metrics.assert_counter("local.dispatcher").sum(1.);
+    assert_eq!(metrics.len(), 1);
+}
+
+#[test]
+fn test_scoped_dispatcher_drop() {
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Waker};
+
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<&'static str>>>);
+    impl Sink for Collect {
+        fn emit(&self, metric: Metric) {
+            self.0.lock().unwrap().push(metric.key());
+        }
+    }
+
+    struct EmitOnDrop;
+    impl Drop for EmitOnDrop {
+        fn drop(&mut self) {
+            counter!("scoped.dropped": 1);
+        }
+    }
+
+    let dispatcher = TestDispatcher::new();
+    let scoped = Collect::default();
+
+    // the future is dropped before completing, while still holding `EmitOnDrop`
+    let mut future = Box::pin(scope(Dispatcher::new(scoped.clone()), async {
+        let _emit = EmitOnDrop;
+        std::future::pending::<()>().await;
+    }));
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(future.as_mut().poll(&mut cx).is_pending());
+    drop(future);
+
+    assert_eq!(*scoped.0.lock().unwrap(), &["scoped.dropped"]);
+    assert!(dispatcher.finish().is_empty());
 }
@@ -0,0 +1,105 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use pin_project_lite::pin_project;
+
+use crate::tags::{TagValues, record_tags};
+use crate::{IntoMetricValue, MetricMeta, TaggedMetricMeta, with_dispatcher};
+
+/// A guard which records the elapsed time as a metric when being dropped.
+///
+/// This is usually created using the [`time!`](crate::time!) macro.
+#[must_use = "the elapsed time is recorded when the guard is dropped"]
+#[derive(Debug)]
+pub struct TimerGuard {
+    metric: Option<(&'static MetricMeta, TagValues)>,
+    start: Instant,
+}
+
+impl TimerGuard {
+    /// Starts timing the given metric.
+    pub fn start(metric: &'static MetricMeta) -> Self {
+        Self {
+            metric: Some((metric, None)),
+            start: Instant::now(),
+        }
+    }
+
+    /// Starts timing the given metric, along with its tags.
+    ///
+    /// The tag values are captured immediately.
+    pub fn start_tagged<const N: usize>(
+        metric: &'static TaggedMetricMeta<N>,
+        tag_values: [&dyn Display; N],
+    ) -> Self {
+        let TaggedMetricMeta { meta } = metric;
+        Self {
+            metric: Some((meta, record_tags(&tag_values))),
+            start: Instant::now(),
+        }
+    }
+
+    /// The time elapsed since the timer was started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Stops the timer without recording the elapsed time.
+    pub fn cancel(mut self) {
+        self.metric = None;
+    }
+
+    /// Wraps the given [`Future`], recording the elapsed time once it completes.
+    ///
+    /// The elapsed time is not recorded if the future is dropped before completing.
+    pub fn timed<F: Future>(self, future: F) -> Timed<F> {
+        Timed {
+            future,
+            guard: Some(self),
+        }
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        if let Some((meta, tag_values)) = self.metric.take() {
+            let value = self.start.elapsed().into_metric_value(meta);
+            with_dispatcher(|dispatcher| dispatcher.record_values(meta, value, tag_values, 1.));
+        }
+    }
+}
+
+pin_project! {
+    /// A [`Future`] which records the time it took to complete.
+    ///
+    /// This is created using [`TimerGuard::timed`].
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    #[derive(Debug)]
+    pub struct Timed<F> {
+        #[pin]
+        future: F,
+        guard: Option<TimerGuard>,
+    }
+
+    impl<F> PinnedDrop for Timed<F> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(guard) = this.project().guard.take() {
+                guard.cancel();
+            }
+        }
+    }
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = std::task::ready!(this.future.poll(cx));
+        drop(this.guard.take());
+        Poll::Ready(output)
+    }
+}
@@ -1,10 +1,11 @@
+use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
 use crate::MetricMeta;
 
 /// The Type of a Metric.
 ///
-/// Counters, Gauges and Distributions are supported,
+/// Counters, Gauges, Distributions and Sets are supported,
 /// with more types to be added later.
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -20,20 +21,68 @@ pub enum MetricType {
     /// This is similar to [`MetricType::Distribution`], except it defaults to
     /// recording millisecond durations if no explicit [`MetricUnit`] was defined.
     Timer,
+    /// A set metric, counting the number of unique values.
+    ///
+    /// Values are identified by their hash, see [`SetValue`].
+    Set,
 }
 
 /// The Unit of a Metric.
+///
+/// Sinks translate the unit into the naming convention of their backend.
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-#[cfg_attr(feature = "serde", derive(serde::Serialize))]
-#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum MetricUnit {
     /// An unknown fallback unit.
     Unknown,
+    /// The metric counts nanoseconds.
+    Nanoseconds,
+    /// The metric counts microseconds.
+    Microseconds,
+    /// The metric counts milliseconds.
+    Milliseconds,
     /// The metric counts seconds.
     Seconds,
     /// The metric counts bytes.
     Bytes,
+    /// The metric counts kibibytes, or 1024 bytes.
+    Kibibytes,
+    /// The metric counts mebibytes, or 1024 kibibytes.
+    Mebibytes,
+    /// The metric is a percentage, in the range `0..=100`.
+    Percent,
+    /// The metric is a ratio, in the range `0..=1`.
+    Ratio,
+    /// A custom unit, which is passed to the sinks as-is.
+    Custom(&'static str),
+}
+
+impl MetricUnit {
+    /// The name of the unit.
+    ///
+    /// This is the lowercase variant name, or the name of a [`Custom`](MetricUnit::Custom) unit.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricUnit::Unknown => "unknown",
+            MetricUnit::Nanoseconds => "nanoseconds",
+            MetricUnit::Microseconds => "microseconds",
+            MetricUnit::Milliseconds => "milliseconds",
+            MetricUnit::Seconds => "seconds",
+            MetricUnit::Bytes => "bytes",
+            MetricUnit::Kibibytes => "kibibytes",
+            MetricUnit::Mebibytes => "mebibytes",
+            MetricUnit::Percent => "percent",
+            MetricUnit::Ratio => "ratio",
+            MetricUnit::Custom(unit) => unit,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for MetricUnit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 /// The value of a metric.
@@ -92,11 +141,54 @@ impl IntoMetricValue for bool {
 }
 
 impl IntoMetricValue for Duration {
+    /// Converts the duration into the time unit of the metric.
+    ///
+    /// Durations of metrics without a time unit are recorded in seconds,
+    /// or milliseconds for [`MetricType::Timer`]s without any unit.
     fn into_metric_value(self, meta: &MetricMeta) -> MetricValue {
         let secs = self.as_secs_f64();
-        MetricValue::new(match meta.unit() {
-            MetricUnit::Unknown if meta.ty() == MetricType::Timer => secs * 1_000.,
+        MetricValue::new(match meta.value_unit() {
+            MetricUnit::Nanoseconds => secs * 1_000_000_000.,
+            MetricUnit::Microseconds => secs * 1_000_000.,
+            MetricUnit::Milliseconds => secs * 1_000.,
             _ => secs,
         })
     }
 }
+
+/// A value of a [`MetricType::Set`], identified by its hash.
+///
+/// This is used by the [`set!`](crate::set!) macro, and hashes any [`Hash`]able value,
+/// like strings or integers. The hash is stable across processes, and truncated to 53 bits,
+/// so it can be represented exactly as a [`MetricValue`].
+#[derive(Debug, Clone, Copy)]
+pub struct SetValue<T>(pub T);
+
+impl<T: Hash> IntoMetricValue for SetValue<T> {
+    fn into_metric_value(self, _meta: &MetricMeta) -> MetricValue {
+        let mut hasher = FnvHasher::default();
+        self.0.hash(&mut hasher);
+        MetricValue::new((hasher.finish() >> 11) as f64)
+    }
+}
+
+/// The 64-bit FNV-1a hash function, which is stable unlike the [`std`] default hasher.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}